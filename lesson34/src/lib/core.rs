@@ -1,11 +1,11 @@
 use std::{
     collections::{BTreeMap, HashMap, LinkedList},
-    num::{NonZeroU128, NonZeroU32},
+    num::{NonZeroU128, NonZeroU64},
 };
 
 pub type AccountId = String;
-pub type Money = u32;
-pub type NonZeroMoney = NonZeroU32;
+pub type Money = u64;
+pub type NonZeroMoney = NonZeroU64;
 pub type Err = String;
 pub type OpId = NonZeroU128;
 
@@ -56,41 +56,47 @@ impl Operation {
     }
 }
 
+//операция истории вместе с её идентификатором
+pub type Entry = (OpId, Operation);
+
+//Хранилище и состояние возвращают значения, а не ссылки на свои данные,
+//и каждое чтение может завершиться ошибкой, поэтому их можно держать не только в памяти
 pub trait OpsStorage {
-    fn transact(
-        &mut self,
-        ops: impl Iterator<Item = Operation>,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, Err>;
+    fn transact(&mut self, ops: &[Operation]) -> Result<Vec<OpId>, Err>;
 
-    fn persist(&mut self, op: Operation) -> Result<(OpId, &Operation), Err> {
-        self.transact(std::iter::once(op)).and_then(|mut iter| {
-            iter.next()
+    fn persist(&mut self, op: &Operation) -> Result<OpId, Err> {
+        self.transact(std::slice::from_ref(op)).and_then(|op_ids| {
+            op_ids
+                .first()
+                .copied()
                 .ok_or("a transaction should return at least one operation".to_owned())
         })
     }
     //should be O(N), where N - account ops
-    fn get_ops<'a, 'b>(
-        &'a self,
-        account_id: &'b AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &'a Operation)>, Err>;
+    fn get_ops(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<impl Iterator<Item = Result<Entry, Err>>, Err>;
     //should be O(M), where M - all ops
-    fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, Err>;
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, Err>>, Err>;
 }
 
 pub trait State {
-    fn transact<'a, 'b>(
-        &'a mut self,
+    fn transact<'b>(
+        &mut self,
         ops: impl Iterator<Item = &'b Operation>,
-    ) -> Result<impl Iterator<Item = &'a Account> + 'a, Err>;
+    ) -> Result<Vec<Account>, Err>;
 
-    fn update<'a>(&'a mut self, op: &Operation) -> Result<&'a Account, Err> {
-        self.transact(std::iter::once(op)).and_then(|mut iter| {
-            iter.next()
+    fn update(&mut self, op: &Operation) -> Result<Account, Err> {
+        self.transact(std::iter::once(op)).and_then(|accounts| {
+            accounts
+                .into_iter()
+                .next()
                 .ok_or("a transaction should return at least one account".to_owned())
         })
     }
     //should be O(N), where N - account ops
-    fn get_balance(&self, account_id: &AccountId) -> Result<&Account, Err>;
+    fn get_balance(&self, account_id: &AccountId) -> Result<Account, Err>;
 }
 
 pub struct Bank<T: OpsStorage, S: State> {
@@ -103,7 +109,7 @@ impl<T: OpsStorage, S: State> Bank<T, S> {
         Bank { storage, state }
     }
 
-    pub fn from<'a>(history: impl Iterator<Item = (OpId, &'a Operation)>) -> Bank<T, S>
+    pub fn from(history: impl Iterator<Item = Entry>) -> Bank<T, S>
     where
         S: Default,
         T: Default,
@@ -114,11 +120,10 @@ impl<T: OpsStorage, S: State> Bank<T, S> {
         };
 
         for (_, op) in history {
-            let (_, op) = bank
-                .storage
-                .persist(op.clone())
+            bank.storage
+                .persist(&op)
                 .unwrap_or_else(|_| panic!("something wrong with history operation[{:?}]", op));
-            let ret = bank.state.update(op);
+            let ret = bank.state.update(&op);
 
             if let Some(err) = ret.err() {
                 println!("History operation has an error[{:?}]", err)
@@ -129,48 +134,41 @@ impl<T: OpsStorage, S: State> Bank<T, S> {
     }
 
     //создание аккаунта
-    pub fn create_account(&mut self, account_id: AccountId) -> Result<&Account, Err> {
-        let op = Operation::Create(account_id.clone());
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+    pub fn create_account(&mut self, account_id: AccountId) -> Result<Account, Err> {
+        let op = Operation::Create(account_id);
+        self.storage.persist(&op)?;
+        self.state.update(&op)
     }
 
     //Клиент может получить свой баланс.
-    pub fn get_balance(&self, account_id: &AccountId) -> Result<&Account, Err> {
+    pub fn get_balance(&self, account_id: &AccountId) -> Result<Account, Err> {
         self.state.get_balance(account_id)
     }
 
     //история операций по счету
-    pub fn get_account_ops<'a, 'b>(
+    pub fn get_account_ops<'a>(
         &'a self,
-        account_id: &'b AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &'a Operation)> + 'b, String>
-    where
-        'a: 'b,
-    {
+        account_id: &'a AccountId,
+    ) -> Result<impl Iterator<Item = Result<Entry, Err>> + 'a, Err> {
         self.storage.get_ops(account_id)
     }
     //можно получить историю операций
-    pub fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, String> {
+    pub fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, Err>> + '_, Err> {
         self.storage.get_history()
     }
 
     //Клиент может пополнить свой баланс.
-    pub fn deposit(&mut self, account_id: AccountId, money: NonZeroMoney) -> Result<&Account, Err> {
+    pub fn deposit(&mut self, account_id: AccountId, money: NonZeroMoney) -> Result<Account, Err> {
         let op = Operation::Deposit(account_id, money);
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+        self.storage.persist(&op)?;
+        self.state.update(&op)
     }
 
     //Клиент может забрать деньги
-    pub fn withdraw(
-        &mut self,
-        account_id: AccountId,
-        money: NonZeroMoney,
-    ) -> Result<&Account, Err> {
+    pub fn withdraw(&mut self, account_id: AccountId, money: NonZeroMoney) -> Result<Account, Err> {
         let op = Operation::Withdraw(account_id, money);
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+        self.storage.persist(&op)?;
+        self.state.update(&op)
     }
 
     //перемещение денег от счета на счет
@@ -179,7 +177,7 @@ impl<T: OpsStorage, S: State> Bank<T, S> {
         from: AccountId,
         to: AccountId,
         money: NonZeroMoney,
-    ) -> Result<impl Iterator<Item = &Account>, Err> {
+    ) -> Result<impl Iterator<Item = Account>, Err> {
         if from.eq(to.as_str()) {
             return Err("Sending funds to yourself is prohibited".to_string());
         }
@@ -187,13 +185,12 @@ impl<T: OpsStorage, S: State> Bank<T, S> {
         let ops = vec![
             Operation::Withdraw(from, money),
             Operation::Deposit(to, money),
-        ]
-        .into_iter();
+        ];
         //first save to storage
-        let ops = self.storage.transact(ops)?.map(|(_, op)| op);
+        self.storage.transact(&ops)?;
 
         //and then update state
-        self.state.transact(ops)
+        self.state.transact(ops.iter()).map(Vec::into_iter)
     }
 }
 
@@ -210,7 +207,7 @@ impl<'a> InMemoryState {
             Operation::Create(account_id) => {
                 let new_account = Account {
                     account_id: account_id.clone(),
-                    balance: 0_u32,
+                    balance: 0,
                 };
                 self.0.insert(account_id.clone(), new_account);
                 account_id
@@ -218,17 +215,19 @@ impl<'a> InMemoryState {
 
             Operation::Deposit(account_id, money) if self.0.contains_key(account_id) => {
                 let account: &mut Account = self.0.get_mut(account_id).unwrap();
-                account.balance += money.get();
+                account.balance = account
+                    .balance
+                    .checked_add(money.get())
+                    .ok_or_else(|| format!("Balance of account_id[{}] overflows", account_id))?;
                 account_id
             }
 
             Operation::Withdraw(account_id, money) if self.0.contains_key(account_id) => {
                 let account: &mut Account = self.0.get_mut(account_id).unwrap();
-                if account.balance >= money.get() {
-                    account.balance -= money.get();
-                } else {
-                    return Err("Insufficient funds".to_string());
-                }
+                account.balance = account
+                    .balance
+                    .checked_sub(money.get())
+                    .ok_or_else(|| "Insufficient funds".to_string())?;
                 account_id
             }
 
@@ -254,16 +253,17 @@ impl<'a> InMemoryState {
 }
 
 impl State for InMemoryState {
-    fn get_balance(&self, account_id: &AccountId) -> Result<&Account, Err> {
+    fn get_balance(&self, account_id: &AccountId) -> Result<Account, Err> {
         self.0
             .get(account_id)
+            .cloned()
             .ok_or(format!("Account[{}] not found in bank", account_id))
     }
 
-    fn transact<'a, 'b>(
-        &'a mut self,
+    fn transact<'b>(
+        &mut self,
         ops: impl Iterator<Item = &'b Operation>,
-    ) -> Result<impl Iterator<Item = &'a Account> + 'a, Err> {
+    ) -> Result<Vec<Account>, Err> {
         let mut successful_accounts_ids = Vec::new();
 
         // Фаза 1: модификация данных
@@ -275,11 +275,15 @@ impl State for InMemoryState {
         }
 
         // Фаза 2: чтение данных (поиск по ID, который мы сохранили)
-        Ok(successful_accounts_ids.into_iter().map(|account_id| {
-            self.0
-                .get(&account_id)
-                .expect("something is wrong with your code")
-        }))
+        Ok(successful_accounts_ids
+            .into_iter()
+            .map(|account_id| {
+                self.0
+                    .get(&account_id)
+                    .cloned()
+                    .expect("something is wrong with your code")
+            })
+            .collect())
     }
 }
 
@@ -320,44 +324,36 @@ impl InMemoryOpsStorage {
     }
 }
 
+impl InMemoryOpsStorage {
+    //операция по идентификатору из индекса счёта
+    fn entry(&self, op_id: &OpId) -> Result<Entry, Err> {
+        self.by_ops_storage
+            .get(op_id) //O(lgN)
+            .map(|(_, op)| (*op_id, op.clone()))
+            .ok_or(format!("There is no operation[{}] in the storage", op_id))
+    }
+}
+
 impl OpsStorage for InMemoryOpsStorage {
-    fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, Err> {
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, Err>>, Err> {
         Ok(self
             .by_ops_storage
             .iter()
-            .map(|(op_id, (_, operation))| (*op_id, operation)))
+            .map(|(op_id, (_, operation))| Ok((*op_id, operation.clone()))))
     }
 
     fn get_ops(
         &self,
         account_id: &AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, Err> {
+    ) -> Result<impl Iterator<Item = Result<Entry, Err>>, Err> {
         self.by_acc_storage
-                .get(account_id)//O(1)
-                .map(|list| {
-                    list.iter().map(|op_id|{ //O(N)
-                        let (_, op)=self.by_ops_storage.get(op_id)//O(lgN)
-                        .unwrap_or_else(|| panic!("something get wrong with your code, bsc by_ops_storage doesn't contain value for op_id[{}]",op_id));
-                        (*op_id,op)
-                    })
-                }).ok_or(format!("There is no account[{}] in the bank",account_id))
+            .get(account_id) //O(1)
+            .map(|list| list.iter().map(|op_id| self.entry(op_id))) //O(N)
+            .ok_or(format!("There is no account[{}] in the bank", account_id))
     }
 
-    fn transact(
-        &mut self,
-        ops: impl Iterator<Item = Operation>,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, Err> {
-        let mut vec = Vec::new();
-        for op in ops {
-            let op_id = self.push_to_cols(op);
-            vec.push(op_id);
-        }
-
-        Ok(vec.into_iter().map(|op_id| {
-            let (_, op) = self.by_ops_storage.get(&op_id).unwrap_or_else(|| panic!("something wrong with your code, by_ops_storage should contain op_id[{}]",
-                    op_id));
-            (op_id, op)
-        }))
+    fn transact(&mut self, ops: &[Operation]) -> Result<Vec<OpId>, Err> {
+        Ok(ops.iter().map(|op| self.push_to_cols(op.clone())).collect())
     }
 }
 
@@ -377,18 +373,18 @@ mod test {
         let ret = Bank::create_account(&mut bank, acc_1.clone());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 0_u32
+                balance: 0
             })
         );
 
         let ret = Bank::create_account(&mut bank, acc_2.clone());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
-                balance: 0_u32
+                balance: 0
             })
         );
         drop(ret);
@@ -405,34 +401,34 @@ mod test {
         let _ = bank.create_account(acc_1.clone());
         let _ = bank.create_account(acc_2.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_2.clone(), NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> = //acc_1 again
+        let ret: Result<Account, String> = //acc_1 again
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 84
             })
@@ -450,58 +446,75 @@ mod test {
         let _ = bank.create_account(acc_1.clone());
         let _ = bank.create_account(acc_2.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_2.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> = bank.withdraw(acc_1.clone(), NonZeroMoney::MIN);
+        let ret: Result<Account, String> = bank.withdraw(acc_1.clone(), NonZeroMoney::MIN);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 41
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.withdraw(acc_1.clone(), NonZeroMoney::new(41).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 0
             })
         );
 
-        let ret: Result<&Account, String> = bank.withdraw(acc_2.clone(), NonZeroMoney::MIN);
+        let ret: Result<Account, String> = bank.withdraw(acc_2.clone(), NonZeroMoney::MIN);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 41
             })
         );
 
-        let ret: Result<&Account, String> = bank.withdraw(acc_2.clone(), NonZeroMoney::MAX);
+        let ret: Result<Account, String> = bank.withdraw(acc_2.clone(), NonZeroMoney::MAX);
         assert_eq!(ret, Err("Insufficient funds".to_string()));
     }
 
+    #[test]
+    fn bank_should_reject_balance_overflow() {
+        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
+            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+
+        let acc_1 = "Acc_1".to_string();
+        let _ = bank.create_account(acc_1.clone());
+        let _ = bank.deposit(acc_1.clone(), NonZeroMoney::MAX);
+
+        let ret: Result<Account, String> = bank.deposit(acc_1.clone(), NonZeroMoney::MIN);
+        assert_eq!(
+            ret,
+            Err("Balance of account_id[Acc_1] overflows".to_string())
+        );
+        assert_eq!(bank.get_balance(&acc_1).unwrap().balance, Money::MAX);
+    }
+
     #[test]
     fn bank_should_move_funds() {
         let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
@@ -515,21 +528,21 @@ mod test {
         let _ = bank.create_account(acc_2.clone());
         let _ = bank.create_account(acc_3.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
         //acc_2 is untouched
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_3.clone(), NonZeroMoney::new(21).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_3.clone(),
                 balance: 21
             })
@@ -543,48 +556,48 @@ mod test {
         let second = iter.next().expect("should be the acc_2 with their Account");
         assert_eq!(
             first,
-            &Account {
+            Account {
                 account_id: acc_1.clone(),
                 balance: 0
             }
         );
         assert_eq!(
             second,
-            &Account {
+            Account {
                 account_id: acc_2.clone(),
                 balance: 42
             }
         );
         let _: Vec<_> = iter.collect(); //drain iter
 
-        let ret: &Account = bank
+        let ret: Account = bank
             .get_balance(&acc_3)
             .expect("should be Ok(Account for acc_3)");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_3.clone(),
                 balance: 21
             }
         );
 
-        let ret: &Account = bank
+        let ret: Account = bank
             .get_balance(&acc_2)
             .expect("should be Ok(Account for acc_2)");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_2.clone(),
                 balance: 42
             }
         );
 
-        let ret: &Account = bank
+        let ret: Account = bank
             .get_balance(&acc_1)
             .expect("should be Ok(Account for acc_1)");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_1.clone(),
                 balance: 0
             }
@@ -604,21 +617,21 @@ mod test {
         let _ = bank.create_account(acc_2.clone());
         let _ = bank.create_account(acc_3.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_3.clone(), NonZeroMoney::new(21).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_3.clone(),
                 balance: 21
             })
@@ -627,7 +640,7 @@ mod test {
         let ret = bank.get_balance(&acc_1).expect("should be Account 1");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_1.clone(),
                 balance: 42
             }
@@ -635,7 +648,7 @@ mod test {
         let ret = bank.get_balance(&acc_2).expect("should be Account 2");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_2.clone(),
                 balance: 0
             }
@@ -643,7 +656,7 @@ mod test {
         let ret = bank.get_balance(&acc_3).expect("should be Account 3");
         assert_eq!(
             ret,
-            &Account {
+            Account {
                 account_id: acc_3.clone(),
                 balance: 21
             }
@@ -663,34 +676,37 @@ mod test {
         let _ = bank.create_account(acc_2.clone());
         let _ = bank.create_account(acc_3.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_3.clone(), NonZeroMoney::new(21).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_3.clone(),
                 balance: 21
             })
         );
 
-        let history = bank.get_history().expect("Bank should get history");
+        let history = bank
+            .get_history()
+            .expect("Bank should get history")
+            .map(Result::unwrap);
 
         let clone_of_bank: Bank<InMemoryOpsStorage, InMemoryState> = Bank::from(history);
 
         let ret = clone_of_bank.get_balance(&acc_1);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
@@ -699,7 +715,7 @@ mod test {
         let ret = clone_of_bank.get_balance(&acc_2);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 0
             })
@@ -708,7 +724,7 @@ mod test {
         let ret = clone_of_bank.get_balance(&acc_3);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_3.clone(),
                 balance: 21
             })
@@ -726,21 +742,21 @@ mod test {
         let _ = bank.create_account(acc_1.clone());
         let _ = bank.create_account(acc_2.clone());
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_1.clone(), NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 42
             })
         );
 
-        let ret: Result<&Account, String> =
+        let ret: Result<Account, String> =
             bank.deposit(acc_2.clone(), NonZeroMoney::new(21).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 21
             })
@@ -752,19 +768,19 @@ mod test {
 
         //let _: Vec<(_, _)> = iter.collect();
 
-        let ret: Result<&Account, String> = bank.get_balance(&acc_2);
+        let ret: Result<Account, String> = bank.get_balance(&acc_2);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
                 balance: 63
             })
         );
 
-        let ret: Result<&Account, String> = bank.get_balance(&acc_1);
+        let ret: Result<Account, String> = bank.get_balance(&acc_1);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
                 balance: 0
             })
@@ -108,14 +108,14 @@ impl Serializable for AccountId {
 
 impl Serializable for Money {
     fn serialize(&self) -> Vec<u8> {
-        let be_bytes: [u8; 4] = self.to_be_bytes();
+        let be_bytes: [u8; size_of::<Money>()] = self.to_be_bytes();
         Self::for_simple(TYPE_ID_MONEY, &be_bytes)
     }
 }
 
 impl Serializable for NonZeroMoney {
     fn serialize(&self) -> Vec<u8> {
-        let be_bytes: [u8; 4] = self.get().to_be_bytes();
+        let be_bytes: [u8; size_of::<Money>()] = self.get().to_be_bytes();
         Self::for_simple(TYPE_ID_NONZERO_MONEY, &be_bytes)
     }
 }
@@ -257,9 +257,9 @@ where
             |type_id| *type_id == TYPE_ID_MONEY,
             data,
             |_, next: &[u8]| {
-                let mut array = [0u8; 4];
-                array.copy_from_slice(next);
-                Ok::<u32, E>(u32::from_be_bytes(array))
+                next.try_into().map(Money::from_be_bytes).map_err(|_| {
+                    E::from(format!("Money should be {} bytes long", size_of::<Money>()))
+                })
             },
         )
     }
@@ -274,9 +274,9 @@ where
             |type_id| *type_id == TYPE_ID_NONZERO_MONEY,
             data,
             |_, next: &[u8]| {
-                let mut array = [0u8; 4];
-                array.copy_from_slice(next);
-                let from_bytes_value = u32::from_be_bytes(array);
+                let from_bytes_value = next.try_into().map(Money::from_be_bytes).map_err(|_| {
+                    E::from(format!("Money should be {} bytes long", size_of::<Money>()))
+                })?;
                 NonZeroMoney::new(from_bytes_value).ok_or(E::from(
                     "An error occured while from array to NonZeroMoney conversion".to_owned(),
                 ))
@@ -700,6 +700,18 @@ pub mod deserialize_tests {
         test(42 * 42);
     }
 
+    #[test]
+    fn deserialize_money_of_wrong_width_should_fail() {
+        //старый формат: 4 байта вместо 8
+        let serialized: Vec<u8> = vec![TYPE_ID_MONEY, 4, 0, 0, 0, 42];
+        let actual: DesResult<Money, String> = Money::deserialize(&serialized);
+        assert!(actual.is_err());
+
+        let serialized: Vec<u8> = vec![TYPE_ID_NONZERO_MONEY, 4, 0, 0, 0, 42];
+        let actual: DesResult<NonZeroMoney, String> = NonZeroMoney::deserialize(&serialized);
+        assert!(actual.is_err());
+    }
+
     #[test]
     fn deserialize_account_should_work() {
         fn test(balance: Money, acc: String) {
@@ -39,11 +39,7 @@ where
             println!("Operation[{:?}] request received", op);
 
             let response = match bank_deal(bank, op) {
-                Ok(bank_accs) => {
-                    let cloned =
-                        Vec::from_iter(bank_accs.into_iter().cloned());
-                    Protocol::Response(Ok(cloned))
-                }
+                Ok(bank_accs) => Protocol::Response(Ok(bank_accs)),
                 Err(bank_err) => {
                     eprintln!("An error[{:?}] occurred while bank dealing", bank_err);
                     Protocol::Response(Err(bank_err))
@@ -57,12 +53,12 @@ where
     }
 }
 
-fn bank_deal<T, S>(bank: &mut Bank<T, S>, op: Operation) -> Result<Vec<&Account>, String>
+fn bank_deal<T, S>(bank: &mut Bank<T, S>, op: Operation) -> Result<Vec<Account>, String>
 where
     T: OpsStorage,
     S: State,
 {
-    fn map_ret(ret: Result<&Account, String>) -> Result<Vec<&Account>, String> {
+    fn map_ret(ret: Result<Account, String>) -> Result<Vec<Account>, String> {
         ret.map(|account| vec![account])
     }
 
@@ -63,10 +63,10 @@ where
     T: OpsStorage,
     S: State,
 {
-    fn map_ret(ret: Result<&Account, String>) -> Result<ServerResponse, String> {
+    fn map_ret(ret: Result<Account, String>) -> Result<ServerResponse, String> {
         ret.map(|account| {
             ServerResponse::AccountState(AccountRef {
-                account_id: account.account_id.clone(),
+                account_id: account.account_id,
                 balance: account.balance,
             })
         })
@@ -87,11 +87,11 @@ where
                 )?;
                 Ok(ServerResponse::FundsMovement {
                     from: AccountRef {
-                        account_id: from.account_id.clone(),
+                        account_id: from.account_id,
                         balance: from.balance,
                     },
                     to: AccountRef {
-                        account_id: to.account_id.clone(),
+                        account_id: to.account_id,
                         balance: to.balance,
                     },
                     amount,
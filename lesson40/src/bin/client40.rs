@@ -1,6 +1,7 @@
 use common::{
-    bank::NonZeroMoney,
-    protocol::{AccountRef, ClientRequest, ServerResponse},
+    bank::{NonZeroMoney, OpId, OperationKind},
+    history::HistoryQuery,
+    protocol::{AccountRef, AdminToken, ClientRequest, ServerResponse},
 };
 
 use ftail::Ftail;
@@ -45,14 +46,57 @@ fn handle_connection(stream: &mut TcpStream, requests: Vec<ClientRequest>) -> an
                 log::info!("Account[{}] state changed[{}]", account_id, balance)
             }
 
-            ServerResponse::FundsMovement { from, to } => {
-                log::info!("Funds moved from[{}] to[{}]", from, to)
+            ServerResponse::AccountChange {
+                account:
+                    AccountRef {
+                        account_id,
+                        balance,
+                    },
+                fee,
+            } => {
+                log::info!(
+                    "Account[{}] state changed[{}], fee[{}]",
+                    account_id,
+                    balance,
+                    fee
+                )
+            }
+
+            ServerResponse::FundsMovement { from, to, fee } => {
+                log::info!("Funds moved from[{}] to[{}], fee[{}]", from, to, fee)
             }
 
             ServerResponse::Error { message } => {
                 log::error!("An error[{}] occurred", message)
             }
 
+            ServerResponse::AccountOps { account_id, ops } => {
+                log::info!("Account[{}] has {} operations:", account_id, ops.len());
+                for (op_id, at, op) in ops {
+                    log::info!("  [{}] at[{}] {:?}", op_id, at, op)
+                }
+            }
+
+            ServerResponse::HistoryPage(page) => {
+                log::info!("History page with {} operations:", page.ops.len());
+                for (op_id, at, op) in page.ops {
+                    log::info!("  [{}] at[{}] {:?}", op_id, at, op)
+                }
+                log::info!("next page after[{:?}]", page.next)
+            }
+
+            ServerResponse::Audit(report) => {
+                log::info!(
+                    "Audit checked {} operations and {} accounts, found {} issues",
+                    report.ops,
+                    report.accounts,
+                    report.issues.len()
+                );
+                for issue in report.issues {
+                    log::warn!("  {:?}", issue)
+                }
+            }
+
             ServerResponse::Bye => {
                 log::info!("the server said Goodbye");
                 stream.shutdown(std::net::Shutdown::Both)?;
@@ -84,8 +128,37 @@ fn main() -> anyhow::Result<()> {
                 to: 129,
                 amount: NonZeroMoney::new(12).unwrap(),
             },
+            ClientRequest::Freeze(129),
+            //ошибка: счёт заморожен
+            ClientRequest::Deposit(129, NonZeroMoney::new(1).unwrap()),
+            ClientRequest::Unfreeze(129),
+            //повтор запроса с тем же ключом получает исходный ответ, а не пополняет счёт ещё раз
+            ClientRequest::Keyed {
+                key: 129,
+                request: Box::new(ClientRequest::Deposit(129, NonZeroMoney::new(10).unwrap())),
+            },
+            ClientRequest::Keyed {
+                key: 129,
+                request: Box::new(ClientRequest::Deposit(129, NonZeroMoney::new(10).unwrap())),
+            },
             ClientRequest::GetBalance(128),
             ClientRequest::GetBalance(129),
+            //баланс счёта сразу после первого пополнения (операция 4 в истории нового сервера)
+            ClientRequest::GetBalanceAt {
+                account_id: 128,
+                op_id: OpId::new(4).unwrap(),
+            },
+            ClientRequest::GetAccountOps(129),
+            //первая страница комиссий в истории банка
+            ClientRequest::QueryHistory(HistoryQuery {
+                kinds: vec![OperationKind::Fee],
+                limit: 2,
+                ..HistoryQuery::default()
+            }),
+            //сверка состояния банка с историей: ошибка, если токен не совпадает с токеном сервера
+            ClientRequest::Audit(AdminToken(
+                std::env::var("BANK_ADMIN_TOKEN").unwrap_or_default(),
+            )),
             ClientRequest::Quit,
         ];
 
@@ -1,25 +1,55 @@
-use std::{net::SocketAddr, ops::DerefMut, sync::Arc};
+use std::net::SocketAddr;
 
 use common::{
-    bank::{Account, Bank, BankError, InMemoryOpsStorage, InMemoryState, OpsStorage, State},
-    protocol::{AccountRef, ClientRequest, ServerResponse},
+    async_bank::AsyncBank,
+    bank::{Account, AccountId, Bank, BankError, InMemoryState, Money, OpsStorage},
+    concurrent::ConcurrentBank,
+    crypto::{self, EncryptionKey, Keyring},
+    fee::{FeeKind, PercentFee},
+    file_storage::FileOpsStorage,
+    idempotency::{self, Receipt},
+    protocol::{AccountRef, AdminToken, ClientRequest, ServerResponse},
 };
+use sha2::{Digest, Sha256};
 
 use ftail::Ftail;
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufStream},
     net::{TcpListener, TcpStream},
-    sync::RwLock,
 };
 
+//специальный счёт банка, на который зачисляется комиссия
+const FEE_ACCOUNT_ID: AccountId = 0;
+
+//токен администратора; если он не задан, административные запросы (Audit) отклоняются
+const ADMIN_TOKEN_ENV: &str = "BANK_ADMIN_TOKEN";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     Ftail::new().console(log::LevelFilter::max()).init()?;
 
-    let bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+    //история операций хранится в файле, состояние счетов восстанавливается из неё при старте
+    let ops_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "server40.ops".to_owned());
+    let bank = Bank::restore(open_storage(&ops_path)?, InMemoryState::default())?.with_fees(
+        FEE_ACCOUNT_ID,
+        PercentFee {
+            basis_points: 100, //1%
+            min: 1,
+            max: 100,
+        },
+    )?;
+    //голову цепочки можно сохранить и позже сравнить, чтобы убедиться, что история не переписана
+    log::debug!(
+        "bank is restored from[{ops_path}], history head[{}]",
+        bank.head_hash()?
+    );
 
-    let state = Arc::new(RwLock::new(bank));
+    //запросы клиентов выполняются параллельно, блокируются только их счета;
+    //запись истории на диск идёт в пуле блокирующих потоков, а не в потоках runtime
+    let state = AsyncBank::new(bank.into_concurrent());
 
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
     log::debug!("is listening on localhost:8080");
@@ -27,7 +57,7 @@ async fn main() -> anyhow::Result<()> {
     loop {
         let (stream, addr) = listener.accept().await?;
         log::debug!("New client connected");
-        let bank_ref = Arc::clone(&state);
+        let bank_ref = state.clone();
 
         tokio::spawn(async move {
             match client_loop(addr, stream, bank_ref).await {
@@ -58,18 +88,26 @@ async fn write_response(
     Ok(())
 }
 
-fn process_request<T: OpsStorage, S: State, B>(
+fn process_request<T: OpsStorage<Id = AccountId>>(
     client_request: ClientRequest,
-    bank_ref: &mut B,
-) -> Result<Option<ServerResponse>, BankError>
-where
-    B: DerefMut<Target = Bank<T, S>>,
-{
-    fn to_account_state(account: &Account) -> Option<ServerResponse> {
-        Some(ServerResponse::AccountState(AccountRef {
+    bank_ref: &ConcurrentBank<T>,
+) -> Result<Option<ServerResponse>, BankError> {
+    fn to_account_ref(account: &Account) -> AccountRef {
+        AccountRef {
             account_id: account.account_id,
             balance: account.balance,
-        }))
+        }
+    }
+    fn to_account_state(account: Account) -> Option<ServerResponse> {
+        Some(ServerResponse::AccountState(to_account_ref(&account)))
+    }
+    fn to_account_change(fee: Money) -> impl FnOnce(Account) -> Option<ServerResponse> {
+        move |account| {
+            Some(ServerResponse::AccountChange {
+                account: to_account_ref(&account),
+                fee,
+            })
+        }
     }
     match client_request {
         ClientRequest::Create(account_id) => {
@@ -77,40 +115,161 @@ where
         }
 
         ClientRequest::Deposit(account_id, amount) => {
-            bank_ref.deposit(&account_id, amount).map(to_account_state)
+            let fee = bank_ref.fee(&account_id, FeeKind::Deposit, amount);
+            bank_ref
+                .deposit(&account_id, amount)
+                .map(to_account_change(fee))
         }
 
         ClientRequest::Withdraw(account_id, amount) => {
-            bank_ref.withdraw(account_id, amount).map(to_account_state)
+            let fee = bank_ref.fee(&account_id, FeeKind::Withdraw, amount);
+            bank_ref
+                .withdraw(account_id, amount)
+                .map(to_account_change(fee))
         }
 
         ClientRequest::GetBalance(account_id) => {
             bank_ref.get_balance(&account_id).map(to_account_state)
         }
 
+        ClientRequest::GetAccountOps(account_id) => {
+            let ops = bank_ref.get_account_ops(&account_id)?;
+            Ok(Some(ServerResponse::AccountOps { account_id, ops }))
+        }
+
+        ClientRequest::Audit(token) => {
+            authorize(&token)?;
+            bank_ref
+                .audit()
+                .map(|report| Some(ServerResponse::Audit(report)))
+        }
+
+        ClientRequest::QueryHistory(query) => bank_ref
+            .query(&query)
+            .map(|page| Some(ServerResponse::HistoryPage(page))),
+
+        ClientRequest::GetBalanceAt { account_id, op_id } => bank_ref
+            .get_balance_at(&account_id, op_id)
+            .map(to_account_state),
+
         ClientRequest::Move { from, to, amount } => {
+            let fee = bank_ref.fee(&from, FeeKind::Move, amount);
             bank_ref.move_money(from, to, amount).map(|(from, to)| {
                 Some(ServerResponse::FundsMovement {
-                    from: AccountRef {
-                        account_id: from.account_id,
-                        balance: from.balance,
-                    },
-                    to: AccountRef {
-                        account_id: to.account_id,
-                        balance: to.balance,
-                    },
+                    from: to_account_ref(&from),
+                    to: to_account_ref(&to),
+                    fee,
                 })
             })
         }
 
+        ClientRequest::Freeze(account_id) => {
+            bank_ref.freeze_account(account_id).map(to_account_state)
+        }
+
+        ClientRequest::Unfreeze(account_id) => {
+            bank_ref.unfreeze_account(account_id).map(to_account_state)
+        }
+
+        ClientRequest::Close { account_id, payout } => bank_ref
+            .close_account(account_id, payout)
+            .map(to_account_state),
+
+        ClientRequest::Keyed { key, request } => match *request {
+            ClientRequest::GetBalance(_)
+            | ClientRequest::GetBalanceAt { .. }
+            | ClientRequest::GetAccountOps(_)
+            | ClientRequest::QueryHistory(_)
+            | ClientRequest::Audit(_)
+            | ClientRequest::Quit
+            | ClientRequest::Keyed { .. } => Err(BankError::BadRequest(format!(
+                "Request[{request:?}] can't have an idempotency key"
+            ))),
+            //квитанция выдаётся только тому же запросу: ответ собирается из неё по виду запроса
+            request => {
+                let fingerprint = idempotency::fingerprint(&request)?;
+                let receipt = bank_ref.idempotent(key, fingerprint, |bank| {
+                    process_request(request.clone(), bank).map(drop)
+                })?;
+                from_receipt(&request, &receipt)
+            }
+        },
+
         ClientRequest::Quit => Ok(None),
     }
 }
 
-async fn client_loop<T: OpsStorage, S: State>(
+//сверка читает всю историю и показывает все счета, поэтому доступна только администратору.
+//Сравниваются хэши токенов, чтобы время сравнения не подсказывало, сколько символов совпало
+fn authorize(token: &AdminToken) -> Result<(), BankError> {
+    let admin = std::env::var(ADMIN_TOKEN_ENV)
+        .ok()
+        .filter(|admin| !admin.is_empty())
+        .ok_or_else(|| BankError::Prohibited("Administrative requests are disabled".to_owned()))?;
+    if Sha256::digest(admin) != Sha256::digest(&token.0) {
+        return Err(BankError::Prohibited(
+            "Administrative request has a wrong admin token".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+//ответ на запрос с ключом собирается из квитанции, поэтому повтор получает тот же ответ, что и оригинал
+fn from_receipt(
+    request: &ClientRequest,
+    receipt: &Receipt<Account>,
+) -> Result<Option<ServerResponse>, BankError> {
+    let account = |index: usize| {
+        receipt
+            .accounts
+            .get(index)
+            .map(|account| AccountRef {
+                account_id: account.account_id,
+                balance: account.balance,
+            })
+            .ok_or_else(|| {
+                BankError::CoreError(format!("Receipt of request[{request:?}] is incomplete"))
+            })
+    };
+    let response = match request {
+        ClientRequest::Deposit(..) | ClientRequest::Withdraw(..) => ServerResponse::AccountChange {
+            account: account(0)?,
+            fee: receipt.fee,
+        },
+        ClientRequest::Move { .. } => ServerResponse::FundsMovement {
+            from: account(0)?,
+            to: account(1)?,
+            fee: receipt.fee,
+        },
+        _ => ServerResponse::AccountState(account(0)?),
+    };
+    Ok(Some(response))
+}
+
+//если ключ задан (см. crypto::KEY_ENV и crypto::KEY_FILE_ENV), история хранится зашифрованной.
+//С прежним ключом история сначала перешифровывается текущим, если в ней есть записи не текущим ключом
+fn open_storage(path: &str) -> Result<FileOpsStorage, BankError> {
+    let Some(key) = EncryptionKey::configured()? else {
+        return FileOpsStorage::open(path);
+    };
+    if std::env::var_os(crypto::PREVIOUS_KEY_ENV).is_none() {
+        return FileOpsStorage::open_encrypted(path, Keyring::new(key));
+    }
+    let previous = EncryptionKey::from_env(crypto::PREVIOUS_KEY_ENV)?;
+    let mut storage =
+        FileOpsStorage::open_encrypted(path, Keyring::new(key.clone()).with_previous(previous))?;
+    if !storage.needs_rotation()? {
+        return Ok(storage);
+    }
+    storage.rotate_key(key)?;
+    log::debug!("history[{path}] is re-encrypted with the current key");
+    Ok(storage)
+}
+
+async fn client_loop<T: OpsStorage<Id = AccountId> + Send + 'static>(
     client_addr: SocketAddr,
     stream: TcpStream,
-    bank_ref: Arc<RwLock<Bank<T, S>>>,
+    bank_ref: AsyncBank<T>,
 ) -> anyhow::Result<()> {
     let mut stream = BufStream::new(stream);
 
@@ -129,10 +288,9 @@ async fn client_loop<T: OpsStorage, S: State>(
             client_request
         );
 
-        let mut guard = bank_ref.write().await;
-
-        let maybe_response = process_request(client_request, &mut guard);
-        drop(guard);
+        let maybe_response = bank_ref
+            .run(move |bank| process_request(client_request, bank))
+            .await;
 
         match maybe_response {
             //успешная операция в банке
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use crate::{
+    audit::AuditReport,
+    bank::{
+        Account, BankAccount, BankError, History, Money, NonZeroMoney, OpId, OpsStorage,
+        SignedMoney,
+    },
+    chain::{ChainHash, ChainReport},
+    concurrent::ConcurrentBank,
+    currency::Currency,
+    fee::FeeKind,
+    history::{HistoryPage, HistoryQuery},
+    idempotency::{Receipt, RequestFingerprint, RequestKey},
+    statement::Statement,
+};
+
+//асинхронный фасад банка для tokio. Хранилища синхронные, поэтому вызовы банка с хранилищем,
+//которое обращается к диску (OpsStorage::BLOCKING), выполняются в пуле блокирующих потоков
+//и не останавливают потоки runtime. С хранилищем в памяти вызов выполняется сразу.
+//Чтения счетов тоже идут через run: блокировка счёта держится, пока его транзакция
+//ждёт записи в хранилище
+#[derive(Debug)]
+pub struct AsyncBank<T, A: BankAccount = Account>(Arc<ConcurrentBank<T, A>>);
+
+impl<T, A: BankAccount> Clone for AsyncBank<T, A> {
+    fn clone(&self) -> Self {
+        AsyncBank(Arc::clone(&self.0))
+    }
+}
+
+impl<T, A> AsyncBank<T, A>
+where
+    T: OpsStorage<Id = A::Id> + Send + 'static,
+    A: BankAccount,
+{
+    pub fn new(bank: ConcurrentBank<T, A>) -> Self {
+        AsyncBank(Arc::new(bank))
+    }
+
+    //выполняет f над банком; составные запросы (например, с ключом идемпотентности)
+    //выполняются одним вызовом run
+    pub async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ConcurrentBank<T, A>) -> Result<R, BankError> + Send + 'static,
+    ) -> Result<R, BankError> {
+        if !T::BLOCKING {
+            return f(&self.0);
+        }
+        let bank = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || f(&bank))
+            .await
+            .map_err(|err| BankError::CoreError(format!("Bank task failed: {err}")))?
+    }
+
+    pub async fn create_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.run(move |bank| bank.create_account(account_id)).await
+    }
+
+    pub async fn create_account_in(
+        &self,
+        account_id: A::Id,
+        currency: Currency,
+    ) -> Result<A, BankError> {
+        self.run(move |bank| bank.create_account_in(account_id, currency))
+            .await
+    }
+
+    pub async fn get_balance(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.run(move |bank| bank.get_balance(&account_id)).await
+    }
+
+    pub async fn fee(
+        &self,
+        payer: A::Id,
+        kind: FeeKind,
+        money: NonZeroMoney,
+    ) -> Result<Money, BankError> {
+        self.run(move |bank| Ok(bank.fee(&payer, kind, money)))
+            .await
+    }
+
+    pub async fn receipt_of(&self, key: RequestKey) -> Result<Option<Receipt<A>>, BankError> {
+        self.run(move |bank| Ok(bank.receipt_of(&key))).await
+    }
+
+    pub async fn deposit(&self, account_id: A::Id, money: NonZeroMoney) -> Result<A, BankError> {
+        self.run(move |bank| bank.deposit(&account_id, money)).await
+    }
+
+    pub async fn withdraw(&self, account_id: A::Id, money: NonZeroMoney) -> Result<A, BankError> {
+        self.run(move |bank| bank.withdraw(account_id, money)).await
+    }
+
+    pub async fn move_money(
+        &self,
+        from: A::Id,
+        to: A::Id,
+        money: NonZeroMoney,
+    ) -> Result<(A, A), BankError> {
+        self.run(move |bank| bank.move_money(from, to, money)).await
+    }
+
+    pub async fn set_credit_limit(&self, account_id: A::Id, limit: Money) -> Result<A, BankError> {
+        self.run(move |bank| bank.set_credit_limit(account_id, limit))
+            .await
+    }
+
+    pub async fn freeze_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.run(move |bank| bank.freeze_account(account_id)).await
+    }
+
+    pub async fn unfreeze_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.run(move |bank| bank.unfreeze_account(account_id))
+            .await
+    }
+
+    pub async fn close_account(
+        &self,
+        account_id: A::Id,
+        payout: Option<A::Id>,
+    ) -> Result<A, BankError> {
+        self.run(move |bank| bank.close_account(account_id, payout))
+            .await
+    }
+
+    //run выполняется в том же потоке, что и его транзакции, см. ConcurrentBank::idempotent
+    pub async fn idempotent(
+        &self,
+        key: RequestKey,
+        fingerprint: RequestFingerprint,
+        run: impl FnOnce(&ConcurrentBank<T, A>) -> Result<(), BankError> + Send + 'static,
+    ) -> Result<Receipt<A>, BankError> {
+        self.run(move |bank| bank.idempotent(key, fingerprint, run))
+            .await
+    }
+
+    pub async fn get_balance_at(&self, account_id: A::Id, op_id: OpId) -> Result<A, BankError> {
+        self.run(move |bank| bank.get_balance_at(&account_id, op_id))
+            .await
+    }
+
+    pub async fn get_account_ops(&self, account_id: A::Id) -> Result<History<A::Id>, BankError> {
+        self.run(move |bank| bank.get_account_ops(&account_id))
+            .await
+    }
+
+    pub async fn query(&self, query: HistoryQuery<A::Id>) -> Result<HistoryPage<A::Id>, BankError> {
+        self.run(move |bank| bank.query(&query)).await
+    }
+
+    pub async fn statement(
+        &self,
+        account_id: A::Id,
+        from: OpId,
+        to: OpId,
+    ) -> Result<Statement<A>, BankError>
+    where
+        A::Balance: Send,
+    {
+        self.run(move |bank| bank.statement(&account_id, from, to))
+            .await
+    }
+
+    pub async fn head_hash(&self) -> Result<ChainHash, BankError> {
+        self.run(|bank| bank.head_hash()).await
+    }
+
+    pub async fn verify_history(&self) -> Result<ChainReport, BankError> {
+        self.run(|bank| bank.verify_history()).await
+    }
+
+    pub async fn audit(&self) -> Result<AuditReport<A>, BankError>
+    where
+        A::Balance: Into<SignedMoney>,
+    {
+        self.run(|bank| bank.audit()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        bank::{Bank, InMemoryOpsStorage, InMemoryState},
+        file_storage::FileOpsStorage,
+    };
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn async_bank_should_offload_only_blocking_storage() {
+        let in_memory: AsyncBank<InMemoryOpsStorage> = AsyncBank::new(
+            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default()).into_concurrent(),
+        );
+        let caller = in_memory.run(|_| Ok(thread::current().id())).await;
+        assert_eq!(caller.unwrap(), thread::current().id());
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileOpsStorage::open(dir.path().join("bank.ops")).unwrap();
+        let on_disk: AsyncBank<FileOpsStorage> =
+            AsyncBank::new(Bank::new(storage, InMemoryState::default()).into_concurrent());
+        let caller = on_disk.run(|_| Ok(thread::current().id())).await;
+        assert_ne!(caller.unwrap(), thread::current().id());
+    }
+
+    #[tokio::test]
+    async fn async_bank_should_serve_concurrent_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileOpsStorage::open(dir.path().join("bank.ops")).unwrap();
+        let bank: AsyncBank<FileOpsStorage> =
+            AsyncBank::new(Bank::new(storage, InMemoryState::default()).into_concurrent());
+        for account_id in 1..=4 {
+            bank.create_account(account_id).await.unwrap();
+        }
+
+        let tasks: Vec<_> = (0..16u128)
+            .map(|task| {
+                let bank = bank.clone();
+                tokio::spawn(async move {
+                    let account_id = task % 4 + 1;
+                    bank.deposit(account_id, NonZeroMoney::new(10).unwrap())
+                        .await
+                        .unwrap();
+                    //повтор запроса с тем же ключом не выполняется
+                    let key = task % 2;
+                    bank.idempotent(key, key as u64, move |bank| {
+                        bank.deposit(&(key + 1), NonZeroMoney::MIN).map(drop)
+                    })
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut total = 0;
+        for account_id in 1..=4 {
+            total += bank.get_balance(account_id).await.unwrap().balance;
+        }
+        assert_eq!(total, 16 * 10 + 2);
+        assert!(bank.receipt_of(1).await.unwrap().is_some());
+        assert!(bank.audit().await.unwrap().is_consistent());
+        assert!(bank.verify_history().await.unwrap().is_intact());
+        assert_eq!(bank.get_account_ops(1).await.unwrap().len(), 1 + 4 + 2);
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bank::{
+        Account, BankAccount, BankError, Entry, IdOf, InMemoryState, OpId, Operation, SignedMoney,
+        State,
+    },
+    currency::Currency,
+};
+
+//нарушение, найденное сверкой
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "A: BankAccount")]
+pub enum AuditIssue<A: BankAccount = Account> {
+    //операция по счёту, который не создан в истории; об одном счёте сообщается один раз
+    NotCreated {
+        op_id: OpId,
+        account_id: A::Id,
+    },
+    //операция истории не применяется к состоянию, построенному из предыдущих операций
+    BrokenHistory {
+        op_id: OpId,
+        message: String,
+    },
+    //счёт в состоянии отличается от восстановленного по истории, None - счёта нет
+    Mismatch {
+        account_id: A::Id,
+        expected: Option<A>,
+        actual: Option<A>,
+    },
+    //пополнения минус снятия (с учётом обменов) не равны сумме балансов счетов в валюте
+    Unbalanced {
+        currency: Currency,
+        turnover: SignedMoney,
+        balances: SignedMoney,
+    },
+    //перевод между шардами (см. ShardedBank): номер шарда отправителя и OpId списания TransferOut.
+    //Списание без зачисления или отмены, с несколькими из них или зачисление без списания
+    UnpairedTransfer {
+        shard: usize,
+        id: OpId,
+    },
+}
+
+//отчёт сверки: сколько операций и счетов проверено и что с ними не так
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "A: BankAccount")]
+pub struct AuditReport<A: BankAccount = Account> {
+    pub ops: usize,
+    pub accounts: usize,
+    pub issues: Vec<AuditIssue<A>>,
+}
+
+impl<A: BankAccount> AuditReport<A> {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+//история применяется к пустому состоянию в памяти, результат сравнивается со state.
+//Архивированная (compact) история даёт NotCreated для счетов из архива.
+//Ошибка чтения истории или состояния - не расхождение, а ошибка сверки
+pub(crate) fn audit<S: State>(
+    history: impl Iterator<Item = Result<Entry<IdOf<S>>, BankError>>,
+    state: &S,
+) -> Result<AuditReport<S::Account>, BankError>
+where
+    <S::Account as BankAccount>::Balance: Into<SignedMoney>,
+{
+    let mut replayed = InMemoryState::<S::Account>::default();
+    let mut created = HashSet::new();
+    let mut not_created = HashSet::new();
+    let mut turnover: BTreeMap<Currency, SignedMoney> = BTreeMap::new();
+    let mut issues = Vec::new();
+    let mut ops = 0;
+
+    for entry in history {
+        let (op_id, _, op) = entry?;
+        ops += 1;
+        if let Operation::Create(account_id) | Operation::CreateIn(account_id, _) = &op {
+            created.insert(account_id.clone());
+        }
+        let missing: Vec<_> = op
+            .account_ids()
+            .into_iter()
+            .filter(|account_id| !created.contains(account_id))
+            .collect();
+        if !missing.is_empty() {
+            for account_id in missing {
+                if not_created.insert(account_id.clone()) {
+                    issues.push(AuditIssue::NotCreated { op_id, account_id });
+                }
+            }
+            continue;
+        }
+        if let Err(err) = replayed.update(&op) {
+            issues.push(AuditIssue::BrokenHistory {
+                op_id,
+                message: err.to_string(),
+            });
+            continue;
+        }
+        //переводы и комиссии только перекладывают деньги между счетами одной валюты
+        let mut add = |currency: Currency, money: SignedMoney| {
+            let total = turnover.entry(currency).or_default();
+            *total = total.saturating_add(money);
+        };
+        let currency = |account_id| replayed.get_balance(account_id).map(|a| a.currency());
+        match &op {
+            Operation::Deposit(account_id, amount) => {
+                if let Ok(currency) = currency(account_id) {
+                    add(currency, SignedMoney::from(amount.get()))
+                }
+            }
+            Operation::Withdraw(account_id, amount) => {
+                if let Ok(currency) = currency(account_id) {
+                    add(currency, -SignedMoney::from(amount.get()))
+                }
+            }
+            Operation::Exchange { amount, rate, .. } => {
+                add(rate.from, -SignedMoney::from(amount.get()));
+                if let Ok(converted) = rate.convert(*amount) {
+                    add(rate.to, SignedMoney::from(converted.get()));
+                }
+            }
+            //в шарде перевод между шардами выглядит как снятие или пополнение
+            Operation::TransferOut { from, amount, .. } => {
+                if let Ok(currency) = currency(from) {
+                    add(currency, -SignedMoney::from(amount.get()))
+                }
+            }
+            Operation::TransferIn {
+                to: account_id,
+                amount,
+                ..
+            }
+            | Operation::TransferCancel {
+                from: account_id,
+                amount,
+                ..
+            } => {
+                if let Ok(currency) = currency(account_id) {
+                    add(currency, SignedMoney::from(amount.get()))
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut balances: BTreeMap<Currency, SignedMoney> = BTreeMap::new();
+    let mut account_ids = HashSet::new();
+    for account in state.accounts()? {
+        let account = account?;
+        let total = balances.entry(account.currency()).or_default();
+        *total = total.saturating_add(account.balance().into());
+        account_ids.insert(account.account_id().clone());
+    }
+    for account in replayed.accounts()? {
+        account_ids.insert(account?.account_id().clone());
+    }
+    let mut account_ids: Vec<_> = account_ids.into_iter().collect();
+    account_ids.sort();
+    for account_id in &account_ids {
+        let expected = replayed.get_balance(account_id).ok();
+        let actual = state.get_balance(account_id).ok();
+        if expected != actual {
+            issues.push(AuditIssue::Mismatch {
+                account_id: account_id.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    let mut currencies: Vec<_> = turnover.keys().chain(balances.keys()).copied().collect();
+    currencies.sort();
+    currencies.dedup();
+    for currency in currencies {
+        let turnover = turnover.get(&currency).copied().unwrap_or_default();
+        let balances = balances.get(&currency).copied().unwrap_or_default();
+        if turnover != balances {
+            issues.push(AuditIssue::Unbalanced {
+                currency,
+                turnover,
+                balances,
+            });
+        }
+    }
+
+    Ok(AuditReport {
+        ops,
+        accounts: account_ids.len(),
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bank::{AccountId, Bank, Entry, InMemoryOpsStorage, NonZeroMoney},
+        chain::ChainHash,
+        currency::StaticRates,
+        fee::FlatFee,
+    };
+
+    fn bank() -> Bank<InMemoryOpsStorage, InMemoryState> {
+        let mut bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default())
+            .with_fees(0, FlatFee(1))
+            .unwrap()
+            .with_rates(StaticRates::default().with(Currency::USD, Currency::RUB, 80_000_000));
+        let _ = bank.create_account(128);
+        let _ = bank.create_account_in(129, Currency::USD);
+        let _ = bank.deposit(&128, NonZeroMoney::new(100).unwrap());
+        let _ = bank.deposit(&129, NonZeroMoney::new(5).unwrap());
+        let _ = bank.move_money(129, 128, NonZeroMoney::new(2).unwrap());
+        let _ = bank.withdraw(128, NonZeroMoney::new(50).unwrap());
+        bank
+    }
+
+    //тот же банк с подменёнными историей и состоянием
+    fn tampered(
+        history: impl IntoIterator<Item = Entry<AccountId>>,
+        accounts: impl IntoIterator<Item = Account>,
+    ) -> Bank<InMemoryOpsStorage, InMemoryState> {
+        let mut storage = InMemoryOpsStorage::default();
+        let mut hash = ChainHash::GENESIS;
+        for (op_id, at, op) in history {
+            hash = hash.link(op_id, at, &op).unwrap();
+            storage.restore(op_id, at, op, hash).unwrap();
+        }
+        Bank::new(storage, accounts.into_iter().collect())
+    }
+
+    #[test]
+    fn audit_should_pass_consistent_bank() {
+        let bank = bank();
+        let report = bank.audit().unwrap();
+        assert_eq!(report.issues, vec![]);
+        assert!(report.is_consistent());
+        assert_eq!(report.accounts, 3);
+        assert_eq!(report.ops, bank.get_history().unwrap().count());
+    }
+
+    #[test]
+    fn audit_should_find_state_changed_outside_history() {
+        let bank = bank();
+        let history: Vec<_> = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let accounts = || [0, 128, 129].map(|id| bank.get_balance(&id).unwrap());
+
+        //деньги появились на счёте без операции
+        let mut changed = accounts();
+        changed[1].balance += 10;
+        let report = tampered(history.clone(), changed.clone()).audit().unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                AuditIssue::Mismatch {
+                    account_id: 128,
+                    expected: Some(accounts()[1].clone()),
+                    actual: Some(changed[1].clone()),
+                },
+                AuditIssue::Unbalanced {
+                    currency: Currency::RUB,
+                    turnover: 210,
+                    balances: 220,
+                },
+            ]
+        );
+
+        //счёт есть только в состоянии
+        let mut extra = accounts().to_vec();
+        extra.push(Account::open(130));
+        let report = tampered(history, extra).audit().unwrap();
+        assert_eq!(
+            report.issues,
+            vec![AuditIssue::Mismatch {
+                account_id: 130,
+                expected: None,
+                actual: Some(Account::open(130)),
+            }]
+        );
+    }
+
+    #[test]
+    fn audit_should_find_broken_history() {
+        let bank = bank();
+        let mut history: Vec<_> = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let accounts: Vec<_> = [0, 128, 129]
+            .map(|id| bank.get_balance(&id).unwrap())
+            .to_vec();
+        let (create_id, _, _) = history.remove(1);
+        let last_op_id = history.last().unwrap().0;
+        history.push((
+            last_op_id.checked_add(1).unwrap(),
+            0,
+            Operation::Withdraw(129, NonZeroMoney::new(1_000).unwrap()),
+        ));
+
+        let report = tampered(history, accounts).audit().unwrap();
+        assert!(!report.is_consistent());
+        //без Create счёта 128 все его операции пропускаются, поэтому счёт не восстановлен
+        assert_eq!(
+            report.issues[0],
+            AuditIssue::NotCreated {
+                op_id: create_id.checked_add(2).unwrap(),
+                account_id: 128,
+            }
+        );
+        assert!(matches!(
+            report.issues[1],
+            AuditIssue::BrokenHistory { op_id, .. } if op_id == last_op_id.checked_add(1).unwrap()
+        ));
+        assert!(report.issues.contains(&AuditIssue::Mismatch {
+            account_id: 128,
+            expected: None,
+            actual: Some(bank.get_balance(&128).unwrap().clone()),
+        }));
+    }
+}
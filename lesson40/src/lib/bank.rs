@@ -1,15 +1,34 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap, LinkedList},
-    num::{NonZeroU128, NonZeroU32},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{Debug, Display},
+    hash::Hash,
+    num::{NonZeroU128, NonZeroU64},
+    ops::Bound,
 };
 use thiserror::Error;
 
+use crate::{
+    audit::{self, AuditReport},
+    chain::{self, ChainHash, ChainReport},
+    clock::{Clock, SystemClock, Timestamp},
+    concurrent::ConcurrentBank,
+    currency::{Currency, ExchangeRates, Rate},
+    fee::{FeeKind, FeePolicy, Fees},
+    history::{self, HistoryPage, HistoryQuery},
+    idempotency::{self, Receipt, Receipts, RequestFingerprint, RequestKey},
+    snapshot::Snapshot,
+    statement::{self, Statement},
+};
+
 pub type AccountId = u128;
-pub type Money = u32;
-pub type NonZeroMoney = NonZeroU32;
+pub type Money = u64;
+//баланс счёта может быть отрицательным в пределах кредитного лимита
+pub type SignedMoney = i128;
+pub type NonZeroMoney = NonZeroU64;
 pub type OpId = NonZeroU128;
 
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum BankError {
     #[error("Something get wrong in the bank core facility[{0}]")]
     CoreError(String),
@@ -17,524 +36,1978 @@ pub enum BankError {
     Prohibited(String),
     #[error("Bad request[{0}]")]
     BadRequest(String),
+    //история зашифрована другим ключом (или повреждена так, что ключ её не подтверждает)
+    #[error("Operation log can't be decrypted with the supplied key[{0}]")]
+    WrongKey(String),
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Clone)]
-pub struct Account {
-    pub account_id: AccountId,
-    pub balance: Money,
+impl From<std::io::Error> for BankError {
+    fn from(err: std::io::Error) -> Self {
+        BankError::CoreError(format!("I/O error: {err}"))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Operation {
-    /**
-     * Перед выполнением любых операций по счёту, его необходимо создать.
-     * Если счёт с именем Х существует, то создание нового счёта с именем Х - ошибка
-     */
-    Create(AccountId), //регистрация счёта
+///Идентификатор счёта: любой тип, который можно сравнивать, хешировать и сохранять
+pub trait BankAccountId:
+    Clone + Eq + Hash + Ord + Debug + Display + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+}
 
-    /**Пополнение увеличивает количество денег на счете на указанную сумму.
-     * Пополнение на ноль денежных единиц - ошибка.
-     */
-    Deposit(AccountId, NonZeroMoney), //пополнение
-    /**
-     * Снятие уменьшает количество денег на счете на указанную сумму.
-     * Снятие нуля денежных единиц - ошибка.
-     * Попытка снять больше чем есть на счете - ошибка.
-     */
-    Withdraw(AccountId, NonZeroMoney), //снятие
-                                       //перевод реализован через сумму операций Withdraw + Deposit
+impl<T> BankAccountId for T where
+    T: Clone
+        + Eq
+        + Hash
+        + Ord
+        + Debug
+        + Display
+        + Send
+        + Sync
+        + Serialize
+        + DeserializeOwned
+        + 'static
+{
 }
 
-impl Operation {
-    fn account_id(&self) -> &AccountId {
-        match self {
-            Operation::Create(account_id) => account_id,
-            Operation::Deposit(account_id, _) | Self::Withdraw(account_id, _) => account_id,
+///Счёт банка, реализацию которого выбирает клиент библиотеки:
+///тип идентификатора, тип баланса и дополнительные поля.
+///Правила пополнения и снятия определяет сам счёт
+pub trait BankAccount:
+    Debug + Clone + PartialEq + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+    type Id: BankAccountId;
+    type Balance: Debug + Display + Clone + PartialEq;
+
+    //новый счёт с нулевым балансом
+    fn open(account_id: Self::Id) -> Self;
+
+    //новый счёт в указанной валюте; счёт без поддержки валют открывается только в базовой
+    fn open_in(account_id: Self::Id, currency: Currency) -> Result<Self, BankError> {
+        if currency == Currency::default() {
+            Ok(Self::open(account_id))
+        } else {
+            Err(BankError::Prohibited(format!(
+                "Account[{account_id}] can't be opened in currency[{currency}]"
+            )))
         }
     }
-}
 
-///Банк имеет хранилище операций по счетам клиентов
-pub trait OpsStorage {
-    fn transact(
-        &mut self,
-        ops: impl Iterator<Item = Operation>,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError>;
+    fn account_id(&self) -> &Self::Id;
 
-    fn persist(&mut self, op: Operation) -> Result<(OpId, &Operation), BankError> {
-        self.transact(std::iter::once(op)).and_then(|mut iter| {
-            iter.next().ok_or_else(|| {
-                BankError::CoreError(
-                    "a transaction should return at least one operation".to_owned(),
-                )
-            })
-        })
+    fn currency(&self) -> Currency {
+        Currency::default()
     }
-    //should be O(N), where N - account ops
-    fn get_ops<'a, 'b>(
-        &'a self,
-        account_id: &'b AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &'a Operation)>, BankError>;
-    //should be O(M), where M - all ops
-    fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError>;
-}
 
-///Банк имеет текущее "состояние" счетов клиентов
-pub trait State {
-    fn transact<'a, 'b>(
-        &'a mut self,
-        ops: impl Iterator<Item = &'b Operation>,
-    ) -> Result<impl Iterator<Item = &'a Account> + 'a, BankError>;
+    fn balance(&self) -> Self::Balance;
 
-    fn update<'a>(&'a mut self, op: &Operation) -> Result<&'a Account, BankError> {
-        self.transact(std::iter::once(op)).and_then(|mut iter| {
-            iter.next().ok_or_else(|| {
-                BankError::CoreError("a transaction should return at least one account".to_owned())
-            })
-        })
+    fn deposit(&mut self, money: NonZeroMoney) -> Result<(), BankError>;
+
+    fn withdraw(&mut self, money: NonZeroMoney) -> Result<(), BankError>;
+
+    fn status(&self) -> AccountStatus {
+        AccountStatus::Active
+    }
+
+    //счёт сам решает, можно ли его заморозить или закрыть
+    fn set_status(&mut self, status: AccountStatus) -> Result<(), BankError> {
+        Err(BankError::Prohibited(format!(
+            "Account[{}] can't be {status}",
+            self.account_id()
+        )))
+    }
+
+    //остаток, который переводится на другой счёт при закрытии
+    fn funds(&self) -> Money {
+        0
+    }
+
+    //счёт без поддержки кредита не может уйти в минус
+    fn set_credit_limit(&mut self, limit: Money) -> Result<(), BankError> {
+        Err(BankError::Prohibited(format!(
+            "Account[{}] doesn't support credit limit[{limit}]",
+            self.account_id()
+        )))
     }
-    //should be O(N), where N - account ops
-    fn get_balance(&self, account_id: &AccountId) -> Result<&Account, BankError>;
 }
 
-#[derive(Debug, Default)]
-pub struct Bank<T, S> {
-    storage: T,
-    state: S,
+//состояние счёта: замороженный счёт не участвует в операциях до разморозки, закрытый - никогда
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Frozen,
+    Closed,
 }
 
-impl<T: OpsStorage, S: State> Bank<T, S> {
-    pub fn new(storage: T, state: S) -> Bank<T, S> {
-        Bank { storage, state }
+impl Display for AccountStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountStatus::Active => f.write_str("active"),
+            AccountStatus::Frozen => f.write_str("frozen"),
+            AccountStatus::Closed => f.write_str("closed"),
+        }
     }
+}
 
-    pub fn from<'a>(history: impl Iterator<Item = (OpId, &'a Operation)>) -> Bank<T, S>
-    where
-        S: Default,
-        T: Default,
-    {
-        let mut bank: Bank<T, S> = Bank::default();
+//идентификатор счёта, с которым работает State
+pub type IdOf<S> = <<S as State>::Account as BankAccount>::Id;
 
-        for (_, op) in history {
-            let (_, op) = bank
-                .storage
-                .persist(op.clone())
-                .unwrap_or_else(|_| panic!("something wrong with history operation[{:?}]", op));
+//операция вместе с её идентификатором и временем
+pub type Entry<Id = AccountId> = (OpId, Timestamp, Operation<Id>);
 
-            if let Err(err) = bank.state.update(op) {
-                println!("History operation has an error[{:?}]", err)
-            }
+//операция истории и хэш цепочки, с которым она сохранена
+pub type HashedEntry<Id = AccountId> = (Entry<Id>, ChainHash);
+
+//операции вместе с их идентификаторами и временем, например, архив после compact
+pub type History<Id = AccountId> = Vec<Entry<Id>>;
+
+//счета, изменённые транзакцией, но ещё не зафиксированные в состоянии
+pub(crate) type Overlay<A> = HashMap<<A as BankAccount>::Id, A>;
+
+//копии счетов до транзакции, None - счёта ещё не было (см. State::revert)
+pub type Prior<A> = Vec<(<A as BankAccount>::Id, Option<A>)>;
+
+//счёт по умолчанию
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub account_id: AccountId,
+    pub balance: SignedMoney,
+    pub currency: Currency,
+    pub credit_limit: Money,
+    pub status: AccountStatus,
+}
+
+impl Account {
+    //сколько можно снять с учётом кредитного лимита
+    pub fn available(&self) -> SignedMoney {
+        self.balance
+            .saturating_add(SignedMoney::from(self.credit_limit))
+    }
+
+    fn overflow(&self) -> BankError {
+        BankError::BadRequest(format!("Balance of account[{}] overflows", self.account_id))
+    }
+}
+
+impl BankAccount for Account {
+    type Id = AccountId;
+    type Balance = SignedMoney;
+
+    fn open(account_id: AccountId) -> Self {
+        Account {
+            account_id,
+            balance: 0,
+            currency: Currency::default(),
+            credit_limit: 0,
+            status: AccountStatus::Active,
         }
+    }
 
-        bank
+    fn open_in(account_id: AccountId, currency: Currency) -> Result<Self, BankError> {
+        Ok(Account {
+            currency,
+            ..Account::open(account_id)
+        })
     }
 
-    //создание аккаунта
-    pub fn create_account(&mut self, account_id: AccountId) -> Result<&Account, BankError> {
-        let op = Operation::Create(account_id);
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
     }
 
-    //Клиент может получить свой баланс.
-    pub fn get_balance(&self, account_id: &AccountId) -> Result<&Account, BankError> {
-        self.state.get_balance(account_id)
+    fn currency(&self) -> Currency {
+        self.currency
     }
 
-    //история операций по счету
-    pub fn get_account_ops<'a, 'b>(
-        &'a self,
-        account_id: &'b AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &'a Operation)> + 'b, BankError>
-    where
-        'a: 'b,
-    {
-        self.storage.get_ops(account_id)
+    fn balance(&self) -> SignedMoney {
+        self.balance
     }
-    //можно получить историю операций
-    pub fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError> {
-        self.storage.get_history()
+
+    fn deposit(&mut self, money: NonZeroMoney) -> Result<(), BankError> {
+        self.balance = self
+            .balance
+            .checked_add(SignedMoney::from(money.get()))
+            .ok_or_else(|| self.overflow())?;
+        Ok(())
     }
 
-    //Клиент может пополнить свой баланс.
-    pub fn deposit(
-        &mut self,
-        account_id: &AccountId,
-        money: NonZeroMoney,
-    ) -> Result<&Account, BankError> {
-        let op = Operation::Deposit(*account_id, money);
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+    fn withdraw(&mut self, money: NonZeroMoney) -> Result<(), BankError> {
+        if self.available() >= SignedMoney::from(money.get()) {
+            self.balance = self
+                .balance
+                .checked_sub(SignedMoney::from(money.get()))
+                .ok_or_else(|| self.overflow())?;
+            Ok(())
+        } else {
+            Err(BankError::BadRequest(format!(
+                "Insufficient funds, available[{}] with credit limit[{}]",
+                self.available().max(0),
+                self.credit_limit
+            )))
+        }
     }
 
-    //Клиент может забрать деньги
-    pub fn withdraw(
-        &mut self,
-        account_id: AccountId,
-        money: NonZeroMoney,
-    ) -> Result<&Account, BankError> {
-        let op = Operation::Withdraw(account_id, money);
-        let (_, op) = self.storage.persist(op)?;
-        self.state.update(op)
+    fn status(&self) -> AccountStatus {
+        self.status
     }
 
-    //перемещение денег от счета на счет
-    pub fn move_money(
-        &mut self,
-        from: AccountId,
-        to: AccountId,
-        money: NonZeroMoney,
-    ) -> Result<(&Account, &Account), BankError> {
-        if from == to {
-            return Err(BankError::Prohibited(format!(
-                "Sending funds to yourself[{to}] is prohibited"
+    //закрыть можно только пустой счёт
+    fn set_status(&mut self, status: AccountStatus) -> Result<(), BankError> {
+        if status == AccountStatus::Closed && self.balance != 0 {
+            return Err(BankError::BadRequest(format!(
+                "Account[{}] can't be closed with balance[{}]",
+                self.account_id, self.balance
             )));
         }
+        self.status = status;
+        Ok(())
+    }
 
-        let ops = vec![
-            Operation::Withdraw(from, money),
-            Operation::Deposit(to, money),
-        ]
-        .into_iter();
-        //first save to storage
-        let ops = self.storage.transact(ops)?.map(|(_, op)| op);
-
-        //and then update state
-        let mut accounts = self.state.transact(ops)?;
-        let from = accounts.next().ok_or_else(|| {
-            BankError::CoreError(
-                "the operation did not return the required number of elements".to_owned(),
-            )
-        })?;
-        let to = accounts.next().ok_or_else(|| {
-            BankError::CoreError(
-                "the operation did not return the required number of elements".to_owned(),
-            )
-        })?;
-        Ok((from, to))
+    fn funds(&self) -> Money {
+        Money::try_from(self.balance.max(0)).unwrap_or(Money::MAX)
+    }
+
+    //лимит нельзя уменьшить ниже текущего долга
+    fn set_credit_limit(&mut self, limit: Money) -> Result<(), BankError> {
+        if self.balance.saturating_add(SignedMoney::from(limit)) < 0 {
+            return Err(BankError::BadRequest(format!(
+                "Credit limit[{limit}] is less than the debt[{}] of account[{}]",
+                -self.balance, self.account_id
+            )));
+        }
+        self.credit_limit = limit;
+        Ok(())
     }
 }
 
-//реализация State для банка в памяти
-#[derive(Debug, Default)]
-pub struct InMemoryState(HashMap<AccountId, Account>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation<Id = AccountId> {
+    /**
+     * Перед выполнением любых операций по счёту, его необходимо создать.
+     * Если счёт с именем Х существует, то создание нового счёта с именем Х - ошибка
+     */
+    Create(Id), //регистрация счёта
 
-impl<'a> InMemoryState {
-    fn push_to_col(
-        &'a mut self,
-        op: &'a Operation,
-    ) -> Result<(&'a AccountId, &'a Account), BankError> {
-        let account_id: &AccountId = match op {
-            Operation::Create(account_id) if self.0.contains_key(account_id) => {
-                return Err(BankError::BadRequest(format!(
-                    "Bank already contains account_id[{}]",
-                    account_id
-                )));
-            }
+    /**Пополнение увеличивает количество денег на счете на указанную сумму.
+     * Пополнение на ноль денежных единиц - ошибка.
+     */
+    Deposit(Id, NonZeroMoney), //пополнение
+    /**
+     * Снятие уменьшает количество денег на счете на указанную сумму.
+     * Снятие нуля денежных единиц - ошибка.
+     * Попытка снять больше чем есть на счете - ошибка.
+     */
+    Withdraw(Id, NonZeroMoney), //снятие
+    /**
+     * Комиссия списывается со счёта from и зачисляется на специальный счёт банка to.
+     * Если на счёте from недостаточно денег для оплаты комиссии - ошибка.
+     */
+    Fee {
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+    },
+    CreateIn(Id, Currency), //регистрация счёта в указанной валюте
 
-            Operation::Create(account_id) => {
-                let new_account = Account {
-                    account_id: *account_id,
-                    balance: 0_u32,
-                };
-                self.0.insert(*account_id, new_account);
-                account_id
-            }
+    /**
+     * Перевод между счетами в разных валютах: со счёта from списывается amount,
+     * на счёт to зачисляется amount, пересчитанный по курсу rate.
+     * Курс сохраняется в истории, поэтому восстановление не зависит от текущих курсов.
+     * Если валюты счетов не совпадают с валютами курса - ошибка.
+     */
+    Exchange {
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+        rate: Rate,
+    },
 
-            Operation::Deposit(account_id, money) if self.0.contains_key(account_id) => {
-                let account: &mut Account = self.0.get_mut(account_id).unwrap();
-                account.balance += money.get();
-                account_id
-            }
+    /**
+     * Установка кредитного лимита: баланс счёта может уйти в минус не больше чем на лимит.
+     * Лимит меньше текущего долга - ошибка.
+     */
+    SetCreditLimit(Id, Money),
 
-            Operation::Withdraw(account_id, money) if self.0.contains_key(account_id) => {
-                let account: &mut Account = self.0.get_mut(account_id).unwrap();
-                if account.balance >= money.get() {
-                    account.balance -= money.get();
-                } else {
-                    return Err(BankError::BadRequest("Insufficient funds".to_string()));
-                }
-                account_id
-            }
+    /**
+     * Заморозка запрещает любые операции по счёту, кроме разморозки.
+     * Заморозка не активного счёта или разморозка не замороженного - ошибка.
+     */
+    Freeze(Id),
+    Unfreeze(Id),
 
-            Operation::Withdraw(account_id, _) | Operation::Deposit(account_id, _) => {
-                return Err(BankError::BadRequest(format!(
-                    "Bank doesnt contain account_id[{}]",
-                    account_id
-                )))
-            }
-        };
+    /**
+     * Закрытие счёта навсегда. Закрыть можно только активный счёт с нулевым балансом,
+     * остаток предварительно переводится на другой счёт в той же транзакции.
+     */
+    Close(Id),
 
-        Ok(
-            self.0
-                .get(account_id)
-                .map(|account| (account_id, account))
-                .expect("something is wrong with your code"), //здесь expect потому, что, если такого аккаунта нет, то это ошибка в коде
-        )
-    }
-}
+    /**
+     * Перевод между счетами в одной валюте: со счёта from списывается amount и зачисляется на счёт to.
+     * Операция попадает в историю обоих счетов.
+     * Если на счёте from недостаточно денег или перевод самому себе - ошибка.
+     */
+    Transfer {
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+    },
 
-impl State for InMemoryState {
-    fn get_balance(&self, account_id: &AccountId) -> Result<&Account, BankError> {
-        self.0.get(account_id).ok_or_else(|| {
-            BankError::BadRequest(format!("Account[{}] not found in bank", account_id))
-        })
-    }
+    /**
+     * Квитанция запроса с ключом key, последняя операция его транзакции.
+     * Состояние счетов не меняет, хранит счета транзакции (account_id и related) и списанную комиссию,
+     * чтобы повторный запрос с тем же ключом получил исходный ответ, в том числе после перезапуска.
+     */
+    Receipt {
+        key: RequestKey,
+        fingerprint: RequestFingerprint,
+        account_id: Id,
+        related: Vec<Id>,
+        fee: Money,
+    },
 
-    fn transact<'a, 'b>(
-        &'a mut self,
-        ops: impl Iterator<Item = &'b Operation>,
-    ) -> Result<impl Iterator<Item = &'a Account> + 'a, BankError> {
-        let mut successful_accounts_ids = Vec::new();
+    /**
+     * Первая фаза перевода на счёт другого шарда (см. ShardedBank): со счёта from списывается amount.
+     * Операция хранится только в шарде from, её OpId - идентификатор перевода в следующих фазах.
+     */
+    TransferOut {
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+    },
 
-        // Фаза 1: модификация данных
-        for op in ops {
-            match self.push_to_col(op) {
-                Ok((account_id, _)) => successful_accounts_ids.push(*account_id), // Сохраняем ID аккаунтов
-                Err(err) => return Err(err),
-            }
-        }
+    /**
+     * Вторая фаза перевода id из шарда счёта from: на счёт to зачисляется amount.
+     * Хранится только в шарде to.
+     */
+    TransferIn {
+        id: OpId,
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+    },
 
-        // Фаза 2: чтение данных (поиск по ID, который мы сохранили)
-        Ok(successful_accounts_ids.into_iter().map(|account_id| {
-            self.0
-                .get(&account_id)
-                .expect("something is wrong with your code")
-        }))
-    }
+    /**
+     * Отмена перевода id, если зачисление невозможно: amount возвращается на счёт from.
+     * Возврат не проверяет статус счёта и не может быть отклонён.
+     */
+    TransferCancel {
+        id: OpId,
+        from: Id,
+        to: Id,
+        amount: NonZeroMoney,
+    },
 }
 
-//реализация хранилища операций банка в пмяти
-#[derive(Debug)]
-pub struct InMemoryOpsStorage {
-    cur_key: OpId,
-    by_ops_storage: BTreeMap<OpId, (AccountId, Operation)>,
-    by_acc_storage: HashMap<AccountId, LinkedList<OpId>>,
-}
+impl<Id: Clone> Operation<Id> {
+    pub fn account_id(&self) -> &Id {
+        match self {
+            Operation::Create(account_id) | Operation::CreateIn(account_id, _) => account_id,
+            Operation::Deposit(account_id, _) | Self::Withdraw(account_id, _) => account_id,
+            Operation::SetCreditLimit(account_id, _) => account_id,
+            Operation::Freeze(account_id)
+            | Operation::Unfreeze(account_id)
+            | Operation::Close(account_id)
+            | Operation::Receipt { account_id, .. } => account_id,
+            Operation::TransferOut { from, .. } | Operation::TransferCancel { from, .. } => from,
+            Operation::TransferIn { to, .. } => to,
+            Operation::Fee { from, .. }
+            | Operation::Exchange { from, .. }
+            | Operation::Transfer { from, .. } => from,
+        }
+    }
 
-impl Default for InMemoryOpsStorage {
-    fn default() -> Self {
-        Self {
-            cur_key: OpId::MIN,
-            by_ops_storage: BTreeMap::default(),
-            by_acc_storage: HashMap::default(),
+    //все счета, затронутые операцией; по ним операция индексируется в хранилище
+    pub fn account_ids(&self) -> Vec<Id> {
+        match self {
+            Operation::Fee { from, to, .. }
+            | Operation::Exchange { from, to, .. }
+            | Operation::Transfer { from, to, .. } => vec![from.clone(), to.clone()],
+            other => vec![other.account_id().clone()],
         }
     }
 }
 
-impl InMemoryOpsStorage {
-    fn push_to_cols(&mut self, op: Operation) -> OpId {
-        let new_key = self.cur_key.checked_add(1).unwrap();
+//вид операции без её данных, например, для фильтрации истории
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OperationKind {
+    Create, //в том числе в указанной валюте
+    Deposit,
+    Withdraw,
+    Fee,
+    Exchange,
+    SetCreditLimit,
+    Freeze,
+    Unfreeze,
+    Close,
+    Transfer,
+    Receipt,
+}
 
-        self.cur_key = new_key;
+impl<Id> Operation<Id> {
+    pub fn kind(&self) -> OperationKind {
+        match self {
+            Operation::Create(_) | Operation::CreateIn(..) => OperationKind::Create,
+            Operation::Deposit(..) => OperationKind::Deposit,
+            Operation::Withdraw(..) => OperationKind::Withdraw,
+            Operation::Fee { .. } => OperationKind::Fee,
+            Operation::Exchange { .. } => OperationKind::Exchange,
+            Operation::SetCreditLimit(..) => OperationKind::SetCreditLimit,
+            Operation::Freeze(_) => OperationKind::Freeze,
+            Operation::Unfreeze(_) => OperationKind::Unfreeze,
+            Operation::Close(_) => OperationKind::Close,
+            Operation::Transfer { .. } => OperationKind::Transfer,
+            Operation::Receipt { .. } => OperationKind::Receipt,
+            //фазы перевода между шардами
+            Operation::TransferOut { .. }
+            | Operation::TransferIn { .. }
+            | Operation::TransferCancel { .. } => OperationKind::Transfer,
+        }
+    }
 
-        let account_id = *op.account_id();
+    //сумма, которую переносит операция; у обмена - в валюте счёта from
+    pub fn amount(&self) -> Option<Money> {
+        match self {
+            Operation::Deposit(_, amount)
+            | Operation::Withdraw(_, amount)
+            | Operation::Fee { amount, .. }
+            | Operation::Exchange { amount, .. }
+            | Operation::Transfer { amount, .. }
+            | Operation::TransferOut { amount, .. }
+            | Operation::TransferIn { amount, .. }
+            | Operation::TransferCancel { amount, .. } => Some(amount.get()),
+            _ => None,
+        }
+    }
+}
 
-        self.by_ops_storage.insert(new_key, (account_id, op));
+impl<Id: PartialEq> Operation<Id> {
+    //другой счёт операции с точки зрения счёта account_id, если он есть
+    pub fn counterparty(&self, account_id: &Id) -> Option<&Id> {
+        match self {
+            Operation::Fee { from, to, .. }
+            | Operation::Exchange { from, to, .. }
+            | Operation::Transfer { from, to, .. }
+            | Operation::TransferOut { from, to, .. }
+            | Operation::TransferIn { from, to, .. }
+            | Operation::TransferCancel { from, to, .. } => {
+                if from == account_id {
+                    Some(to)
+                } else if to == account_id {
+                    Some(from)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
 
-        self.by_acc_storage
-            .entry(account_id)
-            .and_modify(|list| list.push_back(new_key))
-            .or_insert({
-                let mut tmp = LinkedList::new();
-                tmp.push_front(new_key);
-                tmp
-            });
+///Банк имеет хранилище операций по счетам клиентов.
+///Операции отдаются по значению, а каждая из них может не прочитаться, поэтому хранилище
+///может читать их с диска, из базы или по сети по мере обхода
+pub trait OpsStorage {
+    type Id: BankAccountId;
+    //операции хранилища обращаются к диску или сети и блокируют поток, см. AsyncBank
+    const BLOCKING: bool = true;
 
-        new_key
+    //все операции транзакции помечаются одним временем at, возвращаются их идентификаторы
+    fn transact(
+        &mut self,
+        at: Timestamp,
+        ops: &[Operation<Self::Id>],
+    ) -> Result<Vec<OpId>, BankError>;
+
+    fn persist(&mut self, at: Timestamp, op: &Operation<Self::Id>) -> Result<OpId, BankError> {
+        self.transact(at, std::slice::from_ref(op))?
+            .pop()
+            .ok_or_else(|| {
+                BankError::CoreError(
+                    "a transaction should return at least one operation".to_owned(),
+                )
+            })
     }
-}
+    //should be O(N), where N - account ops
+    fn get_ops(
+        &self,
+        account_id: &Self::Id,
+    ) -> Result<impl Iterator<Item = Result<Entry<Self::Id>, BankError>>, BankError>;
+    //should be O(M), where M - all ops
+    fn get_history(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<Entry<Self::Id>, BankError>>, BankError>;
 
-impl OpsStorage for InMemoryOpsStorage {
-    fn get_history(&self) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError> {
+    //операции счёта, сохранённые после указанной, для постраничных запросов
+    fn get_ops_after(
+        &self,
+        account_id: &Self::Id,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Self::Id>, BankError>>, BankError> {
         Ok(self
-            .by_ops_storage
-            .iter()
-            .map(|(op_id, (_, operation))| (*op_id, operation)))
+            .get_ops(account_id)?
+            .skip_while(move |entry| matches!(entry, Ok((op_id, _, _)) if *op_id <= after)))
     }
 
-    fn get_ops(
+    //операции, сохранённые после указанной (например, после снимка состояния)
+    fn get_history_after(
         &self,
-        account_id: &AccountId,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError> {
-        self.by_acc_storage
-                .get(account_id)//O(1)
-                .map(|list| {
-                    list.iter().map(|op_id|{ //O(N)
-                        let (_, op)=self.by_ops_storage.get(op_id)//O(lgN)
-                        .unwrap_or_else(||panic!("something get wrong with your code, because by_ops_storage doesn't contain value for op_id[{}]",op_id));
-                        (*op_id,op)
-                    })
-                }).ok_or_else(||BankError::BadRequest(format!("There is no account[{}] in the bank",account_id)))
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Self::Id>, BankError>>, BankError> {
+        Ok(self
+            .get_history()?
+            .skip_while(move |entry| matches!(entry, Ok((op_id, _, _)) if *op_id <= after)))
     }
 
-    fn transact(
-        &mut self,
-        ops: impl Iterator<Item = Operation>,
-    ) -> Result<impl Iterator<Item = (OpId, &Operation)>, BankError> {
-        let mut vec = Vec::new();
-        for op in ops {
-            let op_id = self.push_to_cols(op);
-            vec.push(op_id);
-        }
+    //идентификатор последней сохранённой операции, OpId::MIN - если операций не было
+    fn last_op_id(&self) -> Result<OpId, BankError> {
+        self.get_history()?
+            .try_fold(OpId::MIN, |_, entry| entry.map(|(op_id, _, _)| op_id))
+    }
 
-        Ok(vec.into_iter().map(|op_id| {
-            let (_, op) = self.by_ops_storage.get(&op_id).unwrap_or_else(|| {
-                panic!(
-                    "something wrong with your code, by_ops_storage should contain op_id[{}]",
-                    op_id
-                )
-            });
-            (op_id, op)
+    //хэш цепочки, с которым сохранена операция op_id (см. chain)
+    fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError>;
+
+    //вся история вместе с хэшами операций за один проход (см. chain::verify).
+    //По умолчанию хэш каждой операции читается через get_hash
+    fn get_hashed_history(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<HashedEntry<Self::Id>, BankError>>, BankError> {
+        Ok(self.get_history()?.map(|entry| {
+            let entry = entry?;
+            let hash = self.get_hash(entry.0)?;
+            Ok((entry, hash))
         }))
     }
-}
 
-#[cfg(test)]
-mod test {
+    //хэш последней сохранённой операции, ChainHash::GENESIS - если операций не было.
+    //Совпадение с опубликованной ранее головой подтверждает, что история до неё не изменена
+    fn head_hash(&self) -> Result<ChainHash, BankError>;
 
-    use super::*;
+    //удаляет из хранилища операции старше `before` и возвращает их для архивирования.
+    //Счета, все операции которых удалены, остаются известны хранилищу
+    fn compact(&mut self, before: OpId) -> Result<History<Self::Id>, BankError> {
+        Err(BankError::Prohibited(format!(
+            "This storage can't compact operations before[{before}]"
+        )))
+    }
+}
 
-    #[test]
-    fn bank_should_create_account() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+///Банк имеет текущее "состояние" счетов клиентов.
+///Счета отдаются копиями, поэтому состояние может храниться не в памяти
+pub trait State {
+    type Account: BankAccount;
 
-        let acc_1 = 128;
-        let acc_2 = 129;
+    //операции применяются атомарно: либо все, либо ни одной.
+    //Возвращаются счета, ради которых выполнялись операции, после транзакции
+    fn transact<'b>(
+        &mut self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<Vec<Self::Account>, BankError>;
 
-        let ret = Bank::create_account(&mut bank, acc_1);
-        assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 0_u32
-            })
-        );
+    fn update(&mut self, op: &Operation<IdOf<Self>>) -> Result<Self::Account, BankError> {
+        self.transact(std::iter::once(op))?.pop().ok_or_else(|| {
+            BankError::CoreError("a transaction should return at least one account".to_owned())
+        })
+    }
+    //возвращает счета к копиям, снятым до транзакции (None - счёта не было).
+    //Так отменяется транзакция, которую не удалось сохранить в истории
+    fn revert(&mut self, accounts: Prior<Self::Account>) -> Result<(), BankError>;
 
-        let ret = Bank::create_account(&mut bank, acc_2);
-        assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_2.clone(),
-                balance: 0_u32
+    //копии счетов, которые изменят операции, снятые до транзакции для revert
+    fn prior<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<Prior<Self::Account>, BankError> {
+        let mut account_ids: Vec<_> = ops.flat_map(|op| op.account_ids()).collect();
+        account_ids.sort();
+        account_ids.dedup();
+        account_ids
+            .into_iter()
+            .map(|account_id| match self.get_balance(&account_id) {
+                Ok(account) => Ok((account_id, Some(account))),
+                Err(BankError::BadRequest(_)) => Ok((account_id, None)),
+                Err(err) => Err(err),
             })
-        );
-        drop(ret);
+            .collect()
     }
 
-    #[test]
-    fn bank_should_deposit_funds() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
-
-        let acc_1 = 128;
-        let acc_2 = 129;
+    //проверяет, что операции могут быть применены, не изменяя состояние
+    fn validate<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<(), BankError>;
 
-        let _ = bank.create_account(acc_1);
-        let _ = bank.create_account(acc_2);
+    //should be O(N), where N - account ops
+    fn get_balance(&self, account_id: &IdOf<Self>) -> Result<Self::Account, BankError>;
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+    //все счета состояния в произвольном порядке
+    fn accounts(&self)
+        -> Result<impl Iterator<Item = Result<Self::Account, BankError>>, BankError>;
+}
+
+#[derive(Debug)]
+pub struct Bank<T, S: State> {
+    storage: T,
+    state: S,
+    fees: Option<Fees<IdOf<S>>>,
+    rates: Option<Box<dyn ExchangeRates>>,
+    receipts: Receipts<S::Account>,
+    //ключ и отпечаток запроса, которыми помечается следующая транзакция
+    request_key: Option<(RequestKey, RequestFingerprint)>,
+    clock: Box<dyn Clock>,
+}
+
+impl<T: Default, S: State + Default> Default for Bank<T, S> {
+    fn default() -> Self {
+        Bank {
+            storage: T::default(),
+            state: S::default(),
+            fees: None,
+            rates: None,
+            receipts: Receipts::default(),
+            request_key: None,
+            clock: Box::new(SystemClock),
+        }
+    }
+}
+
+impl<T, S> Bank<T, S>
+where
+    T: OpsStorage<Id = IdOf<S>>,
+    S: State,
+{
+    pub fn new(storage: T, state: S) -> Bank<T, S> {
+        Bank {
+            storage,
+            state,
+            fees: None,
+            rates: None,
+            receipts: Receipts::default(),
+            request_key: None,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    //время операций берётся из clock, по умолчанию - системные часы
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Bank<T, S> {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    //банк помнит квитанции не больше чем capacity последних запросов с ключами
+    pub fn with_receipts_capacity(mut self, capacity: usize) -> Bank<T, S> {
+        self.receipts.set_capacity(capacity);
+        self
+    }
+
+    //за операции взимается комиссия по политике policy, она зачисляется на специальный счёт,
+    //который создаётся, если его ещё нет
+    pub fn with_fees(
+        mut self,
+        fee_account_id: IdOf<S>,
+        policy: impl FeePolicy + 'static,
+    ) -> Result<Bank<T, S>, BankError> {
+        if self.state.get_balance(&fee_account_id).is_err() {
+            self.create_account(fee_account_id.clone())?;
+        }
+        self.fees = Some(Fees {
+            account_id: fee_account_id,
+            policy: Box::new(policy),
+        });
+        Ok(self)
+    }
+
+    //переводы между счетами в разных валютах выполняются по курсам из rates
+    pub fn with_rates(mut self, rates: impl ExchangeRates + 'static) -> Bank<T, S> {
+        self.rates = Some(Box::new(rates));
+        self
+    }
+
+    //комиссия, которую заплатит счёт payer за операцию; со специального счёта комиссия не берётся.
+    //Комиссия не конвертируется, поэтому берётся только со счетов в валюте специального счёта
+    pub fn fee(&self, payer: &IdOf<S>, kind: FeeKind, money: NonZeroMoney) -> Money {
+        self.fees.as_ref().map_or(0, |fees| {
+            fees.fee(payer, |id| self.currency(id).ok(), kind, money)
+        })
+    }
+
+    fn currency(&self, account_id: &IdOf<S>) -> Result<Currency, BankError> {
+        self.state
+            .get_balance(account_id)
+            .map(|account| account.currency())
+    }
+
+    fn charge(
+        &self,
+        payer: &IdOf<S>,
+        kind: FeeKind,
+        money: NonZeroMoney,
+    ) -> Option<Operation<IdOf<S>>> {
+        self.fees
+            .as_ref()?
+            .charge(payer, |id| self.currency(id).ok(), kind, money)
+    }
+
+    //операции истории выполняются заново, но сохраняют своё исходное время.
+    //Операция, которую нельзя выполнить, - ошибка, как и в restore
+    pub fn from(history: impl Iterator<Item = Entry<IdOf<S>>>) -> Result<Bank<T, S>, BankError>
+    where
+        S: Default,
+        T: Default,
+    {
+        let mut bank: Bank<T, S> = Bank::default();
+
+        for (op_id, at, op) in history {
+            bank.execute_at(at, vec![op]).map_err(|err| {
+                BankError::CoreError(format!(
+                    "History operation[{}] can't be applied: {}",
+                    op_id, err
+                ))
+            })?;
+        }
+
+        Ok(bank)
+    }
+
+    //восстановление состояния по истории, уже сохранённой в хранилище (например, на диске)
+    pub fn restore(storage: T, state: S) -> Result<Bank<T, S>, BankError> {
+        let mut bank = Bank::new(storage, state);
+        Self::replay(
+            bank.storage.get_history()?,
+            &mut bank.state,
+            &mut bank.receipts,
+        )?;
+        Ok(bank)
+    }
+
+    //повторное открытие хранилища и состояния, которое хранится отдельно от истории (например, SqliteState).
+    //История выполняется на отдельном состоянии в памяти: по ней восстанавливаются квитанции запросов
+    //с ключами, поэтому повтор запроса с ключом после перезапуска не выполняется. Счета состояния,
+    //которые расходятся с историей (состояние сохранено, а история - нет, например, из-за падения процесса
+    //между ними), возвращаются к счетам из истории. Как и для restore, нужна полная история
+    pub fn reopen(storage: T, mut state: S) -> Result<Bank<T, S>, BankError> {
+        let mut receipts = Receipts::default();
+        let mut replayed = InMemoryState::<S::Account>::default();
+        Self::replay(storage.get_history()?, &mut replayed, &mut receipts)?;
+
+        let mut repaired: Prior<S::Account> = Vec::new();
+        let mut seen = HashSet::new();
+        for account in state.accounts()? {
+            let account = account?;
+            let expected = replayed.get_balance(account.account_id()).ok();
+            if expected.as_ref() != Some(&account) {
+                repaired.push((account.account_id().clone(), expected));
+            }
+            seen.insert(account.account_id().clone());
+        }
+        for account in replayed.accounts()? {
+            let account = account?;
+            if !seen.contains(account.account_id()) {
+                repaired.push((account.account_id().clone(), Some(account)));
+            }
+        }
+        if !repaired.is_empty() {
+            log::warn!(
+                "{} accounts of the state don't match the history, they are restored from it",
+                repaired.len()
+            );
+            state.revert(repaired)?;
+        }
+
+        let mut bank = Bank::new(storage, state);
+        bank.receipts = receipts;
+        Ok(bank)
+    }
+
+    fn replay<R: State<Account = S::Account>>(
+        history: impl Iterator<Item = Result<Entry<IdOf<S>>, BankError>>,
+        state: &mut R,
+        receipts: &mut Receipts<S::Account>,
+    ) -> Result<(), BankError> {
+        for entry in history {
+            let (op_id, _, op) = entry?;
+            state
+                .update(&op)
+                .map(drop)
+                .and_then(|_| Self::remember(receipts, state, &op))
+                .map_err(|err| {
+                    BankError::CoreError(format!(
+                        "History operation[{}] can't be applied: {}",
+                        op_id, err
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    //квитанция применяется последней в транзакции, поэтому счета в состоянии уже после неё
+    fn remember<R: State<Account = S::Account>>(
+        receipts: &mut Receipts<S::Account>,
+        state: &R,
+        op: &Operation<IdOf<S>>,
+    ) -> Result<(), BankError> {
+        if let Operation::Receipt {
+            key,
+            fingerprint,
+            account_id,
+            related,
+            fee,
+        } = op
+        {
+            let accounts = std::iter::once(account_id)
+                .chain(related)
+                .map(|account_id| state.get_balance(account_id))
+                .collect::<Result<_, _>>()?;
+            receipts.insert(
+                *key,
+                *fingerprint,
+                Receipt {
+                    accounts,
+                    fee: *fee,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    //операции сначала проверяются на текущем состоянии, затем сохраняются в хранилище
+    //и только после этого применяются к состоянию, поэтому в историю попадают только успешные операции.
+    //Транзакция запроса с ключом завершается его квитанцией
+    fn execute(&mut self, ops: Vec<Operation<IdOf<S>>>) -> Result<Vec<OpId>, BankError> {
+        self.execute_at(self.clock.now(), ops)
+    }
+
+    fn execute_at(
+        &mut self,
+        at: Timestamp,
+        mut ops: Vec<Operation<IdOf<S>>>,
+    ) -> Result<Vec<OpId>, BankError> {
+        if let Some((key, fingerprint)) = self.request_key.take() {
+            ops.extend(idempotency::receipt(key, fingerprint, &ops));
+        }
+        //состояние изменяется атомарно и первым: ошибка при его изменении ничего не оставляет.
+        //Если затем не удастся сохранить операции в истории, счета вернутся к прежним копиям
+        let before = self.state.prior(ops.iter())?;
+        self.state.transact(ops.iter())?;
+        let op_ids = match self.storage.transact(at, &ops) {
+            Ok(op_ids) => op_ids,
+            Err(err) => {
+                return Err(match self.state.revert(before) {
+                    Ok(()) => err,
+                    Err(revert) => BankError::CoreError(format!(
+                        "{err}, and the state can't be reverted: {revert}"
+                    )),
+                })
+            }
+        };
+        for op in &ops {
+            Self::remember(&mut self.receipts, &self.state, op)?;
+        }
+        Ok(op_ids)
+    }
+
+    //транзакция из готовых операций и её проверка без выполнения, см. ShardedBank
+    pub(crate) fn transact(
+        &mut self,
+        ops: Vec<Operation<IdOf<S>>>,
+    ) -> Result<Vec<OpId>, BankError> {
+        self.execute(ops)
+    }
+
+    pub(crate) fn validate(&self, ops: &[Operation<IdOf<S>>]) -> Result<(), BankError> {
+        self.state.validate(ops.iter())
+    }
+
+    //первый счёт транзакции - тот, ради которого она выполнялась
+    fn execute_one(&mut self, ops: Vec<Operation<IdOf<S>>>) -> Result<S::Account, BankError> {
+        let account_id = ops
+            .first()
+            .map(|op| op.account_id().clone())
+            .ok_or_else(|| {
+                BankError::CoreError(
+                    "a transaction should contain at least one operation".to_owned(),
+                )
+            })?;
+        self.execute(ops)?;
+        self.state.get_balance(&account_id)
+    }
+
+    //выполняет изменяющий запрос run не больше одного раза для ключа key.
+    //Повторный запрос с тем же ключом не выполняется, а получает квитанцию первого.
+    //fingerprint - отпечаток запроса (см. idempotency::fingerprint), другой запрос с тем же ключом - ошибка
+    pub fn idempotent(
+        &mut self,
+        key: RequestKey,
+        fingerprint: RequestFingerprint,
+        run: impl FnOnce(&mut Self) -> Result<(), BankError>,
+    ) -> Result<&Receipt<S::Account>, BankError> {
+        if self.receipts.find(&key, fingerprint)?.is_none() {
+            self.request_key = Some((key, fingerprint));
+            let ret = run(self);
+            //запрос мог завершиться ошибкой до выполнения транзакции
+            self.request_key = None;
+            ret?;
+        }
+        self.receipts.get(&key).ok_or_else(|| {
+            BankError::BadRequest(format!("Request with key[{key}] doesn't change the bank"))
+        })
+    }
+
+    pub fn receipt_of(&self, key: &RequestKey) -> Option<&Receipt<S::Account>> {
+        self.receipts.get(key)
+    }
+
+    //состояние банка, например, чтобы узнать статистику CachedState
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    //создание аккаунта
+    pub fn create_account(&mut self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.execute_one(vec![Operation::Create(account_id)])
+    }
+
+    //создание аккаунта в указанной валюте
+    pub fn create_account_in(
+        &mut self,
+        account_id: IdOf<S>,
+        currency: Currency,
+    ) -> Result<S::Account, BankError> {
+        self.execute_one(vec![Operation::CreateIn(account_id, currency)])
+    }
+
+    //пополнение и снятие в валюте, отличной от валюты счёта, запрещены
+    fn check_currency(&self, account_id: &IdOf<S>, currency: Currency) -> Result<(), BankError> {
+        let expected = self.currency(account_id)?;
+        if expected != currency {
+            return Err(BankError::BadRequest(format!(
+                "Account[{account_id}] is in {expected}, not in {currency}"
+            )));
+        }
+        Ok(())
+    }
+
+    //Клиент может получить свой баланс.
+    pub fn get_balance(&self, account_id: &IdOf<S>) -> Result<S::Account, BankError> {
+        self.state.get_balance(account_id)
+    }
+
+    //состояние счёта сразу после операции op_id, вычисляется по операциям счёта не позже неё
+    pub fn get_balance_at(
+        &self,
+        account_id: &IdOf<S>,
+        op_id: OpId,
+    ) -> Result<S::Account, BankError> {
+        balance_at(&self.storage, account_id, op_id)
+    }
+
+    //голова цепочки хэшей истории: её можно опубликовать и позже сравнить с новой проверкой
+    pub fn head_hash(&self) -> Result<ChainHash, BankError> {
+        self.storage.head_hash()
+    }
+
+    //проверка, что сохранённая история не изменена задним числом
+    pub fn verify_history(&self) -> Result<ChainReport, BankError> {
+        chain::verify(&self.storage)
+    }
+
+    //сверка состояния с историей и проверка денежного баланса банка
+    pub fn audit(&self) -> Result<AuditReport<S::Account>, BankError>
+    where
+        <S::Account as BankAccount>::Balance: Into<SignedMoney>,
+    {
+        audit::audit(self.storage.get_history()?, &self.state)
+    }
+
+    //выписка по счёту за операции [from, to]: операции до from дают начальный баланс,
+    //каждая операция диапазона - строку с балансом после неё
+    pub fn statement(
+        &self,
+        account_id: &IdOf<S>,
+        from: OpId,
+        to: OpId,
+    ) -> Result<Statement<S::Account>, BankError> {
+        statement::build(&self.storage, account_id, from, to)
+    }
+
+    //история операций по счету
+    pub fn get_account_ops<'a>(
+        &'a self,
+        account_id: &'a IdOf<S>,
+    ) -> Result<impl Iterator<Item = Result<Entry<IdOf<S>>, BankError>> + 'a, BankError> {
+        self.storage.get_ops(account_id)
+    }
+    //страница истории (всего банка или одного счёта) по запросу с фильтрами
+    pub fn query(&self, query: &HistoryQuery<IdOf<S>>) -> Result<HistoryPage<IdOf<S>>, BankError> {
+        history::query(&self.storage, query)
+    }
+
+    //можно получить историю операций
+    pub fn get_history(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<Entry<IdOf<S>>, BankError>> + '_, BankError> {
+        self.storage.get_history()
+    }
+
+    //Клиент может пополнить свой баланс.
+    pub fn deposit(
+        &mut self,
+        account_id: &IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<S::Account, BankError> {
+        let ops = std::iter::once(Operation::Deposit(account_id.clone(), money))
+            .chain(self.charge(account_id, FeeKind::Deposit, money))
+            .collect();
+        self.execute_one(ops)
+    }
+
+    //кредитный лимит счёта, изменение сохраняется в истории
+    pub fn set_credit_limit(
+        &mut self,
+        account_id: IdOf<S>,
+        limit: Money,
+    ) -> Result<S::Account, BankError> {
+        self.execute_one(vec![Operation::SetCreditLimit(account_id, limit)])
+    }
+
+    //заморозка счёта, пока счёт заморожен, операции по нему запрещены
+    pub fn freeze_account(&mut self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.execute_one(vec![Operation::Freeze(account_id)])
+    }
+
+    pub fn unfreeze_account(&mut self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.execute_one(vec![Operation::Unfreeze(account_id)])
+    }
+
+    //закрытие счёта; остаток, если он есть, переводится на счёт payout без комиссии
+    pub fn close_account(
+        &mut self,
+        account_id: IdOf<S>,
+        payout: Option<IdOf<S>>,
+    ) -> Result<S::Account, BankError> {
+        let mut ops = Vec::new();
+        if let Some(funds) = NonZeroMoney::new(self.state.get_balance(&account_id)?.funds()) {
+            let to = payout.ok_or_else(|| {
+                BankError::BadRequest(format!(
+                    "Account[{account_id}] has funds[{funds}], a payout account is required"
+                ))
+            })?;
+            if to == account_id {
+                return Err(BankError::Prohibited(format!(
+                    "Account[{to}] can't be a payout for itself"
+                )));
+            }
+            ops.extend(self.transfer(account_id.clone(), to, funds)?);
+        }
+        ops.push(Operation::Close(account_id));
+        self.execute_one(ops)
+    }
+
+    //пополнение с указанием валюты
+    pub fn deposit_in(
+        &mut self,
+        account_id: &IdOf<S>,
+        money: NonZeroMoney,
+        currency: Currency,
+    ) -> Result<S::Account, BankError> {
+        self.check_currency(account_id, currency)?;
+        self.deposit(account_id, money)
+    }
+
+    //Клиент может забрать деньги
+    pub fn withdraw(
+        &mut self,
+        account_id: IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<S::Account, BankError> {
+        let fee = self.charge(&account_id, FeeKind::Withdraw, money);
+        let ops = std::iter::once(Operation::Withdraw(account_id, money))
+            .chain(fee)
+            .collect();
+        self.execute_one(ops)
+    }
+
+    //снятие с указанием валюты
+    pub fn withdraw_in(
+        &mut self,
+        account_id: IdOf<S>,
+        money: NonZeroMoney,
+        currency: Currency,
+    ) -> Result<S::Account, BankError> {
+        self.check_currency(&account_id, currency)?;
+        self.withdraw(account_id, money)
+    }
+
+    //перемещение денег от счета на счет.
+    //Если валюты счетов различаются, сумма money в валюте from пересчитывается по текущему курсу
+    pub fn move_money(
+        &mut self,
+        from: IdOf<S>,
+        to: IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<(S::Account, S::Account), BankError> {
+        if from == to {
+            return Err(BankError::Prohibited(format!(
+                "Sending funds to yourself[{to}] is prohibited"
+            )));
+        }
+
+        let fee = self.charge(&from, FeeKind::Move, money);
+        let ops = self
+            .transfer(from.clone(), to.clone(), money)?
+            .into_iter()
+            .chain(fee)
+            .collect();
+        self.execute(ops)?;
+
+        Ok((self.state.get_balance(&from)?, self.state.get_balance(&to)?))
+    }
+
+    //операция перевода: Transfer в одной валюте или Exchange по текущему курсу
+    fn transfer(
+        &self,
+        from: IdOf<S>,
+        to: IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<Vec<Operation<IdOf<S>>>, BankError> {
+        let currencies = (self.currency(&from)?, self.currency(&to)?);
+        transfer(from, to, money, currencies, self.rates.as_deref())
+    }
+}
+
+impl<T, A> Bank<T, InMemoryState<A>>
+where
+    T: OpsStorage<Id = A::Id>,
+    A: BankAccount,
+{
+    //снимок состояния счетов, помеченный последней сохранённой операцией
+    pub fn snapshot(&self) -> Result<Snapshot<A>, BankError> {
+        Ok(Snapshot::new(
+            self.storage.last_op_id()?,
+            &self.state,
+            &self.receipts,
+        ))
+    }
+
+    //быстрое восстановление: состояние и квитанции из снимка + операции из хранилища после него
+    pub fn from_snapshot(storage: T, mut snapshot: Snapshot<A>) -> Result<Self, BankError> {
+        let last_op_id = snapshot.last_op_id;
+        if storage.last_op_id()? < last_op_id {
+            return Err(BankError::CoreError(format!(
+                "Snapshot[{}] is newer than the history in the storage",
+                last_op_id
+            )));
+        }
+        let receipts = std::mem::take(&mut snapshot.receipts);
+        let mut bank = Bank::new(storage, InMemoryState::from(snapshot));
+        for (key, fingerprint, receipt) in receipts {
+            bank.receipts.insert(key, fingerprint, receipt);
+        }
+        Self::replay(
+            bank.storage.get_history_after(last_op_id)?,
+            &mut bank.state,
+            &mut bank.receipts,
+        )?;
+        Ok(bank)
+    }
+
+    //архивирование операций старше снимка, они возвращаются вызывающему.
+    //После этого банк восстанавливается только через from_snapshot
+    pub fn compact(&mut self, snapshot: &Snapshot<A>) -> Result<History<A::Id>, BankError> {
+        self.storage.compact(snapshot.last_op_id)
+    }
+
+    //банк для работы из нескольких потоков через &self с блокировками отдельных счетов
+    pub fn into_concurrent(self) -> ConcurrentBank<T, A> {
+        ConcurrentBank::from_parts(
+            self.storage,
+            self.state.0.into_values(),
+            self.receipts,
+            self.fees,
+            self.rates,
+            self.clock,
+        )
+    }
+}
+
+//применение операции к одному счёту без проверок состояния: в истории только успешные операции
+pub(crate) fn apply_to<A: BankAccount>(
+    account: Option<A>,
+    account_id: &A::Id,
+    op: &Operation<A::Id>,
+) -> Result<A, BankError> {
+    let mut account = match (account, op) {
+        (None, Operation::Create(_)) => return Ok(A::open(account_id.clone())),
+        (None, Operation::CreateIn(_, currency)) => {
+            return A::open_in(account_id.clone(), *currency)
+        }
+        (Some(account), _) => account,
+        //начало истории счёта архивировано
+        (None, _) => {
+            return Err(BankError::BadRequest(format!(
+                "History of account[{account_id}] is compacted, it starts with {op:?}"
+            )))
+        }
+    };
+    match op {
+        Operation::Create(_) | Operation::CreateIn(..) => {
+            return Err(BankError::CoreError(format!(
+                "Account[{account_id}] is created twice"
+            )))
+        }
+        Operation::Deposit(_, money) => account.deposit(*money)?,
+        Operation::Withdraw(_, money) => account.withdraw(*money)?,
+        Operation::Fee { from, amount, .. } | Operation::Transfer { from, amount, .. } => {
+            if from == account_id {
+                account.withdraw(*amount)?
+            } else {
+                account.deposit(*amount)?
+            }
+        }
+        Operation::Exchange {
+            from, amount, rate, ..
+        } => {
+            if from == account_id {
+                account.withdraw(*amount)?
+            } else {
+                account.deposit(rate.convert(*amount)?)?
+            }
+        }
+        Operation::SetCreditLimit(_, limit) => account.set_credit_limit(*limit)?,
+        Operation::Freeze(_) => account.set_status(AccountStatus::Frozen)?,
+        Operation::Unfreeze(_) => account.set_status(AccountStatus::Active)?,
+        Operation::Close(_) => account.set_status(AccountStatus::Closed)?,
+        Operation::Receipt { .. } => {}
+        Operation::TransferOut { amount, .. } => account.withdraw(*amount)?,
+        Operation::TransferIn { amount, .. } | Operation::TransferCancel { amount, .. } => {
+            account.deposit(*amount)?
+        }
+    }
+    Ok(account)
+}
+
+//операция перевода: Transfer в одной валюте или Exchange по курсу из rates
+pub(crate) fn transfer<Id>(
+    from: Id,
+    to: Id,
+    money: NonZeroMoney,
+    (from_currency, to_currency): (Currency, Currency),
+    rates: Option<&dyn ExchangeRates>,
+) -> Result<Vec<Operation<Id>>, BankError> {
+    if from_currency == to_currency {
+        return Ok(vec![Operation::Transfer {
+            from,
+            to,
+            amount: money,
+        }]);
+    }
+
+    let rate = rates
+        .and_then(|rates| rates.rate(from_currency, to_currency))
+        .ok_or_else(|| {
+            BankError::BadRequest(format!(
+                "There is no exchange rate from {from_currency} to {to_currency}"
+            ))
+        })?;
+    Ok(vec![Operation::Exchange {
+        from,
+        to,
+        amount: money,
+        rate,
+    }])
+}
+
+//состояние счёта сразу после операции op_id по истории в storage
+pub(crate) fn balance_at<T, A>(storage: &T, account_id: &A::Id, op_id: OpId) -> Result<A, BankError>
+where
+    T: OpsStorage<Id = A::Id>,
+    A: BankAccount,
+{
+    let mut account = None;
+    for entry in storage.get_ops(account_id)? {
+        let (id, _, op) = entry?;
+        if id > op_id {
+            break;
+        }
+        account = Some(apply_to(account, account_id, &op)?);
+    }
+    account.ok_or_else(|| {
+        BankError::BadRequest(format!(
+            "Account[{account_id}] doesn't exist at operation[{op_id}]"
+        ))
+    })
+}
+
+//реализация State для банка в памяти
+#[derive(Debug)]
+pub struct InMemoryState<A: BankAccount = Account>(pub(crate) HashMap<A::Id, A>);
+
+impl<A: BankAccount> Default for InMemoryState<A> {
+    fn default() -> Self {
+        InMemoryState(HashMap::default())
+    }
+}
+
+impl<A: BankAccount> InMemoryState<A> {
+    fn lookup(&self, overlay: &Overlay<A>, account_id: &A::Id) -> Result<A, BankError> {
+        overlay
+            .get(account_id)
+            .or_else(|| self.0.get(account_id))
+            .cloned()
+            .ok_or_else(|| {
+                BankError::BadRequest(format!("Bank doesnt contain account_id[{}]", account_id))
+            })
+    }
+
+    //счёт, по которому разрешены операции
+    fn lookup_active(&self, overlay: &Overlay<A>, account_id: &A::Id) -> Result<A, BankError> {
+        let account = self.lookup(overlay, account_id)?;
+        match account.status() {
+            AccountStatus::Active => Ok(account),
+            status => Err(BankError::Prohibited(format!(
+                "Account[{account_id}] is {status}"
+            ))),
+        }
+    }
+
+    fn change_status(
+        &self,
+        overlay: &mut Overlay<A>,
+        account_id: &A::Id,
+        from: AccountStatus,
+        to: AccountStatus,
+    ) -> Result<(), BankError> {
+        let mut account = self.lookup(overlay, account_id)?;
+        if account.status() != from {
+            return Err(BankError::Prohibited(format!(
+                "Account[{account_id}] is {}, but should be {from}",
+                account.status()
+            )));
+        }
+        account.set_status(to)?;
+        overlay.insert(account_id.clone(), account);
+        Ok(())
+    }
+
+    fn credit(
+        &self,
+        overlay: &mut Overlay<A>,
+        account_id: &A::Id,
+        money: NonZeroMoney,
+    ) -> Result<(), BankError> {
+        let mut account = self.lookup_active(overlay, account_id)?;
+        account.deposit(money)?;
+        overlay.insert(account_id.clone(), account);
+        Ok(())
+    }
+
+    fn debit(
+        &self,
+        overlay: &mut Overlay<A>,
+        account_id: &A::Id,
+        money: NonZeroMoney,
+    ) -> Result<(), BankError> {
+        let mut account = self.lookup_active(overlay, account_id)?;
+        account.withdraw(money)?;
+        overlay.insert(account_id.clone(), account);
+        Ok(())
+    }
+
+    fn ensure_new(&self, overlay: &Overlay<A>, account_id: &A::Id) -> Result<(), BankError> {
+        if self.lookup(overlay, account_id).is_ok() {
+            return Err(BankError::BadRequest(format!(
+                "Bank already contains account_id[{}]",
+                account_id
+            )));
+        }
+        Ok(())
+    }
+
+    fn ensure_currency(
+        &self,
+        overlay: &Overlay<A>,
+        account_id: &A::Id,
+        currency: Currency,
+    ) -> Result<(), BankError> {
+        let actual = self.lookup(overlay, account_id)?.currency();
+        if actual != currency {
+            return Err(BankError::BadRequest(format!(
+                "Account[{account_id}] is in {actual}, not in {currency}"
+            )));
+        }
+        Ok(())
+    }
+
+    //вычисляет новые значения счетов, затронутых операцией, не изменяя состояние;
+    //изменённые счета накапливаются в overlay
+    fn push_to_col(
+        &self,
+        overlay: &mut Overlay<A>,
+        op: &Operation<A::Id>,
+    ) -> Result<A::Id, BankError> {
+        match op {
+            Operation::Create(account_id) => {
+                self.ensure_new(overlay, account_id)?;
+                overlay.insert(account_id.clone(), A::open(account_id.clone()));
+            }
+
+            Operation::CreateIn(account_id, currency) => {
+                self.ensure_new(overlay, account_id)?;
+                overlay.insert(
+                    account_id.clone(),
+                    A::open_in(account_id.clone(), *currency)?,
+                );
+            }
+
+            Operation::Deposit(account_id, money) => self.credit(overlay, account_id, *money)?,
+
+            Operation::Withdraw(account_id, money) => self.debit(overlay, account_id, *money)?,
+
+            Operation::Fee { from, to, amount } => {
+                let currency = self.lookup(overlay, to)?.currency();
+                self.ensure_currency(overlay, from, currency)?;
+                self.debit(overlay, from, *amount)?;
+                self.credit(overlay, to, *amount)?;
+            }
+
+            Operation::Exchange {
+                from,
+                to,
+                amount,
+                rate,
+            } => {
+                self.ensure_currency(overlay, from, rate.from)?;
+                self.ensure_currency(overlay, to, rate.to)?;
+                self.debit(overlay, from, *amount)?;
+                self.credit(overlay, to, rate.convert(*amount)?)?;
+            }
+
+            Operation::Transfer { from, to, amount } => {
+                if from == to {
+                    return Err(BankError::Prohibited(format!(
+                        "Sending funds to yourself[{to}] is prohibited"
+                    )));
+                }
+                self.debit(overlay, from, *amount)?;
+                self.credit(overlay, to, *amount)?;
+            }
+
+            Operation::SetCreditLimit(account_id, limit) => {
+                let mut account = self.lookup_active(overlay, account_id)?;
+                account.set_credit_limit(*limit)?;
+                overlay.insert(account_id.clone(), account);
+            }
+
+            Operation::Freeze(account_id) => self.change_status(
+                overlay,
+                account_id,
+                AccountStatus::Active,
+                AccountStatus::Frozen,
+            )?,
+
+            Operation::Unfreeze(account_id) => self.change_status(
+                overlay,
+                account_id,
+                AccountStatus::Frozen,
+                AccountStatus::Active,
+            )?,
+
+            Operation::Close(account_id) => self.change_status(
+                overlay,
+                account_id,
+                AccountStatus::Active,
+                AccountStatus::Closed,
+            )?,
+
+            //квитанция только проверяет, что её счета существуют
+            Operation::Receipt {
+                account_id,
+                related,
+                ..
+            } => {
+                for account_id in std::iter::once(account_id).chain(related) {
+                    self.lookup(overlay, account_id)?;
+                }
+            }
+
+            //счёт другой стороны перевода между шардами хранится в другом шарде
+            Operation::TransferOut { from, amount, .. } => self.debit(overlay, from, *amount)?,
+
+            Operation::TransferIn { to, amount, .. } => self.credit(overlay, to, *amount)?,
+
+            Operation::TransferCancel { from, amount, .. } => {
+                let mut account = self.lookup(overlay, from)?;
+                account.deposit(*amount)?;
+                overlay.insert(from.clone(), account);
+            }
+        }
+
+        Ok(op.account_id().clone())
+    }
+
+    //Фаза 1: вычисление изменений без модификации данных
+    pub(crate) fn prepare<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<A::Id>>,
+    ) -> Result<(Vec<A::Id>, Overlay<A>), BankError> {
+        let mut overlay = HashMap::new();
+        let mut successful_accounts_ids = Vec::new();
+        for op in ops {
+            successful_accounts_ids.push(self.push_to_col(&mut overlay, op)?);
+        }
+        Ok((successful_accounts_ids, overlay))
+    }
+
+    //Фаза 2: фиксация изменений, здесь ошибок уже быть не может
+    pub(crate) fn commit(&mut self, overlay: Overlay<A>) {
+        self.0.extend(overlay);
+    }
+}
+
+impl<A: BankAccount> FromIterator<A> for InMemoryState<A> {
+    fn from_iter<I: IntoIterator<Item = A>>(accounts: I) -> Self {
+        InMemoryState(
+            accounts
+                .into_iter()
+                .map(|account| (account.account_id().clone(), account))
+                .collect(),
+        )
+    }
+}
+
+impl<A: BankAccount> State for InMemoryState<A> {
+    type Account = A;
+
+    fn get_balance(&self, account_id: &A::Id) -> Result<A, BankError> {
+        self.0.get(account_id).cloned().ok_or_else(|| {
+            BankError::BadRequest(format!("Account[{}] not found in bank", account_id))
+        })
+    }
+
+    fn accounts(&self) -> Result<impl Iterator<Item = Result<A, BankError>>, BankError> {
+        Ok(self.0.values().cloned().map(Ok))
+    }
+
+    fn validate<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<A::Id>>,
+    ) -> Result<(), BankError> {
+        self.prepare(ops).map(|_| ())
+    }
+
+    fn revert(&mut self, accounts: Prior<A>) -> Result<(), BankError> {
+        for (account_id, account) in accounts {
+            match account {
+                Some(account) => self.0.insert(account_id, account),
+                None => self.0.remove(&account_id),
+            };
+        }
+        Ok(())
+    }
+
+    fn transact<'b>(
+        &mut self,
+        ops: impl Iterator<Item = &'b Operation<A::Id>>,
+    ) -> Result<Vec<A>, BankError> {
+        let (successful_accounts_ids, overlay) = self.prepare(ops)?;
+        // Фаза 3: счета после транзакции в порядке операций
+        let accounts = successful_accounts_ids
+            .iter()
+            .map(|account_id| self.lookup(&overlay, account_id))
+            .collect::<Result<_, _>>()?;
+        self.commit(overlay);
+        Ok(accounts)
+    }
+}
+
+//реализация хранилища операций банка в пмяти
+#[derive(Debug)]
+pub struct InMemoryOpsStorage<Id = AccountId> {
+    cur_key: OpId,
+    by_ops_storage: BTreeMap<OpId, (Timestamp, Operation<Id>)>,
+    //операции счёта по возрастанию OpId, поэтому по ним работает двоичный поиск
+    by_acc_storage: HashMap<Id, VecDeque<OpId>>,
+    //хэши цепочки, с которыми операции сохранены, и хэш последней операции
+    hashes: BTreeMap<OpId, ChainHash>,
+    head: ChainHash,
+}
+
+impl<Id> Default for InMemoryOpsStorage<Id> {
+    fn default() -> Self {
+        Self {
+            cur_key: OpId::MIN,
+            by_ops_storage: BTreeMap::default(),
+            by_acc_storage: HashMap::default(),
+            hashes: BTreeMap::default(),
+            head: ChainHash::GENESIS,
+        }
+    }
+}
+
+impl<Id: BankAccountId> InMemoryOpsStorage<Id> {
+    //идентификаторы и хэши, которые получат операции транзакции, если их сохранить
+    pub(crate) fn links<'o>(
+        &self,
+        at: Timestamp,
+        ops: impl Iterator<Item = &'o Operation<Id>>,
+    ) -> Result<Vec<(OpId, ChainHash)>, BankError> {
+        let mut op_id = self.cur_key;
+        let mut hash = self.head;
+        let mut links = Vec::new();
+        for op in ops {
+            op_id = op_id
+                .checked_add(1)
+                .ok_or_else(|| BankError::CoreError("OpId overflow".to_owned()))?;
+            hash = hash.link(op_id, at, op)?;
+            links.push((op_id, hash));
+        }
+        Ok(links)
+    }
+
+    fn insert(&mut self, op_id: OpId, at: Timestamp, op: Operation<Id>, hash: ChainHash) {
+        self.cur_key = op_id;
+        self.head = hash;
+        self.hashes.insert(op_id, hash);
+
+        for account_id in op.account_ids() {
+            self.by_acc_storage
+                .entry(account_id)
+                .or_default()
+                .push_back(op_id);
+        }
+
+        self.by_ops_storage.insert(op_id, (at, op));
+    }
+
+    //пустое хранилище, которое продолжает историю после операции after с хэшем head
+    pub(crate) fn after(after: OpId, head: ChainHash) -> Self {
+        Self {
+            cur_key: after,
+            head,
+            ..Self::default()
+        }
+    }
+
+    //счета, известные хранилищу, в том числе те, все операции которых архивированы
+    pub(crate) fn accounts(&self) -> impl Iterator<Item = &Id> {
+        self.by_acc_storage.keys()
+    }
+
+    //счёт, известный хранилищу без операций (его история архивирована)
+    pub(crate) fn add_account(&mut self, account_id: Id) {
+        self.by_acc_storage.entry(account_id).or_default();
+    }
+
+    //вставка операции с уже известными идентификатором и хэшем (например, при чтении с диска).
+    //Хэш не пересчитывается: его проверяет chain::verify
+    pub(crate) fn restore(
+        &mut self,
+        op_id: OpId,
+        at: Timestamp,
+        op: Operation<Id>,
+        hash: ChainHash,
+    ) -> Result<(), BankError> {
+        if op_id <= self.cur_key {
+            return Err(BankError::CoreError(format!(
+                "operation[{}] is out of order, the last one is[{}]",
+                op_id, self.cur_key
+            )));
+        }
+        self.insert(op_id, at, op, hash);
+        Ok(())
+    }
+}
+
+impl<Id: BankAccountId> InMemoryOpsStorage<Id> {
+    fn entry(&self, op_id: &OpId) -> Result<Entry<Id>, BankError> {
+        self.by_ops_storage
+            .get(op_id) //O(lgN)
+            .map(|(at, op)| (*op_id, *at, op.clone()))
+            .ok_or_else(|| {
+                BankError::CoreError(format!(
+                    "something get wrong with your code, because by_ops_storage doesn't contain value for op_id[{}]",
+                    op_id
+                ))
+            })
+    }
+
+    fn account_ops(&self, account_id: &Id) -> Result<&VecDeque<OpId>, BankError> {
+        self.by_acc_storage.get(account_id).ok_or_else(|| {
+            BankError::BadRequest(format!("There is no account[{}] in the bank", account_id))
+        })
+    }
+}
+
+impl<Id: BankAccountId> OpsStorage for InMemoryOpsStorage<Id> {
+    type Id = Id;
+    const BLOCKING: bool = false;
+
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        Ok(self
+            .by_ops_storage
+            .iter()
+            .map(|(op_id, (at, operation))| Ok((*op_id, *at, operation.clone()))))
+    }
+
+    fn get_history_after(
+        &self,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        Ok(self
+            .by_ops_storage
+            .range((Bound::Excluded(after), Bound::Unbounded)) //O(lgM)
+            .map(|(op_id, (at, operation))| Ok((*op_id, *at, operation.clone()))))
+    }
+
+    fn last_op_id(&self) -> Result<OpId, BankError> {
+        Ok(self.cur_key)
+    }
+
+    fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+        self.hashes.get(&op_id).copied().ok_or_else(|| {
+            BankError::BadRequest(format!("There is no operation[{op_id}] in the storage"))
+        })
+    }
+
+    fn head_hash(&self) -> Result<ChainHash, BankError> {
+        Ok(self.head)
+    }
+
+    fn compact(&mut self, before: OpId) -> Result<History<Id>, BankError> {
+        let tail = self.by_ops_storage.split_off(&before);
+        let archived = std::mem::replace(&mut self.by_ops_storage, tail);
+        self.hashes = self.hashes.split_off(&before);
+
+        //списки операций по счёту упорядочены так же, как by_ops_storage,
+        //поэтому архивные операции всегда в их начале
+        for (op_id, (_, op)) in archived.iter() {
+            for account_id in op.account_ids() {
+                if let Some(list) = self.by_acc_storage.get_mut(&account_id) {
+                    if list.front() == Some(op_id) {
+                        list.pop_front();
+                    }
+                }
+            }
+        }
+
+        Ok(archived
+            .into_iter()
+            .map(|(op_id, (at, op))| (op_id, at, op))
+            .collect())
+    }
+
+    fn get_ops(
+        &self,
+        account_id: &Id,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        Ok(self
+            .account_ops(account_id)? //O(1)
+            .iter()
+            .map(|op_id| self.entry(op_id))) //O(N)
+    }
+
+    fn get_ops_after(
+        &self,
+        account_id: &Id,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        let list = self.account_ops(account_id)?;
+        let start = list.partition_point(|op_id| *op_id <= after); //O(lgN)
+        Ok(list.range(start..).map(|op_id| self.entry(op_id)))
+    }
+
+    fn transact(&mut self, at: Timestamp, ops: &[Operation<Id>]) -> Result<Vec<OpId>, BankError> {
+        let links = self.links(at, ops.iter())?;
+        let mut op_ids = Vec::new();
+        for ((op_id, hash), op) in links.into_iter().zip(ops) {
+            self.insert(op_id, at, op.clone(), hash);
+            op_ids.push(op_id);
+        }
+        Ok(op_ids)
+    }
+}
+
+#[cfg(test)]
+//исходные тесты банка клонируют идентификаторы счетов
+#[allow(clippy::clone_on_copy)]
+pub(crate) mod test {
+
+    use super::*;
+    use crate::{
+        cache::CachedState, clock::ManualClock, currency::StaticRates, fee::FlatFee,
+        sqlite::SqliteState,
+    };
+    use std::{cell::Cell, rc::Rc, sync::Arc};
+
+    pub(crate) fn bank_should_create_account<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+
+        let ret = Bank::create_account(&mut bank, acc_1);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = Bank::create_account(&mut bank, acc_2);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_2.clone(),
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+        drop(ret);
+    }
+
+    pub(crate) fn bank_should_deposit_funds<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 42
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(42).unwrap());
+        let ret: Result<Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
-                balance: 42
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = //acc_1 again
+        let ret: Result<Account, BankError> = //acc_1 again
             bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
 
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 84
+                balance: 84,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
     }
 
-    #[test]
-    fn bank_should_withdraw_funds() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
-
+    pub(crate) fn bank_should_withdraw_funds<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
         let acc_1 = 128;
         let acc_2 = 129;
 
         let _ = bank.create_account(acc_1);
         let _ = bank.create_account(acc_2);
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 42
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(42).unwrap());
+        let ret: Result<Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
-                balance: 42
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.withdraw(acc_1, NonZeroMoney::MIN);
+        let ret: Result<Account, BankError> = bank.withdraw(acc_1, NonZeroMoney::MIN);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 41
+                balance: 41,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.withdraw(acc_1, NonZeroMoney::new(41).unwrap());
+        let ret: Result<Account, BankError> = bank.withdraw(acc_1, NonZeroMoney::new(41).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1.clone(),
-                balance: 0
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.withdraw(acc_2, NonZeroMoney::MIN);
+        let ret: Result<Account, BankError> = bank.withdraw(acc_2, NonZeroMoney::MIN);
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_2.clone(),
-                balance: 41
+                balance: 41,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
-        let ret: Result<&Account, BankError> = bank.withdraw(acc_2, NonZeroMoney::MAX);
+        let ret: Result<Account, BankError> = bank.withdraw(acc_2, NonZeroMoney::MAX);
         assert_eq!(
             ret,
-            Err(BankError::BadRequest("Insufficient funds".to_string()))
+            Err(BankError::BadRequest(
+                "Insufficient funds, available[41] with credit limit[0]".to_string()
+            ))
         );
     }
 
-    #[test]
-    fn bank_should_move_funds() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
-
+    pub(crate) fn bank_should_move_funds<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
         let acc_1 = 128;
         let acc_2 = 129;
         let acc_3 = 130;
@@ -543,21 +2016,27 @@ mod test {
         let _ = bank.create_account(acc_2);
         let _ = bank.create_account(acc_3);
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_1,
-                balance: 42
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
         //acc_2 is untouched
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
+        let ret: Result<Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
+            Ok(Account {
                 account_id: acc_3,
-                balance: 21
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             })
         );
 
@@ -566,232 +2045,1487 @@ mod test {
             .expect("should be Ok((from,to))");
         assert_eq!(
             from,
-            &Account {
+            Account {
                 account_id: acc_1,
-                balance: 0
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             }
         );
         assert_eq!(
             to,
-            &Account {
+            Account {
+                account_id: acc_2.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+
+        let ret: Account = bank
+            .get_balance(&acc_3)
+            .expect("should be Ok(Account for acc_3)");
+        assert_eq!(
+            ret,
+            Account {
+                account_id: acc_3.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+
+        let ret: Account = bank
+            .get_balance(&acc_2)
+            .expect("should be Ok(Account for acc_2)");
+        assert_eq!(
+            ret,
+            Account {
+                account_id: acc_2.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+
+        let ret: Account = bank
+            .get_balance(&acc_1)
+            .expect("should be Ok(Account for acc_1)");
+        assert_eq!(
+            ret,
+            Account {
+                account_id: acc_1.clone(),
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+    }
+
+    pub(crate) fn bank_should_get_balance<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let acc_3 = 130;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.create_account(acc_3);
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_3.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = bank.get_balance(&acc_1).expect("should be Account 1");
+        assert_eq!(
+            ret,
+            Account {
+                account_id: acc_1.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+        let ret = bank.get_balance(&acc_2).expect("should be Account 2");
+        assert_eq!(
+            ret,
+            Account {
                 account_id: acc_2.clone(),
-                balance: 42
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            }
+        );
+        let ret = bank.get_balance(&acc_3).expect("should be Account 3");
+        assert_eq!(
+            ret,
+            Account {
+                account_id: acc_3.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
             }
         );
+    }
+
+    pub(crate) fn bank_should_get_history<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let acc_3 = 130;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.create_account(acc_3);
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_3.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let history = bank
+            .get_history()
+            .expect("Bank should get history")
+            .map(Result::unwrap);
+
+        let clone_of_bank: Bank<T, S> = Bank::from(history).unwrap();
+
+        let ret = clone_of_bank.get_balance(&acc_1);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = clone_of_bank.get_balance(&acc_2);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_2.clone(),
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = clone_of_bank.get_balance(&acc_3);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_3.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+    }
+
+    pub(crate) fn bank_should_get_account_ops<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret: Result<Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(21).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_2.clone(),
+                balance: 21,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let _ = bank
+            .move_money(acc_1, acc_2, NonZeroMoney::new(42).unwrap())
+            .expect("move should work");
+
+        //let _: Vec<(_, _)> = iter.collect();
+
+        let ret: Result<Account, BankError> = bank.get_balance(&acc_2);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_2.clone(),
+                balance: 63,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret: Result<Account, BankError> = bank.get_balance(&acc_1);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1.clone(),
+                balance: 0,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = bank.get_account_ops(&acc_1);
+        assert!(ret.is_ok());
+        assert_eq!(ret.unwrap().count(), 3); //Create + Deposit + Transfer
+
+        let ret = bank.get_account_ops(&acc_2);
+        assert!(ret.is_ok());
+        assert_eq!(ret.unwrap().count(), 3); //Create + Deposit + Transfer
+    }
+
+    pub(crate) fn bank_should_not_persist_rejected_operations<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let unknown = 130;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+
+        let ret = bank.create_account(acc_1);
+        assert!(ret.is_err());
+
+        let ret = bank.withdraw(acc_1, NonZeroMoney::new(43).unwrap());
+        assert_eq!(
+            ret,
+            Err(BankError::BadRequest(
+                "Insufficient funds, available[42] with credit limit[0]".to_string()
+            ))
+        );
+
+        let ret = bank.move_money(acc_2, acc_1, NonZeroMoney::MIN);
+        assert_eq!(
+            ret,
+            Err(BankError::BadRequest(
+                "Insufficient funds, available[0] with credit limit[0]".to_string()
+            ))
+        );
+
+        //withdraw leg is valid, deposit leg is not
+        let ret = bank.move_money(acc_1, unknown, NonZeroMoney::MIN);
+        assert!(ret.is_err());
+
+        let ret = bank.get_balance(&acc_1);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1,
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let history = bank
+            .get_history()
+            .expect("Bank should get history")
+            .map(Result::unwrap);
+        assert_eq!(history.count(), 3); //Create + Create + Deposit
+
+        let ret = bank.get_account_ops(&acc_1);
+        assert_eq!(ret.unwrap().count(), 2); //Create + Deposit
+
+        let ret = bank.get_account_ops(&unknown);
+        assert!(ret.is_err());
+    }
+
+    pub(crate) fn bank_should_restore_from_history_of_successful_operations<T, S>(
+        mut bank: Bank<T, S>,
+    ) where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let _ = bank.withdraw(acc_2, NonZeroMoney::MIN); //rejected
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(12).unwrap());
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(31).unwrap()); //rejected
+
+        let history = bank
+            .get_history()
+            .expect("Bank should get history")
+            .map(Result::unwrap);
+        let clone_of_bank: Bank<T, S> = Bank::from(history).unwrap();
+
+        //восстановленная история совпадает с исходной вместе со временем операций
+        let original: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let restored: History = clone_of_bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(original, restored);
+
+        assert_eq!(clone_of_bank.get_balance(&acc_1), bank.get_balance(&acc_1));
+        assert_eq!(clone_of_bank.get_balance(&acc_2), bank.get_balance(&acc_2));
+    }
+
+    pub(crate) fn bank_should_charge_fees<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let fee_acc = 0;
+        let acc_1 = 128;
+        let acc_2 = 129;
+
+        let mut bank = bank.with_fees(fee_acc, FlatFee(2)).unwrap();
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+
+        assert_eq!(bank.fee(&acc_1, FeeKind::Deposit, NonZeroMoney::MIN), 2);
+        assert_eq!(bank.fee(&fee_acc, FeeKind::Deposit, NonZeroMoney::MIN), 0);
+
+        let ret = bank.deposit(&acc_1, NonZeroMoney::new(10).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1,
+                balance: 8,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = bank.withdraw(acc_1, NonZeroMoney::new(3).unwrap());
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_1,
+                balance: 3,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let (from, to) = bank
+            .move_money(acc_1, acc_2, NonZeroMoney::MIN)
+            .expect("should be Ok((from,to))");
+        assert_eq!(from.balance, 0);
+        assert_eq!(to.balance, 1);
+
+        //денег хватает на снятие, но не на комиссию
+        let ret = bank.withdraw(acc_2, NonZeroMoney::MIN);
+        assert_eq!(
+            ret,
+            Err(BankError::BadRequest(
+                "Insufficient funds, available[0] with credit limit[0]".to_string()
+            ))
+        );
+
+        assert_eq!(bank.get_balance(&fee_acc).unwrap().balance, 6);
+        assert_eq!(bank.get_account_ops(&fee_acc).unwrap().count(), 4); //Create + 3 Fee
+        let fees = bank
+            .get_account_ops(&acc_1)
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|(_, _, op)| matches!(op, Operation::Fee { .. }))
+            .count();
+        assert_eq!(fees, 3);
+
+        let clone_of_bank: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        for account_id in [fee_acc, acc_1, acc_2] {
+            assert_eq!(
+                clone_of_bank.get_balance(&account_id),
+                bank.get_balance(&account_id)
+            );
+        }
+    }
+
+    pub(crate) fn bank_should_exchange_currencies<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let fee_acc = 0;
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let money = |amount| NonZeroMoney::new(amount).unwrap();
+
+        let mut bank = bank
+            .with_fees(fee_acc, FlatFee(2))
+            .unwrap()
+            .with_rates(StaticRates::default().with(Currency::USD, Currency::RUB, 80_000_000));
+        let _ = bank.create_account(acc_1);
+        let ret = bank.create_account_in(acc_2, Currency::USD);
+        assert_eq!(
+            ret,
+            Ok(Account {
+                account_id: acc_2,
+                balance: 0,
+                currency: Currency::USD,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+
+        let ret = bank.deposit_in(&acc_2, money(10), Currency::RUB);
+        assert_eq!(
+            ret,
+            Err(BankError::BadRequest(
+                "Account[129] is in USD, not in RUB".to_owned()
+            ))
+        );
+        //комиссия в рублях со счёта в долларах не берётся
+        let ret = bank.deposit_in(&acc_2, money(10), Currency::USD);
+        assert_eq!(ret.unwrap().balance, 10);
+
+        let ret = bank.move_money(acc_2, acc_1, money(3));
+        let (from, to) = ret.unwrap();
+        assert_eq!((from.balance, to.balance), (7, 240));
+        let rate = Rate {
+            from: Currency::USD,
+            to: Currency::RUB,
+            per_million: 80_000_000,
+        };
+        let ops: Vec<Operation> = bank
+            .get_account_ops(&acc_1)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, _, op)| op.clone())
+            .collect();
+        assert_eq!(
+            ops.last(),
+            Some(&Operation::Exchange {
+                from: acc_2,
+                to: acc_1,
+                amount: money(3),
+                rate,
+            })
+        );
 
-        let ret: &Account = bank
-            .get_balance(&acc_3)
-            .expect("should be Ok(Account for acc_3)");
+        //обратного курса нет
+        let ret = bank.move_money(acc_1, acc_2, money(100));
         assert_eq!(
             ret,
-            &Account {
-                account_id: acc_3.clone(),
-                balance: 21
-            }
+            Err(BankError::BadRequest(
+                "There is no exchange rate from RUB to USD".to_owned()
+            ))
         );
 
-        let ret: &Account = bank
-            .get_balance(&acc_2)
-            .expect("should be Ok(Account for acc_2)");
+        let ret = bank.withdraw_in(acc_1, money(40), Currency::RUB);
+        assert_eq!(ret.unwrap().balance, 198);
+        assert_eq!(bank.get_balance(&fee_acc).unwrap().balance, 2);
+
+        //курс взят из истории, поэтому восстановление не зависит от текущих курсов
+        let clone_of_bank: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        for account_id in [fee_acc, acc_1, acc_2] {
+            assert_eq!(
+                clone_of_bank.get_balance(&account_id),
+                bank.get_balance(&account_id)
+            );
+        }
+    }
+
+    pub(crate) fn bank_should_lend_within_credit_limit<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let unknown = 130;
+        let money = |amount| NonZeroMoney::new(amount).unwrap();
+
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.deposit(&acc_1, money(10));
+
+        let ret = bank.set_credit_limit(acc_1, 50);
+        assert_eq!(ret.unwrap().credit_limit, 50);
+        let ret = bank.set_credit_limit(unknown, 50);
+        assert!(ret.is_err());
+
+        let ret = bank.withdraw(acc_1, money(40));
+        assert_eq!(ret.unwrap().balance, -30);
+        let ret = bank.withdraw(acc_1, money(31));
         assert_eq!(
             ret,
-            &Account {
-                account_id: acc_2.clone(),
-                balance: 42
-            }
+            Err(BankError::BadRequest(
+                "Insufficient funds, available[20] with credit limit[50]".to_owned()
+            ))
         );
 
-        let ret: &Account = bank
-            .get_balance(&acc_1)
-            .expect("should be Ok(Account for acc_1)");
+        let ret = bank.move_money(acc_1, acc_2, money(20));
+        let (from, to) = ret.unwrap();
+        assert_eq!((from.balance, from.available(), to.balance), (-50, 0, 20));
+
+        let ret = bank.set_credit_limit(acc_1, 49);
         assert_eq!(
             ret,
-            &Account {
-                account_id: acc_1.clone(),
-                balance: 0
-            }
+            Err(BankError::BadRequest(
+                "Credit limit[49] is less than the debt[50] of account[128]".to_owned()
+            ))
         );
+        let ret = bank.set_credit_limit(acc_1, 100);
+        assert_eq!(ret.unwrap().available(), 50);
+
+        let limits = bank
+            .get_account_ops(&acc_1)
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|(_, _, op)| matches!(op, Operation::SetCreditLimit(..)))
+            .count();
+        assert_eq!(limits, 2);
+
+        let clone_of_bank: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        assert_eq!(clone_of_bank.get_balance(&acc_1), bank.get_balance(&acc_1));
+        assert_eq!(clone_of_bank.get_balance(&acc_2), bank.get_balance(&acc_2));
     }
 
-    #[test]
-    fn bank_should_get_balance() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
-
+    pub(crate) fn bank_should_freeze_and_close_accounts<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
         let acc_1 = 128;
         let acc_2 = 129;
         let acc_3 = 130;
+        let money = |amount| NonZeroMoney::new(amount).unwrap();
+        let frozen = || Err(BankError::Prohibited("Account[128] is frozen".to_owned()));
 
         let _ = bank.create_account(acc_1);
         let _ = bank.create_account(acc_2);
         let _ = bank.create_account(acc_3);
-
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let _ = bank.deposit(&acc_1, money(42));
+
+        let ret = bank.freeze_account(acc_1);
+        assert_eq!(ret.unwrap().status, AccountStatus::Frozen);
+        assert_eq!(bank.deposit(&acc_1, money(1)), frozen());
+        assert_eq!(bank.withdraw(acc_1, money(1)), frozen());
+        assert_eq!(bank.set_credit_limit(acc_1, 1), frozen());
+        assert!(bank.move_money(acc_2, acc_1, money(1)).is_err());
+        assert!(bank.close_account(acc_1, Some(acc_2)).is_err());
+        let ret = bank.freeze_account(acc_1);
         assert_eq!(
             ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 42
-            })
+            Err(BankError::Prohibited(
+                "Account[128] is frozen, but should be active".to_owned()
+            ))
         );
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
-        assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_3.clone(),
-                balance: 21
-            })
-        );
+        let ret = bank.unfreeze_account(acc_1);
+        assert_eq!(ret.unwrap().status, AccountStatus::Active);
+        assert!(bank.unfreeze_account(acc_1).is_err());
 
-        let ret = bank.get_balance(&acc_1).expect("should be Account 1");
+        let ret = bank.close_account(acc_1, None);
         assert_eq!(
             ret,
-            &Account {
-                account_id: acc_1.clone(),
-                balance: 42
-            }
+            Err(BankError::BadRequest(
+                "Account[128] has funds[42], a payout account is required".to_owned()
+            ))
         );
-        let ret = bank.get_balance(&acc_2).expect("should be Account 2");
+        let ret = bank.close_account(acc_1, Some(acc_2));
+        let closed = ret.unwrap();
+        assert_eq!((closed.status, closed.balance), (AccountStatus::Closed, 0));
+        assert_eq!(bank.get_balance(&acc_2).unwrap().balance, 42);
         assert_eq!(
-            ret,
-            &Account {
-                account_id: acc_2.clone(),
-                balance: 0
-            }
+            bank.deposit(&acc_1, money(1)),
+            Err(BankError::Prohibited("Account[128] is closed".to_owned()))
         );
-        let ret = bank.get_balance(&acc_3).expect("should be Account 3");
+        assert!(bank.create_account(acc_1).is_err());
+        assert!(bank.unfreeze_account(acc_1).is_err());
+
+        let ret = bank.close_account(acc_3, None);
+        assert_eq!(ret.unwrap().status, AccountStatus::Closed);
+
+        //долг не даёт закрыть счёт
+        let _ = bank.set_credit_limit(acc_2, 10);
+        let _ = bank.withdraw(acc_2, money(52));
+        let ret = bank.close_account(acc_2, None);
         assert_eq!(
             ret,
-            &Account {
-                account_id: acc_3.clone(),
-                balance: 21
-            }
+            Err(BankError::BadRequest(
+                "Account[129] can't be closed with balance[-10]".to_owned()
+            ))
         );
+
+        let clone_of_bank: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        for account_id in [acc_1, acc_2, acc_3] {
+            assert_eq!(
+                clone_of_bank.get_balance(&account_id),
+                bank.get_balance(&account_id)
+            );
+        }
     }
 
-    #[test]
-    fn bank_should_get_history() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+    pub(crate) fn bank_should_hold_large_balances<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let _ = bank.create_account(acc_1);
+
+        let _ = bank.deposit(&acc_1, NonZeroMoney::MAX);
+        let ret = bank.deposit(&acc_1, NonZeroMoney::MAX);
+        assert_eq!(ret.unwrap().balance, 2 * SignedMoney::from(Money::MAX));
+
+        let ret = bank.withdraw(acc_1, NonZeroMoney::MAX);
+        assert_eq!(ret.unwrap().balance, SignedMoney::from(Money::MAX));
+        assert_eq!(bank.get_balance(&acc_1).unwrap().funds(), Money::MAX);
+    }
 
+    pub(crate) fn bank_should_record_transfer_with_counterparty<T, S>(mut bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
         let acc_1 = 128;
         let acc_2 = 129;
-        let acc_3 = 130;
-
         let _ = bank.create_account(acc_1);
         let _ = bank.create_account(acc_2);
-        let _ = bank.create_account(acc_3);
-
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        let _ = bank.withdraw(acc_1, NonZeroMoney::new(2).unwrap());
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(12).unwrap());
+
+        let transfer = Operation::Transfer {
+            from: acc_1,
+            to: acc_2,
+            amount: NonZeroMoney::new(12).unwrap(),
+        };
+        //перевод - одна операция в общей истории
+        let history: Vec<Operation> = bank
+            .get_history()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, _, op)| op.clone())
+            .collect();
+        assert_eq!(history.last(), Some(&transfer));
+        assert_eq!(history.len(), 5);
+
+        //и в истории каждого счёта, вместе с другой стороной перевода
+        let ops_1: Vec<(Operation, Option<AccountId>)> = bank
+            .get_account_ops(&acc_1)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, _, op)| (op.clone(), op.counterparty(&acc_1).copied()))
+            .collect();
         assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 42
-            })
+            ops_1,
+            vec![
+                (Operation::Create(acc_1), None),
+                (
+                    Operation::Deposit(acc_1, NonZeroMoney::new(42).unwrap()),
+                    None
+                ),
+                (
+                    Operation::Withdraw(acc_1, NonZeroMoney::new(2).unwrap()),
+                    None
+                ),
+                (transfer.clone(), Some(acc_2)),
+            ]
         );
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_3, NonZeroMoney::new(21).unwrap());
+        let ops_2: Vec<(Operation, Option<AccountId>)> = bank
+            .get_account_ops(&acc_2)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, _, op)| (op.clone(), op.counterparty(&acc_2).copied()))
+            .collect();
         assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_3.clone(),
-                balance: 21
-            })
+            ops_2,
+            vec![(Operation::Create(acc_2), None), (transfer, Some(acc_1))]
         );
 
-        let history = bank.get_history().expect("Bank should get history");
+        //перевод сверх остатка не меняет ни один из счетов
+        let ret = bank.move_money(acc_1, acc_2, NonZeroMoney::new(29).unwrap());
+        assert!(ret.is_err());
+        assert_eq!(bank.get_balance(&acc_1).unwrap().balance, 28);
+        assert_eq!(bank.get_balance(&acc_2).unwrap().balance, 12);
+        assert_eq!(bank.get_history().unwrap().count(), 5);
+    }
 
-        let clone_of_bank: Bank<InMemoryOpsStorage, InMemoryState> = Bank::from(history);
+    pub(crate) fn bank_should_execute_keyed_requests_once<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let fee_acc = 0;
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let mut bank = bank.with_fees(fee_acc, FlatFee(1)).unwrap();
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
 
-        let ret = clone_of_bank.get_balance(&acc_1);
+        //отпечатки запросов, см. idempotency::fingerprint
+        let (deposit_request, move_request) = (10, 20);
+        let deposit = |bank: &mut Bank<T, S>| {
+            bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap())
+                .map(drop)
+        };
+        let receipt = bank
+            .idempotent(1, deposit_request, deposit)
+            .cloned()
+            .unwrap();
+        assert_eq!(receipt.fee, 1);
+        assert_eq!(receipt.accounts[0].balance, 41);
+        assert_eq!(receipt.accounts[1].account_id, fee_acc);
+
+        //повтор не выполняется и получает ту же квитанцию
+        let ops_count = bank.get_history().unwrap().count();
+        assert_eq!(bank.idempotent(1, deposit_request, deposit), Ok(&receipt));
+        assert_eq!(bank.get_history().unwrap().count(), ops_count);
+        assert_eq!(bank.get_balance(&acc_1).unwrap().balance, 41);
+
+        //тот же ключ с другим запросом не получает чужую квитанцию
+        assert!(matches!(
+            bank.idempotent(1, move_request, deposit),
+            Err(BankError::BadRequest(_))
+        ));
+        assert_eq!(bank.get_history().unwrap().count(), ops_count);
+
+        let move_money = |bank: &mut Bank<T, S>| {
+            bank.move_money(acc_1, acc_2, NonZeroMoney::new(50).unwrap())
+                .map(drop)
+        };
+        //ошибка не запоминается, запрос с тем же ключом можно повторить
+        assert!(bank.idempotent(2, move_request, move_money).is_err());
+        assert!(bank.receipt_of(&2).is_none());
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(20).unwrap());
+        let moved = bank
+            .idempotent(2, move_request, move_money)
+            .cloned()
+            .unwrap();
         assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 42
-            })
+            moved
+                .accounts
+                .iter()
+                .map(|account| (account.account_id, account.balance))
+                .collect::<Vec<_>>(),
+            vec![(acc_1, 9), (acc_2, 50), (fee_acc, 3)]
         );
+        assert_eq!(bank.get_balance(&acc_1).unwrap().balance, 9);
+
+        //после ошибки до выполнения транзакции ключ не переходит к следующему запросу
+        let self_move =
+            |bank: &mut Bank<T, S>| bank.move_money(acc_1, acc_1, NonZeroMoney::MIN).map(drop);
+        assert!(bank.idempotent(3, move_request, self_move).is_err());
+        let _ = bank.deposit(&acc_2, NonZeroMoney::MIN);
+        assert!(bank.receipt_of(&3).is_none());
+
+        //запрос, не меняющий банк, не может иметь ключа
+        let get_balance = |bank: &mut Bank<T, S>| bank.get_balance(&acc_1).map(drop);
+        assert!(matches!(
+            bank.idempotent(4, 40, get_balance),
+            Err(BankError::BadRequest(_))
+        ));
+
+        //квитанции сохраняются в истории и переживают восстановление
+        let mut restored: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        assert_eq!(restored.receipt_of(&1), Some(&receipt));
+        assert_eq!(restored.receipt_of(&2), Some(&moved));
+        assert!(matches!(
+            restored.idempotent(2, deposit_request, deposit),
+            Err(BankError::BadRequest(_))
+        ));
+
+        let mut bank = bank.with_receipts_capacity(1);
+        assert!(bank.receipt_of(&1).is_none());
+        assert_eq!(bank.receipt_of(&2), Some(&moved));
+        let _ = bank.idempotent(1, deposit_request, deposit);
+        assert_eq!(bank.get_balance(&acc_1).unwrap().balance, 50);
+    }
 
-        let ret = clone_of_bank.get_balance(&acc_2);
+    pub(crate) fn bank_should_get_balance_at_operation<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let fee_acc = 0;
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let usd_acc = 130;
+        let mut bank = bank
+            .with_fees(fee_acc, FlatFee(1))
+            .unwrap()
+            .with_rates(StaticRates::default().with(Currency::RUB, Currency::USD, 10_000));
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.create_account_in(usd_acc, Currency::USD);
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(500).unwrap());
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(100).unwrap());
+        let _ = bank.move_money(acc_1, usd_acc, NonZeroMoney::new(200).unwrap());
+        let _ = bank.set_credit_limit(acc_2, 50);
+        let _ = bank.withdraw(acc_2, NonZeroMoney::new(120).unwrap());
+        let _ = bank.freeze_account(acc_2);
+
+        //на каждой операции баланс из истории совпадает с повторным выполнением истории до неё
+        let history: Vec<(OpId, Operation)> = bank
+            .get_history()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(op_id, _, op)| (op_id, op))
+            .collect();
+        for (op_id, _) in &history {
+            let mut state = InMemoryState::default();
+            for (_, op) in history.iter().take_while(|(id, _)| id <= op_id) {
+                let _ = state.update(op).unwrap();
+            }
+            for account_id in [fee_acc, acc_1, acc_2, usd_acc] {
+                assert_eq!(
+                    bank.get_balance_at(&account_id, *op_id).ok(),
+                    state.get_balance(&account_id).ok(),
+                    "account[{account_id}] at operation[{op_id}]"
+                );
+            }
+        }
+
+        let last = history.last().unwrap().0;
         assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_2.clone(),
-                balance: 0
-            })
+            bank.get_balance_at(&acc_2, last).ok(),
+            bank.get_balance(&acc_2).ok()
         );
+        assert_eq!(bank.get_balance_at(&acc_2, last).unwrap().balance, -21);
+        assert_eq!(bank.get_balance_at(&usd_acc, last).unwrap().balance, 2);
+
+        //счёта ещё не было
+        assert!(matches!(
+            bank.get_balance_at(&acc_2, history[0].0),
+            Err(BankError::BadRequest(_))
+        ));
+        assert!(bank.get_balance_at(&131, last).is_err());
+    }
 
-        let ret = clone_of_bank.get_balance(&acc_3);
+    pub(crate) fn bank_should_stamp_operations_with_clock<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId> + Default,
+        S: State<Account = Account> + Default,
+    {
+        let fee_acc = 0;
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let clock = Arc::new(ManualClock::new(1_000));
+        let mut bank = bank
+            .with_clock(Arc::clone(&clock))
+            .with_fees(fee_acc, FlatFee(1))
+            .unwrap();
+        let _ = bank.create_account(acc_1);
+        clock.advance(10);
+        let _ = bank.create_account(acc_2);
+        clock.advance(10);
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
+        clock.advance(10);
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(12).unwrap());
+        clock.advance(10);
+        let _ = bank.withdraw(acc_2, NonZeroMoney::new(100).unwrap()); //rejected
+
+        //операции одной транзакции получают одно время
+        let times: Vec<Timestamp> = bank
+            .get_account_ops(&acc_1)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, at, _)| at)
+            .collect();
+        assert_eq!(times, vec![1_000, 1_020, 1_020, 1_030, 1_030]);
+        let times: Vec<(Timestamp, Operation)> = bank
+            .get_account_ops(&acc_2)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(_, at, op)| (at, op))
+            .collect();
         assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_3.clone(),
-                balance: 21
-            })
+            times,
+            vec![
+                (1_010, Operation::Create(acc_2)),
+                (
+                    1_030,
+                    Operation::Transfer {
+                        from: acc_1,
+                        to: acc_2,
+                        amount: NonZeroMoney::new(12).unwrap(),
+                    }
+                ),
+            ]
         );
-    }
 
-    #[test]
-    fn bank_should_get_account_ops() {
-        let mut bank: Bank<InMemoryOpsStorage, InMemoryState> =
-            Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+        //восстановление по истории сохраняет исходное время, а не время восстановления
+        clock.set(5_000);
+        let restored: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        let original: Vec<(OpId, Timestamp)> = bank
+            .get_history()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(op_id, at, _)| (op_id, at))
+            .collect();
+        let replayed: Vec<(OpId, Timestamp)> = restored
+            .get_history()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(op_id, at, _)| (op_id, at))
+            .collect();
+        assert_eq!(original, replayed);
+        assert_eq!(original.first().map(|(_, at)| *at), Some(1_000)); //создание счёта комиссий
+    }
 
+    pub(crate) fn bank_should_query_history_by_pages<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let fee_acc = 0;
         let acc_1 = 128;
         let acc_2 = 129;
-
+        let mut bank = bank.with_fees(fee_acc, FlatFee(1)).unwrap();
         let _ = bank.create_account(acc_1);
         let _ = bank.create_account(acc_2);
+        for amount in 1..=10 {
+            let _ = bank.deposit(&acc_1, NonZeroMoney::new(amount * 10).unwrap());
+            let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(amount).unwrap());
+        }
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_1, NonZeroMoney::new(42).unwrap());
-        assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 42
+        //постраничный обход с фильтрами совпадает с фильтрацией всей истории
+        let query = HistoryQuery {
+            account_id: Some(acc_2),
+            kinds: vec![OperationKind::Transfer, OperationKind::Withdraw],
+            min_amount: Some(3),
+            max_amount: Some(8),
+            limit: 4,
+            ..HistoryQuery::default()
+        };
+        let expected: Vec<OpId> = bank
+            .get_account_ops(&acc_2)
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|(op_id, _, op)| query.matches(*op_id, op))
+            .map(|(op_id, _, _)| op_id)
+            .collect();
+        assert_eq!(expected.len(), 6);
+
+        let first = bank.query(&query).unwrap();
+        assert_eq!(first.ops.len(), 4);
+        assert_eq!(first.next, Some(first.ops[3].0));
+        assert!(first
+            .ops
+            .iter()
+            .all(|(_, _, op)| op.kind() == OperationKind::Transfer
+                && op.amount().is_some_and(|amount| (3..=8).contains(&amount))));
+        let second = bank
+            .query(&HistoryQuery {
+                after: first.next,
+                ..query.clone()
+            })
+            .unwrap();
+        assert_eq!(second.next, None);
+        let paged: Vec<OpId> = first
+            .ops
+            .iter()
+            .chain(second.ops.iter())
+            .map(|(op_id, _, _)| *op_id)
+            .collect();
+        assert_eq!(paged, expected);
+
+        //диапазон OpId по всей истории: операции Fee в нём
+        let history: Vec<OpId> = bank
+            .get_history()
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        let page = bank
+            .query(&HistoryQuery {
+                from: Some(history[5]),
+                to: Some(history[15]),
+                kinds: vec![OperationKind::Fee],
+                ..HistoryQuery::default()
             })
+            .unwrap();
+        assert_eq!(page.next, None);
+        assert!(!page.ops.is_empty());
+        assert!(page.ops.iter().all(|(op_id, _, op)| {
+            (history[5]..=history[15]).contains(op_id) && op.kind() == OperationKind::Fee
+        }));
+        assert_eq!(
+            page.ops.len(),
+            bank.get_history()
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|(op_id, _, op)| (history[5]..=history[15]).contains(op_id)
+                    && op.kind() == OperationKind::Fee)
+                .count()
         );
 
-        let ret: Result<&Account, BankError> = bank.deposit(&acc_2, NonZeroMoney::new(21).unwrap());
-        assert_eq!(
-            ret,
-            Ok(&Account {
-                account_id: acc_2.clone(),
-                balance: 21
+        //фильтр, которому не соответствует ни одна операция, не читает всю историю за один запрос:
+        //страницы пустые, но с курсором, пока история не закончится
+        let query = HistoryQuery {
+            kinds: vec![OperationKind::Freeze],
+            limit: 2,
+            ..HistoryQuery::default()
+        };
+        let scan = query.limit * HistoryQuery::<AccountId>::SCAN_FACTOR;
+        assert!(history.len() > scan);
+        let first = bank.query(&query).unwrap();
+        assert_eq!(first.ops, vec![]);
+        assert_eq!(first.next, Some(history[scan - 1]));
+        let mut pages = 1;
+        let mut next = first.next;
+        while let Some(after) = next {
+            let page = bank
+                .query(&HistoryQuery {
+                    after: Some(after),
+                    ..query.clone()
+                })
+                .unwrap();
+            assert_eq!(page.ops, vec![]);
+            pages += 1;
+            next = page.next;
+        }
+        //последняя страница - та, на которой история закончилась раньше лимита просмотра
+        assert_eq!(pages, history.len() / scan + 1);
+
+        //курсор после диапазона - пустая страница
+        let page = bank
+            .query(&HistoryQuery {
+                to: Some(history[3]),
+                after: Some(history[3]),
+                ..HistoryQuery::default()
             })
-        );
+            .unwrap();
+        assert_eq!(page.ops, vec![]);
 
-        let _ = bank
-            .move_money(acc_1, acc_2, NonZeroMoney::new(42).unwrap())
-            .expect("move should work");
+        assert!(bank
+            .query(&HistoryQuery {
+                limit: 0,
+                ..HistoryQuery::default()
+            })
+            .is_err());
+        //вся история одним ответом не отдаётся
+        assert!(matches!(
+            bank.query(&HistoryQuery {
+                limit: usize::MAX,
+                ..HistoryQuery::default()
+            }),
+            Err(BankError::BadRequest(_))
+        ));
+        assert!(bank
+            .query(&HistoryQuery {
+                limit: HistoryQuery::<AccountId>::MAX_LIMIT,
+                ..HistoryQuery::default()
+            })
+            .is_ok());
+        assert!(bank
+            .query(&HistoryQuery {
+                account_id: Some(130),
+                ..HistoryQuery::default()
+            })
+            .is_err());
+    }
 
-        //let _: Vec<(_, _)> = iter.collect();
+    pub(crate) fn bank_should_pass_audit<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = AccountId>,
+        S: State<Account = Account>,
+    {
+        let acc_1 = 128;
+        let acc_2 = 129;
+        let mut bank = bank.with_fees(0, FlatFee(1)).unwrap();
+        let _ = bank.create_account(acc_1);
+        let _ = bank.create_account(acc_2);
+        let _ = bank.deposit(&acc_1, NonZeroMoney::new(100).unwrap());
+        let _ = bank.move_money(acc_1, acc_2, NonZeroMoney::new(30).unwrap());
+        let _ = bank.withdraw(acc_2, NonZeroMoney::new(10).unwrap());
+        let _ = bank.freeze_account(acc_2);
+        //ошибочные операции не попадают ни в историю, ни в состояние
+        let _ = bank.withdraw(acc_1, NonZeroMoney::new(1_000).unwrap());
+
+        let report = bank.audit().unwrap();
+        assert_eq!(report.issues, vec![]);
+        assert_eq!(report.accounts, 3);
+        assert_eq!(report.ops, bank.get_history().unwrap().count());
+    }
 
-        let ret: Result<&Account, BankError> = bank.get_balance(&acc_2);
+    #[test]
+    fn account_should_reject_balance_overflow() {
+        let mut account = Account {
+            balance: SignedMoney::MAX - 1,
+            ..Account::open(128)
+        };
+        let ret = account.deposit(NonZeroMoney::new(2).unwrap());
         assert_eq!(
             ret,
-            Ok(&Account {
-                account_id: acc_2.clone(),
-                balance: 63
-            })
+            Err(BankError::BadRequest(
+                "Balance of account[128] overflows".to_owned()
+            ))
+        );
+        assert_eq!(account.balance, SignedMoney::MAX - 1);
+        assert!(account.deposit(NonZeroMoney::MIN).is_ok());
+    }
+
+    #[test]
+    fn bank_from_should_reject_broken_history() {
+        let history: History = vec![
+            (OpId::MIN, 0, Operation::Create(128)),
+            (
+                OpId::new(2).unwrap(),
+                0,
+                Operation::Deposit(129, NonZeroMoney::MIN),
+            ),
+        ];
+        let ret: Result<Bank<InMemoryOpsStorage, InMemoryState>, _> =
+            Bank::from(history.into_iter());
+        assert!(
+            matches!(ret, Err(BankError::CoreError(msg)) if msg.starts_with("History operation[2]"))
         );
+    }
+
+    //хранилище, запись в которое перестаёт удаваться, как на переполненном диске
+    #[derive(Debug)]
+    struct FullDisk {
+        ops: InMemoryOpsStorage,
+        full: Rc<Cell<bool>>,
+    }
+
+    impl OpsStorage for FullDisk {
+        type Id = AccountId;
+
+        fn transact(&mut self, at: Timestamp, ops: &[Operation]) -> Result<Vec<OpId>, BankError> {
+            if self.full.get() {
+                return Err(BankError::CoreError("No space left on device".to_owned()));
+            }
+            self.ops.transact(at, ops)
+        }
+
+        fn get_ops(
+            &self,
+            account_id: &AccountId,
+        ) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_ops(account_id)
+        }
+
+        fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_history()
+        }
+
+        fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+            self.ops.get_hash(op_id)
+        }
+
+        fn head_hash(&self) -> Result<ChainHash, BankError> {
+            self.ops.head_hash()
+        }
+    }
+
+    fn revert_state_if_history_is_not_saved<S: State<Account = Account>>(state: S) {
+        let full = Rc::new(Cell::new(false));
+        let storage = FullDisk {
+            ops: InMemoryOpsStorage::default(),
+            full: Rc::clone(&full),
+        };
+        let mut bank = Bank::new(storage, state);
+        bank.create_account(128).unwrap();
+        bank.deposit(&128, NonZeroMoney::new(10).unwrap()).unwrap();
+
+        full.set(true);
+        assert!(bank.create_account(129).is_err());
+        assert!(bank.get_balance(&129).is_err());
+        assert!(bank.withdraw(128, NonZeroMoney::new(4).unwrap()).is_err());
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 10);
+
+        full.set(false);
+        bank.create_account(129).unwrap();
+        bank.move_money(128, 129, NonZeroMoney::new(4).unwrap())
+            .unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 6);
+        assert_eq!(bank.audit().unwrap().issues, vec![]);
+    }
+
+    #[test]
+    fn bank_should_revert_state_if_history_is_not_saved() {
+        revert_state_if_history_is_not_saved(InMemoryState::default());
+        revert_state_if_history_is_not_saved(CachedState::new(SqliteState::default()));
+    }
+
+    //общий набор тестов для любой пары OpsStorage + State
+    macro_rules! bank_test_suite {
+        ($new_bank:expr) => {
+            #[test]
+            fn bank_should_create_account() {
+                $crate::bank::test::bank_should_create_account($new_bank)
+            }
+
+            #[test]
+            fn bank_should_deposit_funds() {
+                $crate::bank::test::bank_should_deposit_funds($new_bank)
+            }
+
+            #[test]
+            fn bank_should_withdraw_funds() {
+                $crate::bank::test::bank_should_withdraw_funds($new_bank)
+            }
+
+            #[test]
+            fn bank_should_move_funds() {
+                $crate::bank::test::bank_should_move_funds($new_bank)
+            }
+
+            #[test]
+            fn bank_should_get_balance() {
+                $crate::bank::test::bank_should_get_balance($new_bank)
+            }
+
+            #[test]
+            fn bank_should_get_history() {
+                $crate::bank::test::bank_should_get_history($new_bank)
+            }
+
+            #[test]
+            fn bank_should_get_account_ops() {
+                $crate::bank::test::bank_should_get_account_ops($new_bank)
+            }
+
+            #[test]
+            fn bank_should_not_persist_rejected_operations() {
+                $crate::bank::test::bank_should_not_persist_rejected_operations($new_bank)
+            }
+
+            #[test]
+            fn bank_should_restore_from_history_of_successful_operations() {
+                $crate::bank::test::bank_should_restore_from_history_of_successful_operations(
+                    $new_bank,
+                )
+            }
+
+            #[test]
+            fn bank_should_charge_fees() {
+                $crate::bank::test::bank_should_charge_fees($new_bank)
+            }
+
+            #[test]
+            fn bank_should_exchange_currencies() {
+                $crate::bank::test::bank_should_exchange_currencies($new_bank)
+            }
+
+            #[test]
+            fn bank_should_lend_within_credit_limit() {
+                $crate::bank::test::bank_should_lend_within_credit_limit($new_bank)
+            }
+
+            #[test]
+            fn bank_should_freeze_and_close_accounts() {
+                $crate::bank::test::bank_should_freeze_and_close_accounts($new_bank)
+            }
+
+            #[test]
+            fn bank_should_hold_large_balances() {
+                $crate::bank::test::bank_should_hold_large_balances($new_bank)
+            }
+
+            #[test]
+            fn bank_should_record_transfer_with_counterparty() {
+                $crate::bank::test::bank_should_record_transfer_with_counterparty($new_bank)
+            }
+
+            #[test]
+            fn bank_should_execute_keyed_requests_once() {
+                $crate::bank::test::bank_should_execute_keyed_requests_once($new_bank)
+            }
+
+            #[test]
+            fn bank_should_get_balance_at_operation() {
+                $crate::bank::test::bank_should_get_balance_at_operation($new_bank)
+            }
+
+            #[test]
+            fn bank_should_stamp_operations_with_clock() {
+                $crate::bank::test::bank_should_stamp_operations_with_clock($new_bank)
+            }
+
+            #[test]
+            fn bank_should_query_history_by_pages() {
+                $crate::bank::test::bank_should_query_history_by_pages($new_bank)
+            }
+
+            #[test]
+            fn bank_should_pass_audit() {
+                $crate::bank::test::bank_should_pass_audit($new_bank)
+            }
+        };
+    }
+
+    pub(crate) use bank_test_suite;
+
+    mod in_memory {
+        use super::*;
 
-        let ret: Result<&Account, BankError> = bank.get_balance(&acc_1);
+        bank_test_suite!(Bank::new(
+            InMemoryOpsStorage::default(),
+            InMemoryState::default()
+        ));
+    }
+
+    //счёт со своей моделью: текстовый идентификатор, баланс u64 и счётчик операций
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub(crate) struct NamedAccount {
+        pub(crate) name: String,
+        pub(crate) balance: u64,
+        pub(crate) ops_count: u32,
+    }
+
+    impl BankAccount for NamedAccount {
+        type Id = String;
+        type Balance = u64;
+
+        fn open(name: String) -> Self {
+            NamedAccount {
+                name,
+                balance: 0,
+                ops_count: 0,
+            }
+        }
+
+        fn account_id(&self) -> &String {
+            &self.name
+        }
+
+        fn balance(&self) -> u64 {
+            self.balance
+        }
+
+        fn deposit(&mut self, money: NonZeroMoney) -> Result<(), BankError> {
+            self.balance = self
+                .balance
+                .checked_add(money.get())
+                .ok_or_else(|| BankError::BadRequest(format!("{} is too rich", self.name)))?;
+            self.ops_count += 1;
+            Ok(())
+        }
+
+        fn withdraw(&mut self, money: NonZeroMoney) -> Result<(), BankError> {
+            self.balance = self
+                .balance
+                .checked_sub(money.get())
+                .ok_or_else(|| BankError::BadRequest(format!("{} is short of money", self.name)))?;
+            self.ops_count += 1;
+            Ok(())
+        }
+    }
+
+    pub(crate) fn bank_should_work_with_custom_account<T, S>(bank: Bank<T, S>)
+    where
+        T: OpsStorage<Id = String> + Default,
+        S: State<Account = NamedAccount> + Default,
+    {
+        let alice = "alice".to_owned();
+        let bob = "bob".to_owned();
+
+        let mut bank = bank.with_fees("bank".to_owned(), FlatFee(1)).unwrap();
+        let _ = bank.create_account(alice.clone());
+        let _ = bank.create_account(bob.clone());
+        let _ = bank.deposit(&alice, NonZeroMoney::new(42).unwrap());
+
+        let ret = bank.move_money(alice.clone(), bob.clone(), NonZeroMoney::new(12).unwrap());
+        let (from, to) = ret.unwrap();
+        assert_eq!((from.balance(), from.ops_count), (28, 4));
+        assert_eq!((to.balance(), to.ops_count), (12, 1));
+
+        let ret = bank.withdraw(bob.clone(), NonZeroMoney::new(12).unwrap()); //fee is not covered
         assert_eq!(
             ret,
-            Ok(&Account {
-                account_id: acc_1.clone(),
-                balance: 0
-            })
+            Err(BankError::BadRequest("bob is short of money".to_owned()))
         );
+        assert_eq!(bank.get_balance(&"bank".to_owned()).unwrap().balance(), 2);
+        assert!(bank
+            .get_account_ops(&bob)
+            .unwrap()
+            .map(Result::unwrap)
+            .all(|(_, _, op)| op.account_ids().contains(&bob)));
+
+        let clone_of_bank: Bank<T, S> =
+            Bank::from(bank.get_history().unwrap().map(Result::unwrap)).unwrap();
+        for account_id in [alice, bob] {
+            assert_eq!(
+                clone_of_bank.get_balance(&account_id),
+                bank.get_balance(&account_id)
+            );
+        }
+    }
 
-        let ret = bank.get_account_ops(&acc_1);
-        assert!(ret.is_ok());
-        assert_eq!(ret.unwrap().count(), 3); //Create + Deposit + Withdraw
-
-        let ret = bank.get_account_ops(&acc_2);
-        assert!(ret.is_ok());
-        assert_eq!(ret.unwrap().count(), 3); //Create + Deposit + Deposit
+    #[test]
+    fn in_memory_bank_should_work_with_custom_account() {
+        bank_should_work_with_custom_account(Bank::new(
+            InMemoryOpsStorage::default(),
+            InMemoryState::default(),
+        ));
     }
 }
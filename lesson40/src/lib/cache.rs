@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::bank::{BankAccount, BankError, IdOf, Operation, Prior, State};
+
+//статистика кэша счетов
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+//последние прочитанные или изменённые счета, при превышении capacity вытесняется
+//счёт, к которому дольше всего не обращались. Порядок обращений - номер обращения tick
+#[derive(Debug)]
+struct Lru<A: BankAccount> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<A::Id, (A, u64)>,
+    order: BTreeMap<u64, A::Id>,
+    stats: CacheStats,
+}
+
+impl<A: BankAccount> Lru<A> {
+    fn get(&mut self, account_id: &A::Id) -> Option<A> {
+        let tick = self.next_tick();
+        let (account, used) = self.entries.get_mut(account_id)?;
+        self.order.remove(used);
+        self.order.insert(tick, account_id.clone());
+        *used = tick;
+        Some(account.clone())
+    }
+
+    fn put(&mut self, account: A) {
+        let tick = self.next_tick();
+        let account_id = account.account_id().clone();
+        if let Some((_, used)) = self.entries.insert(account_id.clone(), (account, tick)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(tick, account_id);
+        while self.entries.len() > self.capacity {
+            if let Some((_, account_id)) = self.order.pop_first() {
+                self.entries.remove(&account_id);
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, account_id: &A::Id) {
+        if let Some((_, used)) = self.entries.remove(account_id) {
+            self.order.remove(&used);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+//состояние с кэшем счетов перед другим состоянием, например, SqliteState.
+//Чтение счёта сначала ищется в кэше, промах читается из inner и запоминается.
+//Транзакция выполняется в inner, и счета, которые она вернула, записываются в кэш.
+//Остальные счета транзакции (получатели перевода, обмена и комиссии) inner не возвращает,
+//поэтому они удаляются из кэша и будут прочитаны заново.
+//Кэш верен, пока inner изменяется только через этот CachedState
+#[derive(Debug)]
+pub struct CachedState<S: State> {
+    inner: S,
+    //чтение через &self тоже меняет порядок вытеснения и статистику
+    cache: Mutex<Lru<S::Account>>,
+}
+
+impl<S: State> CachedState<S> {
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    pub fn new(inner: S) -> CachedState<S> {
+        CachedState::with_capacity(inner, Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(inner: S, capacity: usize) -> CachedState<S> {
+        CachedState {
+            inner,
+            cache: Mutex::new(Lru {
+                capacity: capacity.max(1),
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, BankError> {
+        Ok(self.cache()?.stats)
+    }
+
+    //количество счетов в кэше
+    pub fn len(&self) -> Result<usize, BankError> {
+        Ok(self.cache()?.entries.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, BankError> {
+        Ok(self.cache()?.entries.is_empty())
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn cache(&self) -> Result<MutexGuard<'_, Lru<S::Account>>, BankError> {
+        self.cache
+            .lock()
+            .map_err(|_| BankError::CoreError("Account cache lock is poisoned".to_owned()))
+    }
+}
+
+impl<S: State + Default> Default for CachedState<S> {
+    fn default() -> Self {
+        CachedState::new(S::default())
+    }
+}
+
+impl<S: State> State for CachedState<S> {
+    type Account = S::Account;
+
+    fn transact<'b>(
+        &mut self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<Vec<S::Account>, BankError> {
+        let ops: Vec<_> = ops.collect();
+        let ret = self.inner.transact(ops.iter().copied());
+
+        let cache = self
+            .cache
+            .get_mut()
+            .map_err(|_| BankError::CoreError("Account cache lock is poisoned".to_owned()))?;
+        //после ошибки (например, ввода-вывода) неизвестно, что осталось в inner
+        for account_id in ops.iter().flat_map(|op| op.account_ids()) {
+            cache.remove(&account_id);
+        }
+        let accounts = ret?;
+        for account in &accounts {
+            cache.put(account.clone());
+        }
+        Ok(accounts)
+    }
+
+    fn revert(&mut self, accounts: Prior<S::Account>) -> Result<(), BankError> {
+        let cache = self
+            .cache
+            .get_mut()
+            .map_err(|_| BankError::CoreError("Account cache lock is poisoned".to_owned()))?;
+        for (account_id, _) in &accounts {
+            cache.remove(account_id);
+        }
+        self.inner.revert(accounts)
+    }
+
+    //проверка читает счета из inner: по ней нельзя судить о частоте чтений
+    fn validate<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<(), BankError> {
+        self.inner.validate(ops)
+    }
+
+    //копии для отката тоже читаются мимо кэша
+    fn prior<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<IdOf<Self>>>,
+    ) -> Result<Prior<S::Account>, BankError> {
+        self.inner.prior(ops)
+    }
+
+    fn get_balance(&self, account_id: &IdOf<Self>) -> Result<S::Account, BankError> {
+        {
+            let mut cache = self.cache()?;
+            if let Some(account) = cache.get(account_id) {
+                cache.stats.hits += 1;
+                return Ok(account);
+            }
+            cache.stats.misses += 1;
+        }
+        //inner читается без блокировки кэша: менять inner может только transact через &mut self
+        let account = self.inner.get_balance(account_id)?;
+        self.cache()?.put(account.clone());
+        Ok(account)
+    }
+
+    //обход всех счетов идёт мимо кэша, чтобы не вытеснить из него часто читаемые счета
+    fn accounts(&self) -> Result<impl Iterator<Item = Result<S::Account, BankError>>, BankError> {
+        self.inner.accounts()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bank::{Account, Bank, InMemoryOpsStorage, InMemoryState, NonZeroMoney},
+        fee::FlatFee,
+        sqlite::SqliteState,
+    };
+
+    mod cached {
+        use super::*;
+        use crate::bank::test::bank_test_suite;
+
+        //маленький кэш, чтобы в тестах банка счета вытеснялись
+        bank_test_suite!(Bank::new(
+            InMemoryOpsStorage::default(),
+            CachedState::with_capacity(SqliteState::default(), 2)
+        ));
+    }
+
+    #[test]
+    fn cached_state_should_count_hits_and_evict_least_recently_used() {
+        let mut bank = Bank::new(
+            InMemoryOpsStorage::default(),
+            CachedState::with_capacity(InMemoryState::<Account>::default(), 2),
+        );
+        for account_id in [128, 129, 130] {
+            let _ = bank.create_account(account_id);
+        }
+        let state = bank.state();
+        //созданные счета записаны в кэш, и банк вернул их из кэша; 128 вытеснен первым
+        assert_eq!(
+            state.stats().unwrap(),
+            CacheStats {
+                hits: 3,
+                misses: 0,
+                evictions: 1,
+            }
+        );
+        assert_eq!(state.len().unwrap(), 2);
+
+        assert_eq!(bank.get_balance(&129).unwrap().account_id, 129);
+        assert_eq!(bank.get_balance(&128).unwrap().account_id, 128);
+        //129 читали позже 130, поэтому вытеснен 130
+        assert_eq!(bank.get_balance(&129).unwrap().account_id, 129);
+        assert_eq!(bank.get_balance(&130).unwrap().account_id, 130);
+        assert_eq!(
+            bank.state().stats().unwrap(),
+            CacheStats {
+                hits: 5,
+                misses: 2,
+                evictions: 3,
+            }
+        );
+        assert!(bank.get_balance(&131).is_err());
+        assert_eq!(bank.state().stats().unwrap().misses, 3);
+    }
+
+    #[test]
+    fn cached_state_should_invalidate_every_account_of_transaction() {
+        let mut bank = Bank::new(
+            InMemoryOpsStorage::default(),
+            CachedState::new(InMemoryState::<Account>::default()),
+        )
+        .with_fees(0, FlatFee(1))
+        .unwrap();
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        //все счета в кэше
+        for account_id in [0, 128, 129] {
+            let _ = bank.get_balance(&account_id).unwrap();
+        }
+
+        //транзакция перевода возвращает только отправителя,
+        //получатель (его читает move_money) и счёт комиссии читаются заново
+        let misses = bank.state().stats().unwrap().misses;
+        let (from, to) = bank
+            .move_money(128, 129, NonZeroMoney::new(20).unwrap())
+            .unwrap();
+        assert_eq!((from.balance, to.balance), (20, 20));
+        assert_eq!(bank.get_balance(&129).unwrap().balance, 20);
+        assert_eq!(bank.get_balance(&0).unwrap().balance, 2);
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 20);
+        assert_eq!(bank.state().stats().unwrap().misses, misses + 2);
+
+        //отклонённая транзакция не меняет счета
+        assert!(bank.withdraw(129, NonZeroMoney::new(100).unwrap()).is_err());
+        assert_eq!(bank.get_balance(&129).unwrap().balance, 20);
+
+        let accounts: Vec<Account> = [0, 128, 129]
+            .map(|account_id| bank.state().inner().get_balance(&account_id).unwrap())
+            .to_vec();
+        let cached: Vec<Account> = [0, 128, 129]
+            .map(|account_id| bank.get_balance(&account_id).unwrap())
+            .to_vec();
+        assert_eq!(accounts, cached);
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    bank::{BankError, OpId, Operation, OpsStorage},
+    clock::Timestamp,
+};
+
+//звено цепочки: sha256 от хэша предыдущей операции и закодированной операции вместе с OpId и временем.
+//Изменение любой сохранённой операции меняет хэши всех следующих, поэтому хэша последней операции
+//(головы) достаточно, чтобы убедиться, что история не переписана
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChainHash(pub [u8; 32]);
+
+impl ChainHash {
+    //хэш "перед" первой операцией
+    pub const GENESIS: ChainHash = ChainHash([0; 32]);
+
+    pub fn link<Id: Serialize>(
+        &self,
+        op_id: OpId,
+        at: Timestamp,
+        op: &Operation<Id>,
+    ) -> Result<ChainHash, BankError> {
+        let encoded = bincode::serialize(&(op_id, at, op)).map_err(|err| {
+            BankError::CoreError(format!(
+                "Can't encode operation[{op_id}] for hashing: {err}"
+            ))
+        })?;
+        let mut hasher = Sha256::new();
+        hasher.update(self.0);
+        hasher.update(encoded);
+        Ok(ChainHash(hasher.finalize().into()))
+    }
+}
+
+impl Display for ChainHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+//результат проверки цепочки: сколько операций проверено, голова и первая изменённая операция
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainReport {
+    pub ops: usize,
+    pub head: ChainHash,
+    pub tampered: Option<OpId>,
+}
+
+impl ChainReport {
+    pub fn is_intact(&self) -> bool {
+        self.tampered.is_none()
+    }
+}
+
+//хэши операций пересчитываются по истории и сравниваются с сохранёнными.
+//Если операцию изменили (или удалили), не совпадёт её хэш (или хэш следующей за ней).
+//Переписанную целиком цепочку выдаёт только голова, отличная от опубликованной ранее.
+//После compact предыдущий хэш первой оставшейся операции неизвестен, она принимается как есть
+pub fn verify<T: OpsStorage>(storage: &T) -> Result<ChainReport, BankError> {
+    let first = OpId::MIN.checked_add(1);
+    let mut prev: Option<ChainHash> = None;
+    let mut ops = 0;
+    let mut tampered = None;
+    for entry in storage.get_hashed_history()? {
+        let ((op_id, at, op), stored) = entry?;
+        if ops == 0 && Some(op_id) == first {
+            prev = Some(ChainHash::GENESIS);
+        }
+        ops += 1;
+        if let Some(prev) = prev {
+            if tampered.is_none() && prev.link(op_id, at, &op)? != stored {
+                tampered = Some(op_id);
+            }
+        }
+        prev = Some(stored);
+    }
+    let head = storage.head_hash()?;
+    if tampered.is_none() && prev.is_some_and(|last| last != head) {
+        tampered = Some(storage.last_op_id()?);
+    }
+    Ok(ChainReport {
+        ops,
+        head,
+        tampered,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bank::{History, InMemoryOpsStorage, NonZeroMoney};
+
+    fn history() -> Vec<(OpId, Timestamp, Operation, ChainHash)> {
+        let mut storage = InMemoryOpsStorage::default();
+        let transactions = [
+            (1_000, vec![Operation::Create(128), Operation::Create(129)]),
+            (
+                1_010,
+                vec![Operation::Deposit(128, NonZeroMoney::new(42).unwrap())],
+            ),
+            (
+                1_020,
+                vec![Operation::Transfer {
+                    from: 128,
+                    to: 129,
+                    amount: NonZeroMoney::new(12).unwrap(),
+                }],
+            ),
+        ];
+        for (at, ops) in transactions {
+            storage.transact(at, &ops).unwrap();
+        }
+        let history: History = storage
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        history
+            .into_iter()
+            .map(|(op_id, at, op)| {
+                let hash = storage.get_hash(op_id).unwrap();
+                (op_id, at, op, hash)
+            })
+            .collect()
+    }
+
+    fn storage(history: Vec<(OpId, Timestamp, Operation, ChainHash)>) -> InMemoryOpsStorage {
+        let mut storage = InMemoryOpsStorage::default();
+        for (op_id, at, op, hash) in history {
+            storage.restore(op_id, at, op, hash).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn chain_should_link_operations() {
+        let history = history();
+        let mut prev = ChainHash::GENESIS;
+        for (op_id, at, op, hash) in &history {
+            assert_eq!(prev.link(*op_id, *at, op).unwrap(), *hash);
+            prev = *hash;
+        }
+        assert_ne!(prev, ChainHash::GENESIS);
+        assert_eq!(prev.to_string().len(), 64);
+
+        let storage = storage(history.clone());
+        assert_eq!(storage.head_hash().unwrap(), prev);
+        assert_eq!(
+            verify(&storage).unwrap(),
+            ChainReport {
+                ops: history.len(),
+                head: prev,
+                tampered: None,
+            }
+        );
+        assert_eq!(
+            verify(&InMemoryOpsStorage::<u128>::default()).unwrap(),
+            ChainReport {
+                ops: 0,
+                head: ChainHash::GENESIS,
+                tampered: None,
+            }
+        );
+    }
+
+    #[test]
+    fn chain_should_pinpoint_the_first_tampered_operation() {
+        let history = history();
+
+        //сумма пополнения изменена, хэши сохранены исходные
+        let mut edited = history.clone();
+        edited[2].2 = Operation::Deposit(128, NonZeroMoney::new(4_200).unwrap());
+        let report = verify(&storage(edited)).unwrap();
+        assert_eq!(report.tampered, Some(history[2].0));
+        assert!(!report.is_intact());
+
+        //изменено время операции
+        let mut edited = history.clone();
+        edited[3].1 += 1;
+        assert_eq!(
+            verify(&storage(edited)).unwrap().tampered,
+            Some(history[3].0)
+        );
+
+        //операция удалена: не сходится звено следующей
+        let mut edited = history.clone();
+        edited.remove(1);
+        assert_eq!(
+            verify(&storage(edited)).unwrap().tampered,
+            Some(history[2].0)
+        );
+
+        //хэш изменённой операции пересчитан, но следующая ссылается на старый
+        let mut edited = history.clone();
+        edited[1].2 = Operation::Create(130);
+        edited[1].3 = edited[0]
+            .3
+            .link(edited[1].0, edited[1].1, &edited[1].2)
+            .unwrap();
+        assert_eq!(
+            verify(&storage(edited)).unwrap().tampered,
+            Some(history[2].0)
+        );
+    }
+}
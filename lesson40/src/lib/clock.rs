@@ -0,0 +1,126 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+//время операции: миллисекунды с начала эпохи Unix
+pub type Timestamp = u64;
+
+///Источник времени, которым помечаются сохраняемые операции
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Timestamp;
+}
+
+//системные часы
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| Timestamp::try_from(elapsed.as_millis()).unwrap_or(Timestamp::MAX))
+            .unwrap_or(0)
+    }
+}
+
+//часы, которые идут только вручную, например, в тестах
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now: Timestamp) -> ManualClock {
+        ManualClock(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: Timestamp) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+//часы, время которых не повторяется и не идёт назад: каждое следующее больше предыдущего,
+//даже если inner показывает то же время или его перевели назад.
+//Общие для нескольких банков (см. ShardedBank) упорядочивают их операции во времени
+#[derive(Debug)]
+pub struct MonotonicClock<C> {
+    inner: C,
+    last: AtomicU64,
+}
+
+impl<C: Clock> MonotonicClock<C> {
+    pub fn new(inner: C) -> MonotonicClock<C> {
+        MonotonicClock {
+            inner,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    //следующее время будет больше after, например, времени уже сохранённых операций
+    pub fn advance_past(&self, after: Timestamp) {
+        self.last.fetch_max(after, Ordering::SeqCst);
+    }
+}
+
+impl<C: Clock> Clock for MonotonicClock<C> {
+    fn now(&self) -> Timestamp {
+        let now = self.inner.now();
+        let next = |last: Timestamp| now.max(last.saturating_add(1));
+        let last = self
+            .last
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .unwrap_or_else(|last| last);
+        next(last)
+    }
+}
+
+//общие часы: банк владеет одной ссылкой, тест переводит стрелки через другую
+impl<C: Clock> Clock for Arc<C> {
+    fn now(&self) -> Timestamp {
+        self.as_ref().now()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock_should_move_only_by_hand() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let shared: Box<dyn Clock> = Box::new(Arc::clone(&clock));
+        assert_eq!(shared.now(), 1_000);
+        clock.advance(500);
+        assert_eq!(shared.now(), 1_500);
+        clock.set(42);
+        assert_eq!(shared.now(), 42);
+        assert!(SystemClock.now() > 1_600_000_000_000);
+    }
+
+    #[test]
+    fn monotonic_clock_should_never_repeat_time() {
+        let manual = Arc::new(ManualClock::new(1_000));
+        let clock = MonotonicClock::new(Arc::clone(&manual));
+        assert_eq!(clock.now(), 1_000);
+        assert_eq!(clock.now(), 1_001);
+        manual.set(500);
+        assert_eq!(clock.now(), 1_002);
+        manual.set(2_000);
+        assert_eq!(clock.now(), 2_000);
+        clock.advance_past(3_000);
+        assert_eq!(clock.now(), 3_001);
+    }
+}
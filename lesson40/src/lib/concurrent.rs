@@ -0,0 +1,879 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::{Arc, Condvar, LockResult, Mutex, MutexGuard, RwLock},
+};
+
+use crate::{
+    audit::{self, AuditReport},
+    bank::{
+        self, Account, BankAccount, BankError, History, InMemoryState, Money, NonZeroMoney, OpId,
+        Operation, OpsStorage, Overlay, SignedMoney, State,
+    },
+    chain::{self, ChainHash, ChainReport},
+    clock::Clock,
+    currency::{Currency, ExchangeRates},
+    fee::{FeeKind, Fees},
+    history::{self, HistoryPage, HistoryQuery},
+    idempotency::{self, Receipt, Receipts, RequestFingerprint, RequestKey},
+    statement::{self, Statement},
+};
+
+thread_local! {
+    //ключ и отпечаток запроса, которыми помечается следующая транзакция этого потока (см. idempotent)
+    static REQUEST_KEY: Cell<Option<(RequestKey, RequestFingerprint)>> = const { Cell::new(None) };
+}
+
+//банк для работы из нескольких потоков через &self.
+//У каждого счёта своя блокировка, транзакция блокирует свои счета по возрастанию идентификаторов,
+//поэтому транзакции по разным счетам выполняются параллельно и не ждут друг друга по кругу.
+//Специальный счёт комиссии транзакции не блокируют: комиссия зачисляется на него позже, см. settle.
+//Порядок блокировок: запросы с ключами, комиссии (fee_gate), таблица счетов, счета,
+//очередь записи, хранилище, комиссии к зачислению, квитанции
+#[derive(Debug)]
+pub struct ConcurrentBank<T, A: BankAccount = Account> {
+    storage: Mutex<T>,
+    accounts: RwLock<HashMap<A::Id, Arc<Mutex<A>>>>,
+    receipts: Mutex<Receipts<A>>,
+    //запросы с ключами выполняются по одному, иначе повтор мог бы выполниться одновременно с оригиналом
+    keyed: Mutex<()>,
+    //транзакции со специальным счётом комиссии (закрытие, заморозка, снятие с него) выполняются
+    //без транзакций, которые начисляют на него комиссию, остальные транзакции друг другу не мешают
+    fee_gate: RwLock<()>,
+    //комиссии, уже сохранённые в истории, но ещё не зачисленные на специальный счёт
+    fees_due: Mutex<Vec<NonZeroMoney>>,
+    commits: Mutex<CommitQueue<A::Id>>,
+    committed: Condvar,
+    fees: Option<Fees<A::Id>>,
+    rates: Option<Box<dyn ExchangeRates>>,
+    clock: Box<dyn Clock>,
+}
+
+//транзакции, ожидающие записи в хранилище. Их записывает одним transact (и одним fsync)
+//первый освободившийся поток, остальные ждут результата, см. commit
+#[derive(Debug)]
+struct CommitQueue<Id> {
+    pending: Vec<(u64, Commit<Id>)>,
+    done: HashMap<u64, Result<Vec<OpId>, BankError>>,
+    next: u64,
+    writing: bool,
+}
+
+impl<Id> Default for CommitQueue<Id> {
+    fn default() -> Self {
+        CommitQueue {
+            pending: Vec::new(),
+            done: HashMap::new(),
+            next: 0,
+            writing: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Commit<Id> {
+    ops: Vec<Operation<Id>>,
+    //комиссии на специальный счёт, который транзакция не блокировала
+    fees: Vec<NonZeroMoney>,
+}
+
+//блокировка, отравленная паникой другого потока, означает, что счета могли остаться недописанными
+fn locked<G>(lock: LockResult<G>) -> Result<G, BankError> {
+    lock.map_err(|_| BankError::CoreError("Bank lock is poisoned by a panic".to_owned()))
+}
+
+impl<T, A> ConcurrentBank<T, A>
+where
+    T: OpsStorage<Id = A::Id>,
+    A: BankAccount,
+{
+    pub(crate) fn from_parts(
+        storage: T,
+        accounts: impl Iterator<Item = A>,
+        receipts: Receipts<A>,
+        fees: Option<Fees<A::Id>>,
+        rates: Option<Box<dyn ExchangeRates>>,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        ConcurrentBank {
+            storage: Mutex::new(storage),
+            accounts: RwLock::new(
+                accounts
+                    .map(|account| (account.account_id().clone(), Arc::new(Mutex::new(account))))
+                    .collect(),
+            ),
+            receipts: Mutex::new(receipts),
+            keyed: Mutex::new(()),
+            fee_gate: RwLock::new(()),
+            fees_due: Mutex::new(Vec::new()),
+            commits: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+            fees,
+            rates,
+            clock,
+        }
+    }
+
+    fn account(&self, account_id: &A::Id) -> Result<Arc<Mutex<A>>, BankError> {
+        locked(self.accounts.read())?
+            .get(account_id)
+            .cloned()
+            .ok_or_else(|| {
+                BankError::BadRequest(format!("Bank doesnt contain account_id[{}]", account_id))
+            })
+    }
+
+    //зачисляет на специальный счёт накопленные комиссии. Вызывается под блокировкой этого счёта,
+    //поэтому его видят с комиссиями всех транзакций, сохранённых до блокировки
+    fn settle(&self, fee_account: &mut A) -> Result<(), BankError> {
+        let mut due = locked(self.fees_due.lock())?;
+        while let Some(amount) = due.pop() {
+            if let Err(err) = fee_account.deposit(amount) {
+                due.push(amount);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    //транзакция над счетами account_ids: счета блокируются, build строит операции по их копиям,
+    //операции проверяются, сохраняются и только потом применяются к счетам.
+    //Блокировки счетов держатся и на время записи: проверка и изменение должны видеть одно
+    //состояние, порядок в истории должен совпадать с порядком зависимых транзакций,
+    //а изменение нельзя показывать раньше, чем оно сохранено. Запись при этом не выстраивает
+    //транзакции по разным счетам в очередь: они сохраняются вместе, см. commit.
+    //Возвращает счета после транзакции в порядке account_ids
+    fn execute(
+        &self,
+        account_ids: &[A::Id],
+        build: impl FnOnce(&InMemoryState<A>) -> Result<Vec<Operation<A::Id>>, BankError>,
+    ) -> Result<Vec<A>, BankError> {
+        let mut order: Vec<&A::Id> = account_ids.iter().collect();
+        order.sort();
+        order.dedup();
+        let fee_account = self.fees.as_ref().map(|fees| &fees.account_id);
+        let party = fee_account.is_some_and(|fee_account| order.contains(&fee_account));
+        let _exclusive = party.then(|| locked(self.fee_gate.write())).transpose()?;
+        let _shared = (!party).then(|| locked(self.fee_gate.read())).transpose()?;
+        //специальный счёт нужен транзакции только для проверки зачисления комиссии: статус
+        //под fee_gate не изменится, а баланс от зачислений других транзакций может только вырасти
+        let fee_cell = match fee_account {
+            Some(fee_account) if !party => Some(self.account(fee_account)?),
+            _ => None,
+        };
+        let fee_copy = match &fee_cell {
+            Some(cell) => {
+                let mut fee_account = locked(cell.lock())?;
+                self.settle(&mut fee_account)?;
+                Some(fee_account.clone())
+            }
+            None => None,
+        };
+        let cells = order
+            .iter()
+            .map(|account_id| self.account(account_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut guards = cells
+            .iter()
+            .map(|cell| locked(cell.lock()))
+            .collect::<Result<Vec<MutexGuard<A>>, _>>()?;
+        if let Some(guard) = guards
+            .iter_mut()
+            .find(|guard| Some(guard.account_id()) == fee_account)
+        {
+            self.settle(guard)?;
+        }
+
+        let view: InMemoryState<A> = guards
+            .iter()
+            .map(|guard| (**guard).clone())
+            .chain(fee_copy)
+            .collect();
+        let ops = build(&view)?;
+        let fees = match fee_account {
+            Some(fee_account) if !party => ops
+                .iter()
+                .filter_map(|op| match op {
+                    Operation::Fee { to, amount, .. } if to == fee_account => Some(*amount),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let (_, overlay) = self.persist(&view, ops, fees)?;
+        for guard in guards.iter_mut() {
+            if let Some(account) = overlay.get(guard.account_id()) {
+                **guard = account.clone();
+            }
+        }
+        //очередь комиссий не должна расти, пока специальный счёт никто не читает
+        if let Some(Ok(mut fee_account)) = fee_cell.as_ref().map(|cell| cell.try_lock()) {
+            self.settle(&mut fee_account)?;
+        }
+        Ok(account_ids
+            .iter()
+            .filter_map(|account_id| {
+                let index = order.binary_search(&account_id).ok()?;
+                Some((*guards[index]).clone())
+            })
+            .collect())
+    }
+
+    //операции проверяются на view, сохраняются в хранилище, квитанция запоминается.
+    //fees - комиссии, которые нужно зачислить на незаблокированный специальный счёт
+    fn persist(
+        &self,
+        view: &InMemoryState<A>,
+        mut ops: Vec<Operation<A::Id>>,
+        fees: Vec<NonZeroMoney>,
+    ) -> Result<(Vec<A::Id>, Overlay<A>), BankError> {
+        if let Some((key, fingerprint)) = REQUEST_KEY.take() {
+            ops.extend(idempotency::receipt(key, fingerprint, &ops));
+        }
+        let (account_ids, overlay) = view.prepare(ops.iter())?;
+        let mut receipts = Vec::new();
+        for op in &ops {
+            if let Operation::Receipt {
+                key,
+                fingerprint,
+                account_id,
+                related,
+                fee,
+            } = op
+            {
+                let accounts = std::iter::once(account_id)
+                    .chain(related)
+                    .map(|account_id| {
+                        overlay.get(account_id).cloned().ok_or_else(|| {
+                            BankError::CoreError(format!(
+                                "Receipt account[{account_id}] is not in the transaction"
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                receipts.push((
+                    *key,
+                    *fingerprint,
+                    Receipt {
+                        accounts,
+                        fee: *fee,
+                    },
+                ));
+            }
+        }
+        self.commit(Commit { ops, fees })?;
+        if !receipts.is_empty() {
+            let mut stored = locked(self.receipts.lock())?;
+            for (key, fingerprint, receipt) in receipts {
+                stored.insert(key, fingerprint, receipt);
+            }
+        }
+        Ok((account_ids, overlay))
+    }
+
+    //групповая запись: транзакция встаёт в очередь, и если никто не пишет, записывает всю очередь
+    //сама, иначе ждёт, пока её запишет другой поток. Пока идёт одна запись, копится следующая
+    fn commit(&self, commit: Commit<A::Id>) -> Result<Vec<OpId>, BankError> {
+        let mut queue = locked(self.commits.lock())?;
+        let ticket = queue.next;
+        queue.next += 1;
+        queue.pending.push((ticket, commit));
+        loop {
+            if let Some(ret) = queue.done.remove(&ticket) {
+                return ret;
+            }
+            if queue.writing {
+                queue = locked(self.committed.wait(queue))?;
+                continue;
+            }
+            queue.writing = true;
+            let batch = std::mem::take(&mut queue.pending);
+            drop(queue);
+            let done = self.write(batch);
+            queue = locked(self.commits.lock())?;
+            queue.writing = false;
+            queue.done.extend(done);
+            self.committed.notify_all();
+        }
+    }
+
+    //записывает очередь одной транзакцией хранилища; ошибка записи - ошибка каждой транзакции
+    fn write(&self, batch: Vec<(u64, Commit<A::Id>)>) -> Vec<(u64, Result<Vec<OpId>, BankError>)> {
+        let ops: Vec<_> = batch
+            .iter()
+            .flat_map(|(_, commit)| commit.ops.iter().cloned())
+            .collect();
+        let written = locked(self.storage.lock()).and_then(|mut storage| {
+            let op_ids = storage.transact(self.clock.now(), &ops)?;
+            //комиссии ставятся в очередь под блокировкой хранилища, до того как транзакции
+            //отпустят свои счета, поэтому сверка и settle не пропустят сохранённую комиссию
+            locked(self.fees_due.lock())?.extend(
+                batch
+                    .iter()
+                    .flat_map(|(_, commit)| commit.fees.iter().copied()),
+            );
+            Ok(op_ids)
+        });
+        match written {
+            Ok(op_ids) => {
+                let mut op_ids = op_ids.into_iter();
+                batch
+                    .into_iter()
+                    .map(|(ticket, commit)| {
+                        (ticket, Ok(op_ids.by_ref().take(commit.ops.len()).collect()))
+                    })
+                    .collect()
+            }
+            Err(err) => batch
+                .into_iter()
+                .map(|(ticket, _)| (ticket, Err(err.clone())))
+                .collect(),
+        }
+    }
+
+    //счёт создаётся под блокировкой таблицы счетов на запись, поэтому его не создадут дважды
+    fn create(&self, op: Operation<A::Id>) -> Result<A, BankError> {
+        let account_id = op.account_id().clone();
+        let mut accounts = locked(self.accounts.write())?;
+        if accounts.contains_key(&account_id) {
+            return Err(BankError::BadRequest(format!(
+                "Bank already contains account_id[{}]",
+                account_id
+            )));
+        }
+        let (_, overlay) = self.persist(&InMemoryState::default(), vec![op], Vec::new())?;
+        let account = overlay
+            .get(&account_id)
+            .cloned()
+            .ok_or_else(|| BankError::CoreError(format!("Account[{account_id}] is not created")))?;
+        accounts.insert(account_id, Arc::new(Mutex::new(account.clone())));
+        Ok(account)
+    }
+
+    fn execute_one(
+        &self,
+        account_ids: Vec<A::Id>,
+        build: impl FnOnce(&InMemoryState<A>) -> Result<Vec<Operation<A::Id>>, BankError>,
+    ) -> Result<A, BankError> {
+        self.execute(&account_ids, build)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                BankError::CoreError("a transaction should return at least one account".to_owned())
+            })
+    }
+
+    fn charge(
+        &self,
+        view: &InMemoryState<A>,
+        payer: &A::Id,
+        kind: FeeKind,
+        money: NonZeroMoney,
+    ) -> Option<Operation<A::Id>> {
+        self.fees.as_ref()?.charge(
+            payer,
+            |account_id| currency(view, account_id).ok(),
+            kind,
+            money,
+        )
+    }
+
+    //комиссия, которую заплатит счёт payer за операцию, см. Bank::fee
+    pub fn fee(&self, payer: &A::Id, kind: FeeKind, money: NonZeroMoney) -> Money {
+        self.fees.as_ref().map_or(0, |fees| {
+            fees.fee(
+                payer,
+                |account_id| self.get_balance(account_id).ok().map(|a| a.currency()),
+                kind,
+                money,
+            )
+        })
+    }
+
+    //выполняет изменяющий запрос run не больше одного раза для ключа key, см. Bank::idempotent
+    pub fn idempotent(
+        &self,
+        key: RequestKey,
+        fingerprint: RequestFingerprint,
+        run: impl FnOnce(&Self) -> Result<(), BankError>,
+    ) -> Result<Receipt<A>, BankError> {
+        let _keyed = locked(self.keyed.lock())?;
+        let done = locked(self.receipts.lock())?
+            .find(&key, fingerprint)?
+            .is_some();
+        if !done {
+            REQUEST_KEY.set(Some((key, fingerprint)));
+            let ret = run(self);
+            //запрос мог завершиться ошибкой до выполнения транзакции
+            REQUEST_KEY.set(None);
+            ret?;
+        }
+        self.receipt_of(&key).ok_or_else(|| {
+            BankError::BadRequest(format!("Request with key[{key}] doesn't change the bank"))
+        })
+    }
+
+    pub fn receipt_of(&self, key: &RequestKey) -> Option<Receipt<A>> {
+        locked(self.receipts.lock()).ok()?.get(key).cloned()
+    }
+
+    pub fn create_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.create(Operation::Create(account_id))
+    }
+
+    pub fn create_account_in(&self, account_id: A::Id, currency: Currency) -> Result<A, BankError> {
+        self.create(Operation::CreateIn(account_id, currency))
+    }
+
+    //копия счёта: блокировка держится только на время копирования
+    pub fn get_balance(&self, account_id: &A::Id) -> Result<A, BankError> {
+        let account = self.account(account_id)?;
+        let mut account = locked(account.lock())?;
+        if self.fees.as_ref().map(|fees| &fees.account_id) == Some(account_id) {
+            self.settle(&mut account)?;
+        }
+        Ok(account.clone())
+    }
+
+    pub fn deposit(&self, account_id: &A::Id, money: NonZeroMoney) -> Result<A, BankError> {
+        self.execute_one(vec![account_id.clone()], |view| {
+            Ok(
+                std::iter::once(Operation::Deposit(account_id.clone(), money))
+                    .chain(self.charge(view, account_id, FeeKind::Deposit, money))
+                    .collect(),
+            )
+        })
+    }
+
+    pub fn withdraw(&self, account_id: A::Id, money: NonZeroMoney) -> Result<A, BankError> {
+        self.execute_one(vec![account_id.clone()], |view| {
+            let fee = self.charge(view, &account_id, FeeKind::Withdraw, money);
+            Ok(std::iter::once(Operation::Withdraw(account_id, money))
+                .chain(fee)
+                .collect())
+        })
+    }
+
+    //перемещение денег со счёта на счёт, см. Bank::move_money
+    pub fn move_money(
+        &self,
+        from: A::Id,
+        to: A::Id,
+        money: NonZeroMoney,
+    ) -> Result<(A, A), BankError> {
+        if from == to {
+            return Err(BankError::Prohibited(format!(
+                "Sending funds to yourself[{to}] is prohibited"
+            )));
+        }
+        let mut accounts = self
+            .execute(&[from.clone(), to.clone()], |view| {
+                let currencies = (currency(view, &from)?, currency(view, &to)?);
+                let fee = self.charge(view, &from, FeeKind::Move, money);
+                Ok(bank::transfer(
+                    from.clone(),
+                    to.clone(),
+                    money,
+                    currencies,
+                    self.rates.as_deref(),
+                )?
+                .into_iter()
+                .chain(fee)
+                .collect())
+            })?
+            .into_iter();
+        match (accounts.next(), accounts.next()) {
+            (Some(from), Some(to)) => Ok((from, to)),
+            _ => Err(BankError::CoreError(
+                "a transfer should return both accounts".to_owned(),
+            )),
+        }
+    }
+
+    pub fn set_credit_limit(&self, account_id: A::Id, limit: Money) -> Result<A, BankError> {
+        self.execute_one(vec![account_id.clone()], |_| {
+            Ok(vec![Operation::SetCreditLimit(account_id, limit)])
+        })
+    }
+
+    pub fn freeze_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.execute_one(vec![account_id.clone()], |_| {
+            Ok(vec![Operation::Freeze(account_id)])
+        })
+    }
+
+    pub fn unfreeze_account(&self, account_id: A::Id) -> Result<A, BankError> {
+        self.execute_one(vec![account_id.clone()], |_| {
+            Ok(vec![Operation::Unfreeze(account_id)])
+        })
+    }
+
+    //закрытие счёта, остаток переводится на счёт payout без комиссии, см. Bank::close_account
+    pub fn close_account(&self, account_id: A::Id, payout: Option<A::Id>) -> Result<A, BankError> {
+        let account_ids = std::iter::once(account_id.clone())
+            .chain(payout.clone())
+            .collect();
+        self.execute_one(account_ids, |view| {
+            let mut ops = Vec::new();
+            if let Some(funds) = NonZeroMoney::new(view.get_balance(&account_id)?.funds()) {
+                let to = payout.ok_or_else(|| {
+                    BankError::BadRequest(format!(
+                        "Account[{account_id}] has funds[{funds}], a payout account is required"
+                    ))
+                })?;
+                if to == account_id {
+                    return Err(BankError::Prohibited(format!(
+                        "Account[{to}] can't be a payout for itself"
+                    )));
+                }
+                let currencies = (currency(view, &account_id)?, currency(view, &to)?);
+                ops.extend(bank::transfer(
+                    account_id.clone(),
+                    to,
+                    funds,
+                    currencies,
+                    self.rates.as_deref(),
+                )?);
+            }
+            ops.push(Operation::Close(account_id));
+            Ok(ops)
+        })
+    }
+
+    pub fn get_balance_at(&self, account_id: &A::Id, op_id: OpId) -> Result<A, BankError> {
+        bank::balance_at(&*locked(self.storage.lock())?, account_id, op_id)
+    }
+
+    //история операций по счёту; читается целиком, потому что хранилище нельзя держать заблокированным
+    pub fn get_account_ops(&self, account_id: &A::Id) -> Result<History<A::Id>, BankError> {
+        let storage = locked(self.storage.lock())?;
+        let ops = storage.get_ops(account_id)?.collect();
+        ops
+    }
+
+    pub fn query(&self, query: &HistoryQuery<A::Id>) -> Result<HistoryPage<A::Id>, BankError> {
+        history::query(&*locked(self.storage.lock())?, query)
+    }
+
+    pub fn statement(
+        &self,
+        account_id: &A::Id,
+        from: OpId,
+        to: OpId,
+    ) -> Result<Statement<A>, BankError> {
+        statement::build(&*locked(self.storage.lock())?, account_id, from, to)
+    }
+
+    pub fn head_hash(&self) -> Result<ChainHash, BankError> {
+        locked(self.storage.lock())?.head_hash()
+    }
+
+    pub fn verify_history(&self) -> Result<ChainReport, BankError> {
+        chain::verify(&*locked(self.storage.lock())?)
+    }
+
+    //сверка останавливает все изменения: блокируются таблица, все счета и хранилище
+    pub fn audit(&self) -> Result<AuditReport<A>, BankError>
+    where
+        A::Balance: Into<SignedMoney>,
+    {
+        let accounts = locked(self.accounts.read())?;
+        let mut cells: Vec<_> = accounts.iter().collect();
+        cells.sort_by_key(|(account_id, _)| *account_id);
+        let mut guards = cells
+            .into_iter()
+            .map(|(_, cell)| locked(cell.lock()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(guard) = guards.iter_mut().find(|guard| {
+            Some(guard.account_id()) == self.fees.as_ref().map(|fees| &fees.account_id)
+        }) {
+            self.settle(guard)?;
+        }
+        let state: InMemoryState<A> = guards.iter().map(|guard| (**guard).clone()).collect();
+        let storage = locked(self.storage.lock())?;
+        let report = audit::audit(storage.get_history()?, &state);
+        report
+    }
+}
+
+fn currency<A: BankAccount>(
+    view: &InMemoryState<A>,
+    account_id: &A::Id,
+) -> Result<Currency, BankError> {
+    view.get_balance(account_id)
+        .map(|account| account.currency())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        bank::{AccountId, AccountStatus, Bank, Entry, InMemoryOpsStorage},
+        clock::Timestamp,
+        fee::FlatFee,
+    };
+
+    type TestBank = ConcurrentBank<InMemoryOpsStorage>;
+
+    fn bank(accounts: AccountId, funds: Money) -> TestBank {
+        let bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default())
+            .with_fees(0, FlatFee(1))
+            .unwrap()
+            .into_concurrent();
+        for account_id in 1..=accounts {
+            bank.create_account(account_id).unwrap();
+            bank.deposit(&account_id, NonZeroMoney::new(funds).unwrap())
+                .unwrap();
+        }
+        bank
+    }
+
+    //простой генератор, чтобы не тянуть зависимость ради тестов
+    fn next(seed: &mut u64) -> u64 {
+        *seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        *seed >> 33
+    }
+
+    #[test]
+    fn concurrent_bank_should_work_like_bank() {
+        let bank = bank(2, 100);
+        assert_eq!(bank.get_balance(&1).unwrap().balance, 99);
+        assert_eq!(bank.get_balance(&0).unwrap().balance, 2);
+
+        let (from, to) = bank
+            .move_money(1, 2, NonZeroMoney::new(10).unwrap())
+            .unwrap();
+        assert_eq!((from.balance, to.balance), (88, 109));
+        assert!(bank.move_money(1, 1, NonZeroMoney::MIN).is_err());
+        assert!(bank.create_account(1).is_err());
+        assert!(bank.deposit(&3, NonZeroMoney::MIN).is_err());
+
+        let account = bank.close_account(1, Some(2)).unwrap();
+        assert_eq!(account.status, AccountStatus::Closed);
+        assert_eq!(bank.get_balance(&2).unwrap().balance, 197);
+
+        let receipt = bank
+            .idempotent(42, 1, |bank| {
+                bank.withdraw(2, NonZeroMoney::new(7).unwrap()).map(drop)
+            })
+            .unwrap();
+        let repeated = bank
+            .idempotent(42, 1, |bank| {
+                bank.withdraw(2, NonZeroMoney::new(7).unwrap()).map(drop)
+            })
+            .unwrap();
+        assert_eq!(receipt, repeated);
+        assert_eq!(receipt.fee, 1);
+        assert!(matches!(
+            bank.idempotent(42, 2, |bank| bank.deposit(&2, NonZeroMoney::MIN).map(drop)),
+            Err(BankError::BadRequest(_))
+        ));
+        assert_eq!(bank.get_balance(&2).unwrap().balance, 189);
+
+        assert!(bank.audit().unwrap().is_consistent());
+        assert!(bank.verify_history().unwrap().is_intact());
+        let ops = bank.get_account_ops(&2).unwrap();
+        //перед снятием с комиссией и квитанцией - перевод остатка закрытого счёта
+        let (op_id, _, _) = ops[ops.len() - 4];
+        assert_eq!(bank.get_balance_at(&2, op_id).unwrap().balance, 197);
+    }
+
+    //переводы в обе стороны между немногими счетами: при неупорядоченных блокировках тест
+    //зависал бы, а потерянное обновление нарушило бы сверку
+    #[test]
+    fn concurrent_bank_should_keep_money_under_contention() {
+        const ACCOUNTS: AccountId = 6;
+        const THREADS: u64 = 8;
+        const TRANSFERS: usize = 500;
+        let bank = Arc::new(bank(ACCOUNTS, 1_000));
+        let total = || {
+            (0..=ACCOUNTS)
+                .map(|id| bank.get_balance(&id).unwrap().balance)
+                .sum::<i128>()
+        };
+        let before = total();
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let bank = Arc::clone(&bank);
+                thread::spawn(move || {
+                    let mut seed = thread;
+                    for _ in 0..TRANSFERS {
+                        let from = (next(&mut seed) % ACCOUNTS as u64) as AccountId + 1;
+                        let to = (next(&mut seed) % ACCOUNTS as u64) as AccountId + 1;
+                        let money = NonZeroMoney::new(next(&mut seed) % 50 + 1).unwrap();
+                        //недостаточно средств или перевод самому себе - ожидаемые отказы
+                        let _ = bank.move_money(from, to, money);
+                        let _ = bank.get_balance(&from).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(total(), before);
+        let report = bank.audit().unwrap();
+        assert_eq!(report.issues, vec![]);
+        assert!(bank.verify_history().unwrap().is_intact());
+        for account_id in 0..=ACCOUNTS {
+            assert!(bank.get_balance(&account_id).unwrap().balance >= 0);
+        }
+    }
+
+    #[test]
+    fn concurrent_bank_should_execute_keyed_request_once() {
+        let bank = Arc::new(bank(1, 100));
+        let barrier = Arc::new(Barrier::new(4));
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (bank, barrier, receipts) = (
+                    Arc::clone(&bank),
+                    Arc::clone(&barrier),
+                    Arc::clone(&receipts),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    let receipt = bank
+                        .idempotent(7, 1, |bank| {
+                            bank.deposit(&1, NonZeroMoney::new(10).unwrap()).map(drop)
+                        })
+                        .unwrap();
+                    receipts.lock().unwrap().push(receipt);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let receipts = receipts.lock().unwrap();
+        assert!(receipts.iter().all(|receipt| *receipt == receipts[0]));
+        assert_eq!(bank.get_balance(&1).unwrap().balance, 108);
+    }
+
+    //хранилище, запись в которое занимает время, как fsync на диске; считает записи
+    #[derive(Debug, Default)]
+    struct SlowStorage {
+        ops: InMemoryOpsStorage,
+        transacts: Arc<AtomicUsize>,
+    }
+
+    const FSYNC: Duration = Duration::from_millis(2);
+
+    impl OpsStorage for SlowStorage {
+        type Id = AccountId;
+
+        fn transact(
+            &mut self,
+            at: Timestamp,
+            ops: &[Operation<AccountId>],
+        ) -> Result<Vec<OpId>, BankError> {
+            self.transacts.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(FSYNC);
+            self.ops.transact(at, ops)
+        }
+
+        fn get_ops(
+            &self,
+            account_id: &AccountId,
+        ) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_ops(account_id)
+        }
+
+        fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_history()
+        }
+
+        fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+            self.ops.get_hash(op_id)
+        }
+
+        fn head_hash(&self) -> Result<ChainHash, BankError> {
+            self.ops.head_hash()
+        }
+    }
+
+    const THREADS: usize = 16;
+    const DEPOSITS: usize = 25;
+
+    //банк с медленной записью и комиссией, счета 1..=THREADS, и счётчик записей в хранилище
+    fn slow_bank() -> (ConcurrentBank<SlowStorage>, Arc<AtomicUsize>) {
+        let storage = SlowStorage::default();
+        let transacts = storage.transacts.clone();
+        let bank = Bank::new(storage, InMemoryState::default())
+            .with_fees(0, FlatFee(1))
+            .unwrap()
+            .into_concurrent();
+        for account_id in 1..=THREADS as AccountId {
+            bank.create_account(account_id).unwrap();
+        }
+        (bank, transacts)
+    }
+
+    //threads потоков по DEPOSITS пополнений с комиссией, каждый поток - по своему счёту
+    fn deposit_in_threads(bank: &ConcurrentBank<SlowStorage>, threads: usize) -> Duration {
+        let start = Barrier::new(threads + 1);
+        thread::scope(|scope| {
+            for thread in 0..threads {
+                let start = &start;
+                scope.spawn(move || {
+                    start.wait();
+                    for _ in 0..DEPOSITS {
+                        bank.deposit(&(thread as AccountId + 1), NonZeroMoney::MIN)
+                            .unwrap();
+                    }
+                });
+            }
+            start.wait();
+            //scope возвращается, когда завершатся все потоки
+            Instant::now()
+        })
+        .elapsed()
+    }
+
+    //общий специальный счёт комиссии не выстраивает транзакции в очередь,
+    //а пока одна группа записывается, следующая копится и записывается одним transact
+    #[test]
+    fn concurrent_bank_should_group_commit_writes() {
+        let (bank, transacts) = slow_bank();
+        let before = transacts.load(Ordering::Relaxed);
+        deposit_in_threads(&bank, THREADS);
+
+        let writes = transacts.load(Ordering::Relaxed) - before;
+        let deposits = THREADS * DEPOSITS;
+        assert!(
+            writes * 4 <= deposits,
+            "writes[{writes}] for deposits[{deposits}]"
+        );
+        assert_eq!(bank.get_balance(&0).unwrap().balance, deposits as i128);
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    //ускорение по времени зависит от загрузки машины, поэтому запускается вручную:
+    //cargo test -p lesson40 -- --ignored concurrent_bank_writes_benchmark
+    //Ожидание записи не нагружает процессор, поэтому ускорение видно и на одном ядре
+    #[test]
+    #[ignore]
+    fn concurrent_bank_writes_benchmark() {
+        let (bank, _) = slow_bank();
+        let single = deposit_in_threads(&bank, 1);
+        let parallel = deposit_in_threads(&bank, THREADS);
+
+        //THREADS потоков сделали в THREADS раз больше пополнений
+        let speedup = single.as_secs_f64() * THREADS as f64 / parallel.as_secs_f64();
+        assert!(speedup > 4.0, "speedup[{speedup:.1}]");
+    }
+}
@@ -0,0 +1,323 @@
+use std::{fmt::Debug, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bank::BankError;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+//переменные окружения с ключом истории: сам ключ в hex или путь к файлу с ним
+pub const KEY_ENV: &str = "BANK_KEY";
+pub const KEY_FILE_ENV: &str = "BANK_KEY_FILE";
+//прежний ключ в hex: записи, зашифрованные им, перешифровываются текущим ключом при старте
+pub const PREVIOUS_KEY_ENV: &str = "BANK_PREVIOUS_KEY";
+
+//зашифрованная полезная нагрузка начинается с метки, незашифрованная (bincode) так начаться не может
+const SEALED_MAGIC: &[u8; 4] = b"BKE1";
+
+//ключ шифрования истории (ChaCha20-Poly1305).
+//Идентификатор ключа - начало его sha256, он сохраняется рядом с шифротекстом,
+//поэтому чужой ключ отличается от повреждённой записи
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; KEY_LEN]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    //случайный ключ, например, для ротации
+    pub fn generate() -> Result<EncryptionKey, BankError> {
+        let mut bytes = [0; KEY_LEN];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|err| BankError::CoreError(format!("Can't generate a key: {err}")))?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    //64 шестнадцатеричные цифры
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey, BankError> {
+        let hex = hex.trim();
+        let bad_key = || {
+            BankError::BadRequest(format!(
+                "Key should be {} hex digits, got {} characters",
+                KEY_LEN * 2,
+                hex.len()
+            ))
+        };
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(bad_key());
+        }
+        let mut bytes = [0; KEY_LEN];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| bad_key())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| bad_key())?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+
+    //файл с ключом: 32 байта или 64 шестнадцатеричные цифры
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey, BankError> {
+        let content = std::fs::read(path.as_ref()).map_err(|err| {
+            BankError::BadRequest(format!(
+                "Can't read key file[{}]: {err}",
+                path.as_ref().display()
+            ))
+        })?;
+        match <[u8; KEY_LEN]>::try_from(&content[..]) {
+            Ok(bytes) => Ok(EncryptionKey(bytes)),
+            Err(_) => Self::from_hex(&String::from_utf8_lossy(&content)),
+        }
+    }
+
+    pub fn from_env(var: &str) -> Result<EncryptionKey, BankError> {
+        let hex = std::env::var(var).map_err(|err| {
+            BankError::BadRequest(format!("Can't read key from variable[{var}]: {err}"))
+        })?;
+        Self::from_hex(&hex)
+    }
+
+    //ключ из файла KEY_FILE_ENV или из переменной KEY_ENV, None - если не задано ни то, ни другое
+    pub fn configured() -> Result<Option<EncryptionKey>, BankError> {
+        if let Some(path) = std::env::var_os(KEY_FILE_ENV) {
+            return Self::from_file(path).map(Some);
+        }
+        if std::env::var_os(KEY_ENV).is_some() {
+            return Self::from_env(KEY_ENV).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn id(&self) -> u64 {
+        let digest = Sha256::new()
+            .chain_update(b"bank operation log key")
+            .chain_update(self.0)
+            .finalize();
+        let mut id = [0; 8];
+        id.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(id)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+//сам ключ в логи не попадает
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey[{:016x}]", self.id())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    key_id: u64,
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+//ключи хранилища: текущим шифруются новые записи, прежние нужны, чтобы читать записи до ротации
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    current: EncryptionKey,
+    previous: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(current: EncryptionKey) -> Keyring {
+        Keyring {
+            current,
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn with_previous(mut self, key: EncryptionKey) -> Keyring {
+        self.previous.push(key);
+        self
+    }
+
+    pub fn current(&self) -> &EncryptionKey {
+        &self.current
+    }
+
+    //aad не шифруется, но подтверждается вместе с шифротекстом (например, OpId записи)
+    pub(crate) fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, BankError> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| BankError::CoreError(format!("Can't generate a nonce: {err}")))?;
+        let ciphertext = self
+            .current
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| BankError::CoreError("Can't encrypt a record".to_owned()))?;
+        let sealed = Sealed {
+            key_id: self.current.id(),
+            nonce,
+            ciphertext,
+        };
+        let mut buf = SEALED_MAGIC.to_vec();
+        bincode::serialize_into(&mut buf, &sealed)
+            .map_err(|err| BankError::CoreError(format!("Can't encode a sealed record: {err}")))?;
+        Ok(buf)
+    }
+
+    fn unseal(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, BankError> {
+        let sealed: Sealed = bincode::deserialize(sealed)
+            .map_err(|err| BankError::CoreError(format!("Can't decode a sealed record: {err}")))?;
+        let key = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id() == sealed.key_id)
+            .ok_or_else(|| {
+                BankError::WrongKey(format!(
+                    "record is encrypted with key[{:016x}], supplied keys are {:?}",
+                    sealed.key_id,
+                    self.key_ids()
+                ))
+            })?;
+        key.cipher()
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                BankError::CoreError(format!(
+                    "record can't be authenticated with key[{:016x}], it is damaged or forged",
+                    sealed.key_id
+                ))
+            })
+    }
+
+    //запись зашифрована текущим ключом, её не нужно перешифровывать
+    pub(crate) fn is_current(&self, payload: &[u8]) -> bool {
+        payload
+            .strip_prefix(SEALED_MAGIC)
+            .and_then(|sealed| bincode::deserialize::<Sealed>(sealed).ok())
+            .is_some_and(|sealed| sealed.key_id == self.current.id())
+    }
+
+    fn key_ids(&self) -> Vec<String> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .map(|key| format!("{:016x}", key.id()))
+            .collect()
+    }
+}
+
+//расшифровывает полезную нагрузку ключами keys, без ключей полезная нагрузка не должна быть зашифрована.
+//Запись, которая не проходит проверку подходящим ключом, повреждена (CoreError).
+//Незашифрованная запись в зашифрованной истории - тоже ошибка ключа: иначе подложенную запись
+//нельзя было бы отличить от настоящей. Незашифрованную историю шифрует rotate_key хранилища,
+//открытого без ключа
+pub(crate) fn open_payload(
+    keys: Option<&Keyring>,
+    payload: Vec<u8>,
+    aad: &[u8],
+) -> Result<Vec<u8>, BankError> {
+    match (payload.strip_prefix(SEALED_MAGIC), keys) {
+        (None, None) => Ok(payload),
+        (Some(sealed), Some(keys)) => keys.unseal(sealed, aad),
+        (None, Some(_)) => Err(BankError::WrongKey(
+            "record isn't encrypted, but a key is supplied".to_owned(),
+        )),
+        (Some(_), None) => Err(BankError::WrongKey(
+            "record is encrypted, but no key is supplied".to_owned(),
+        )),
+    }
+}
+
+//шифрует полезную нагрузку текущим ключом, без ключей оставляет как есть
+pub(crate) fn seal_payload(
+    keys: Option<&Keyring>,
+    payload: Vec<u8>,
+    aad: &[u8],
+) -> Result<Vec<u8>, BankError> {
+    match keys {
+        Some(keys) => keys.seal(&payload, aad),
+        None => Ok(payload),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keyring_should_reject_foreign_and_damaged_records() {
+        let old = EncryptionKey::new([1; KEY_LEN]);
+        let new = EncryptionKey::generate().unwrap();
+        assert_ne!(old.id(), new.id());
+        assert_eq!(EncryptionKey::from_hex(&old.to_hex()).unwrap(), old);
+        assert!(matches!(
+            EncryptionKey::from_hex("0123"),
+            Err(BankError::BadRequest(_))
+        ));
+        assert!(!format!("{old:?}").contains(&old.to_hex()));
+
+        let keys = Keyring::new(old.clone());
+        let sealed = seal_payload(Some(&keys), b"payload".to_vec(), b"1").unwrap();
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert!(!sealed.windows(7).any(|window| window == b"payload"));
+        assert_eq!(
+            open_payload(Some(&keys), sealed.clone(), b"1").unwrap(),
+            b"payload"
+        );
+
+        //прежний ключ читает записи до ротации, чужой - нет
+        let rotated = Keyring::new(new.clone()).with_previous(old);
+        assert_eq!(
+            open_payload(Some(&rotated), sealed.clone(), b"1").unwrap(),
+            b"payload"
+        );
+        let foreign = Keyring::new(new);
+        assert!(matches!(
+            open_payload(Some(&foreign), sealed.clone(), b"1"),
+            Err(BankError::WrongKey(_))
+        ));
+        assert!(matches!(
+            open_payload(None, sealed.clone(), b"1"),
+            Err(BankError::WrongKey(_))
+        ));
+        //запись, перенесённая на место другой, и изменённый шифротекст не проходят проверку
+        assert!(matches!(
+            open_payload(Some(&keys), sealed.clone(), b"2"),
+            Err(BankError::CoreError(_))
+        ));
+        let mut damaged = sealed.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open_payload(Some(&keys), damaged, b"1"),
+            Err(BankError::CoreError(_))
+        ));
+        assert!(keys.is_current(&sealed));
+        assert!(!rotated.is_current(&sealed));
+        assert!(!keys.is_current(b"plain"));
+
+        assert!(matches!(
+            open_payload(Some(&keys), b"plain".to_vec(), b"1"),
+            Err(BankError::WrongKey(_))
+        ));
+        assert_eq!(
+            open_payload(None, b"plain".to_vec(), b"1").unwrap(),
+            b"plain"
+        );
+    }
+}
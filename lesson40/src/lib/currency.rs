@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bank::{BankError, Money, NonZeroMoney};
+
+//валюта счёта, трёхбуквенный код ISO 4217
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const RUB: Currency = Currency(*b"RUB");
+    pub const USD: Currency = Currency(*b"USD");
+    pub const EUR: Currency = Currency(*b"EUR");
+
+    pub fn new(code: &str) -> Result<Currency, BankError> {
+        match code.as_bytes() {
+            &[a, b, c] if code.bytes().all(|ch| ch.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(BankError::BadRequest(format!(
+                "Currency code[{code}] should be three uppercase latin letters"
+            ))),
+        }
+    }
+}
+
+//базовая валюта банка, в ней открываются счета, если валюта не указана
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::RUB
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        //код создаётся только из латинских букв
+        f.write_str(std::str::from_utf8(&self.0).unwrap_or("???"))
+    }
+}
+
+//курс обмена: сколько единиц валюты to дают за одну единицу валюты from, в миллионных долях
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rate {
+    pub from: Currency,
+    pub to: Currency,
+    pub per_million: u64,
+}
+
+impl Rate {
+    pub const SCALE: u64 = 1_000_000;
+
+    //сумма в валюте to, округлённая вниз; обмен в ноль или с переполнением - ошибка
+    pub fn convert(&self, amount: NonZeroMoney) -> Result<NonZeroMoney, BankError> {
+        let converted = amount.get() as u128 * self.per_million as u128 / Self::SCALE as u128;
+        Money::try_from(converted)
+            .ok()
+            .and_then(NonZeroMoney::new)
+            .ok_or_else(|| {
+                BankError::BadRequest(format!(
+                    "Amount[{amount}] can't be exchanged at the rate[{self}]"
+                ))
+            })
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "1 {} = {}.{:06} {}",
+            self.from,
+            self.per_million / Self::SCALE,
+            self.per_million % Self::SCALE,
+            self.to
+        )
+    }
+}
+
+///Источник курсов обмена для переводов между счетами в разных валютах
+pub trait ExchangeRates: Debug + Send + Sync {
+    fn rate(&self, from: Currency, to: Currency) -> Option<Rate>;
+}
+
+//таблица курсов, заданная заранее
+#[derive(Debug, Default, Clone)]
+pub struct StaticRates(HashMap<(Currency, Currency), u64>);
+
+impl StaticRates {
+    pub fn with(mut self, from: Currency, to: Currency, per_million: u64) -> StaticRates {
+        self.0.insert((from, to), per_million);
+        self
+    }
+}
+
+impl ExchangeRates for StaticRates {
+    fn rate(&self, from: Currency, to: Currency) -> Option<Rate> {
+        self.0.get(&(from, to)).map(|&per_million| Rate {
+            from,
+            to,
+            per_million,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn currency_should_be_parsed_from_code() {
+        assert_eq!(Currency::new("USD"), Ok(Currency::USD));
+        assert_eq!(Currency::EUR.to_string(), "EUR");
+        assert!(Currency::new("usd").is_err());
+        assert!(Currency::new("USDT").is_err());
+        assert!(Currency::new("РУБ").is_err());
+    }
+
+    #[test]
+    fn rate_should_convert_rounding_down() {
+        let rates = StaticRates::default().with(Currency::USD, Currency::RUB, 81_500_000);
+        let rate = rates.rate(Currency::USD, Currency::RUB).unwrap();
+        assert_eq!(rate.to_string(), "1 USD = 81.500000 RUB");
+        assert_eq!(
+            rate.convert(NonZeroMoney::new(3).unwrap()).unwrap().get(),
+            244
+        );
+        assert!(rate.convert(NonZeroMoney::MAX).is_err());
+        assert!(rates.rate(Currency::RUB, Currency::USD).is_none());
+
+        let rate = Rate {
+            from: Currency::RUB,
+            to: Currency::USD,
+            per_million: 12_270,
+        };
+        assert_eq!(
+            rate.convert(NonZeroMoney::new(100).unwrap()).unwrap().get(),
+            1
+        );
+        assert!(rate.convert(NonZeroMoney::new(81).unwrap()).is_err());
+    }
+}
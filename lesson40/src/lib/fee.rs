@@ -0,0 +1,148 @@
+use std::fmt::Debug;
+
+use crate::{
+    bank::{AccountId, Money, NonZeroMoney, Operation},
+    currency::Currency,
+};
+
+//операции, за которые может взиматься комиссия
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeKind {
+    Deposit,
+    Withdraw,
+    Move,
+}
+
+///Политика комиссии: сколько взять за операцию на сумму amount, 0 - без комиссии
+pub trait FeePolicy: Debug + Send + Sync {
+    fn fee(&self, kind: FeeKind, amount: NonZeroMoney) -> Money;
+}
+
+//фиксированная комиссия за любую операцию
+#[derive(Debug, Clone, Copy)]
+pub struct FlatFee(pub Money);
+
+impl FeePolicy for FlatFee {
+    fn fee(&self, _: FeeKind, _: NonZeroMoney) -> Money {
+        self.0
+    }
+}
+
+//процент от суммы операции в базисных пунктах (1/100 процента), ограниченный снизу и сверху
+#[derive(Debug, Clone, Copy)]
+pub struct PercentFee {
+    pub basis_points: u32,
+    pub min: Money,
+    pub max: Money,
+}
+
+impl FeePolicy for PercentFee {
+    fn fee(&self, _: FeeKind, amount: NonZeroMoney) -> Money {
+        let fee = u128::from(amount.get()) * u128::from(self.basis_points) / 10_000;
+        //после ограничения сверху комиссия всегда помещается в Money
+        fee.clamp(u128::from(self.min), u128::from(self.max.max(self.min))) as Money
+    }
+}
+
+//своя политика для каждого типа операции
+#[derive(Debug)]
+pub struct PerOperationFee {
+    pub deposit: Box<dyn FeePolicy>,
+    pub withdraw: Box<dyn FeePolicy>,
+    pub transfer: Box<dyn FeePolicy>,
+}
+
+impl FeePolicy for PerOperationFee {
+    fn fee(&self, kind: FeeKind, amount: NonZeroMoney) -> Money {
+        match kind {
+            FeeKind::Deposit => self.deposit.fee(kind, amount),
+            FeeKind::Withdraw => self.withdraw.fee(kind, amount),
+            FeeKind::Move => self.transfer.fee(kind, amount),
+        }
+    }
+}
+
+//комиссия банка: политика и специальный счёт, на который она зачисляется
+#[derive(Debug)]
+pub(crate) struct Fees<Id = AccountId> {
+    pub(crate) account_id: Id,
+    pub(crate) policy: Box<dyn FeePolicy>,
+}
+
+impl<Id: Clone + PartialEq> Fees<Id> {
+    //комиссия, которую заплатит счёт payer, currency - валюта счёта банка.
+    //Со специального счёта комиссия не берётся, и она не конвертируется,
+    //поэтому берётся только со счетов в валюте специального счёта
+    pub(crate) fn fee(
+        &self,
+        payer: &Id,
+        currency: impl Fn(&Id) -> Option<Currency>,
+        kind: FeeKind,
+        money: NonZeroMoney,
+    ) -> Money {
+        if self.account_id != *payer && currency(payer) == currency(&self.account_id) {
+            self.policy.fee(kind, money)
+        } else {
+            0
+        }
+    }
+
+    //операция комиссии, если комиссия не нулевая
+    pub(crate) fn charge(
+        &self,
+        payer: &Id,
+        currency: impl Fn(&Id) -> Option<Currency>,
+        kind: FeeKind,
+        money: NonZeroMoney,
+    ) -> Option<Operation<Id>> {
+        NonZeroMoney::new(self.fee(payer, currency, kind, money)).map(|amount| Operation::Fee {
+            from: payer.clone(),
+            to: self.account_id.clone(),
+            amount,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn money(amount: Money) -> NonZeroMoney {
+        NonZeroMoney::new(amount).unwrap()
+    }
+
+    #[test]
+    fn flat_fee_should_not_depend_on_amount() {
+        let policy = FlatFee(3);
+        assert_eq!(policy.fee(FeeKind::Deposit, money(1)), 3);
+        assert_eq!(policy.fee(FeeKind::Move, NonZeroMoney::MAX), 3);
+    }
+
+    #[test]
+    fn percent_fee_should_be_clamped() {
+        let policy = PercentFee {
+            basis_points: 150, //1.5%
+            min: 2,
+            max: 100,
+        };
+        assert_eq!(policy.fee(FeeKind::Withdraw, money(10)), 2);
+        assert_eq!(policy.fee(FeeKind::Withdraw, money(1_000)), 15);
+        assert_eq!(policy.fee(FeeKind::Withdraw, NonZeroMoney::MAX), 100);
+    }
+
+    #[test]
+    fn per_operation_fee_should_dispatch_by_kind() {
+        let policy = PerOperationFee {
+            deposit: Box::new(FlatFee(0)),
+            withdraw: Box::new(FlatFee(1)),
+            transfer: Box::new(PercentFee {
+                basis_points: 100,
+                min: 0,
+                max: Money::MAX,
+            }),
+        };
+        assert_eq!(policy.fee(FeeKind::Deposit, money(500)), 0);
+        assert_eq!(policy.fee(FeeKind::Withdraw, money(500)), 1);
+        assert_eq!(policy.fee(FeeKind::Move, money(500)), 5);
+    }
+}
@@ -0,0 +1,894 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    bank::{
+        AccountId, BankAccountId, BankError, Entry, History, InMemoryOpsStorage, OpId, Operation,
+        OpsStorage,
+    },
+    chain::ChainHash,
+    clock::Timestamp,
+    crypto::{self, EncryptionKey, Keyring},
+};
+
+//заголовок записи: длина полезной нагрузки + контрольная сумма crc32, оба в big-endian
+pub(crate) const HEADER_LEN: usize = 8;
+
+//операция в записи файла
+pub(crate) type StoredOp<Id> = (OpId, Timestamp, Operation<Id>, ChainHash);
+
+//реализация хранилища операций банка в append-only файле.
+//Каждая транзакция записывается в конец файла одной записью
+//[len: u32][crc32: u32][Vec<(OpId, Timestamp, Operation, ChainHash)>],
+//поэтому перевод не может оказаться записанным наполовину.
+//Хэши цепочки хранятся вместе с операциями и при открытии не пересчитываются, их проверяет chain::verify.
+//Индексы по операциям и по счетам держатся в памяти и перестраиваются при открытии файла.
+//После архивирования (compact) начало истории - последняя архивированная операция, её хэш
+//и известные хранилищу счета - лежит рядом, в файле `<path>.base`.
+//С ключами (open_encrypted) полезная нагрузка записи шифруется текущим ключом (см. crypto),
+//смещение записи в файле подтверждается вместе с ней, поэтому записи нельзя переставить
+#[derive(Debug)]
+pub struct FileOpsStorage<Id = AccountId> {
+    path: PathBuf,
+    file: File,
+    index: InMemoryOpsStorage<Id>,
+    keys: Option<Keyring>,
+}
+
+impl<Id: BankAccountId> FileOpsStorage<Id> {
+    //открывает (или создаёт) файл истории и восстанавливает индексы.
+    //Недописанная последняя запись (например, после падения процесса) отрезается
+    pub fn open(path: impl AsRef<Path>) -> Result<FileOpsStorage<Id>, BankError> {
+        Self::open_with(path.as_ref(), None, InMemoryOpsStorage::default())
+    }
+
+    //файл истории, зашифрованный ключами keys. Запись, зашифрованная другим ключом
+    //или не зашифрованная вовсе, - ошибка WrongKey, а не мусор при разборе;
+    //запись, не прошедшая проверку своим ключом (повреждённая или перенесённая), - CoreError
+    pub fn open_encrypted(
+        path: impl AsRef<Path>,
+        keys: Keyring,
+    ) -> Result<FileOpsStorage<Id>, BankError> {
+        Self::open_with(path.as_ref(), Some(keys), InMemoryOpsStorage::default())
+    }
+
+    //файл, который продолжает историю после операции after с хэшем head (см. SegmentedOpsStorage)
+    pub(crate) fn open_after(
+        path: &Path,
+        keys: Option<Keyring>,
+        after: OpId,
+        head: ChainHash,
+    ) -> Result<FileOpsStorage<Id>, BankError> {
+        Self::open_with(path, keys, InMemoryOpsStorage::after(after, head))
+    }
+
+    fn open_with(
+        path: &Path,
+        keys: Option<Keyring>,
+        mut index: InMemoryOpsStorage<Id>,
+    ) -> Result<FileOpsStorage<Id>, BankError> {
+        let base: Option<Base<Id>> = read_base(path, keys.as_ref())?;
+        let archived = base.as_ref().map(|base| base.after);
+        if let Some(base) = base {
+            index = InMemoryOpsStorage::after(base.after, base.head);
+            for account_id in base.accounts {
+                index.add_account(account_id);
+            }
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut offset = 0;
+        while let Some((len, payload)) = decode_frame(&buf[offset..]) {
+            let ops: Vec<StoredOp<Id>> = open_record(keys.as_ref(), path, offset, payload)?;
+            for (op_id, at, op, hash) in ops {
+                //начало истории записано, а файл истории не успели заменить (см. compact)
+                if archived.is_some_and(|archived| op_id <= archived) {
+                    continue;
+                }
+                index.restore(op_id, at, op, hash)?;
+            }
+            offset += len;
+        }
+
+        if offset < buf.len() {
+            //повреждённую запись, за которой есть другие, нельзя отрезать: вместе с ней пропали бы
+            //зафиксированные транзакции. Недописанной может быть только последняя запись
+            if let Some(end) = frame_end(&buf[offset..]).filter(|end| offset + end < buf.len()) {
+                return Err(BankError::CoreError(format!(
+                    "File[{}] has a damaged record at offset[{}], it is followed by {} bytes",
+                    path.display(),
+                    offset,
+                    buf.len() - offset - end
+                )));
+            }
+            log::warn!(
+                "File[{}] has a torn record at offset[{}], {} bytes are truncated",
+                path.display(),
+                offset,
+                buf.len() - offset
+            );
+            file.set_len(offset as u64)?;
+            file.sync_data()?;
+        }
+
+        Ok(FileOpsStorage {
+            path: path.to_path_buf(),
+            file,
+            index,
+            keys,
+        })
+    }
+
+    //перешифровывает весь файл ключом key (или впервые шифрует незашифрованный).
+    //Записи расшифровываются текущими ключами, новый файл атомарно подменяет старый,
+    //после этого прежние ключи больше не нужны
+    pub fn rotate_key(&mut self, key: EncryptionKey) -> Result<(), BankError> {
+        let keys = Keyring::new(key);
+        let mut old = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut old)?;
+        let mut buf = Vec::new();
+        let mut offset = 0;
+        while let Some((len, payload)) = decode_frame(&old[offset..]) {
+            let payload = crypto::open_payload(self.keys.as_ref(), payload.to_vec(), &aad(offset))
+                .map_err(|err| at_offset(&self.path, offset, err))?;
+            let sealed = crypto::seal_payload(Some(&keys), payload, &aad(buf.len()))?;
+            encode_frame(&mut buf, &sealed);
+            offset += len;
+        }
+        if let Some(base) = read_base::<Id>(&self.path, self.keys.as_ref())? {
+            write_base(&self.path, Some(&keys), &base)?;
+        }
+        replace_file(&self.path, &buf)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.keys = Some(keys);
+        Ok(())
+    }
+
+    //есть записи (или база сжатия), зашифрованные не текущим ключом: rotate_key что-то перешифрует
+    pub fn needs_rotation(&self) -> Result<bool, BankError> {
+        let Some(keys) = &self.keys else {
+            return Ok(true);
+        };
+        let mut buf = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut buf)?;
+        let mut offset = 0;
+        while let Some((len, payload)) = decode_frame(&buf[offset..]) {
+            if !keys.is_current(payload) {
+                return Ok(true);
+            }
+            offset += len;
+        }
+        match std::fs::read(base_path(&self.path)) {
+            Ok(base) => {
+                Ok(decode_frame(&base).is_none_or(|(_, payload)| !keys.is_current(payload)))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    //счета, известные хранилищу, в том числе те, все операции которых архивированы
+    pub(crate) fn accounts(&self) -> impl Iterator<Item = &Id> {
+        self.index.accounts()
+    }
+
+    //размер файла в байтах
+    pub(crate) fn len(&self) -> Result<u64, BankError> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    //запись, которая окажется в файле по смещению offset
+    fn encode<T: Serialize + ?Sized>(
+        &self,
+        buf: &mut Vec<u8>,
+        offset: usize,
+        record: &T,
+    ) -> Result<(), BankError> {
+        let payload = bincode::serialize(record)
+            .map_err(|err| BankError::CoreError(format!("Can't encode record: {err}")))?;
+        let sealed = crypto::seal_payload(self.keys.as_ref(), payload, &aad(offset))?;
+        encode_frame(buf, &sealed);
+        Ok(())
+    }
+}
+
+//запись шифруется вместе со своим смещением в файле
+fn aad(offset: usize) -> [u8; 8] {
+    (offset as u64).to_be_bytes()
+}
+
+//начало истории не лежит в файле истории, поэтому подтверждается своей меткой, а не смещением
+const BASE_AAD: [u8; 8] = *b"ops.base";
+
+//начало истории после архивирования: операции до after включительно архивированы
+#[derive(Serialize, Deserialize)]
+struct Base<Id> {
+    after: OpId,
+    head: ChainHash,
+    accounts: Vec<Id>,
+}
+
+fn base_path(path: &Path) -> PathBuf {
+    let mut base = path.as_os_str().to_owned();
+    base.push(".base");
+    PathBuf::from(base)
+}
+
+//удаляет файл истории вместе с его началом
+pub(crate) fn remove_log(path: &Path) -> Result<(), BankError> {
+    std::fs::remove_file(path)?;
+    match std::fs::remove_file(base_path(path)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn write_base<Id: Serialize>(
+    path: &Path,
+    keys: Option<&Keyring>,
+    base: &Base<Id>,
+) -> Result<(), BankError> {
+    let payload = bincode::serialize(base)
+        .map_err(|err| BankError::CoreError(format!("Can't encode record: {err}")))?;
+    let mut buf = Vec::new();
+    encode_frame(&mut buf, &crypto::seal_payload(keys, payload, &BASE_AAD)?);
+    replace_file(&base_path(path), &buf)
+}
+
+//None - история не архивировалась
+fn read_base<Id: DeserializeOwned>(
+    path: &Path,
+    keys: Option<&Keyring>,
+) -> Result<Option<Base<Id>>, BankError> {
+    let path = base_path(path);
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let (_, payload) = decode_frame(&buf)
+        .ok_or_else(|| BankError::CoreError(format!("File[{}] is damaged", path.display())))?;
+    crypto::open_payload(keys, payload.to_vec(), &BASE_AAD)
+        .and_then(|payload| {
+            bincode::deserialize(&payload)
+                .map_err(|err| BankError::CoreError(format!("Can't decode record: {err}")))
+        })
+        .map(Some)
+        .map_err(|err| at_offset(&path, 0, err))
+}
+
+//ошибка записи с указанием файла и смещения, вид ошибки (например, WrongKey) сохраняется
+fn at_offset(path: &Path, offset: usize, err: BankError) -> BankError {
+    let place = format!("File[{}] at offset[{offset}]", path.display());
+    match err {
+        BankError::WrongKey(msg) => BankError::WrongKey(format!("{place}: {msg}")),
+        err => BankError::CoreError(format!("{place} has an unreadable record: {err}")),
+    }
+}
+
+//целая запись, которую не удалось расшифровать или разобрать (например, записанная в другом формате), - ошибка:
+//её нельзя отрезать как недописанную
+fn open_record<T: DeserializeOwned>(
+    keys: Option<&Keyring>,
+    path: &Path,
+    offset: usize,
+    payload: &[u8],
+) -> Result<T, BankError> {
+    crypto::open_payload(keys, payload.to_vec(), &aad(offset))
+        .and_then(|payload| {
+            bincode::deserialize(&payload)
+                .map_err(|err| BankError::CoreError(format!("Can't decode record: {err}")))
+        })
+        .map_err(|err| at_offset(path, offset, err))
+}
+
+//атомарная замена содержимого файла: запись во временный файл, fsync и переименование
+pub(crate) fn replace_file(path: &Path, content: &[u8]) -> Result<(), BankError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(content)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub(crate) fn encode_record<T: Serialize + ?Sized>(
+    buf: &mut Vec<u8>,
+    value: &T,
+) -> Result<(), BankError> {
+    let payload = bincode::serialize(value)
+        .map_err(|err| BankError::CoreError(format!("Can't encode record: {err}")))?;
+    encode_frame(buf, &payload);
+    Ok(())
+}
+
+pub(crate) fn encode_frame(buf: &mut Vec<u8>, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+//возвращает длину записи вместе с заголовком или None, если запись неполная или повреждена.
+//Целая запись, которую не удалось разобрать (например, записанная в другом формате), - ошибка:
+//её нельзя отрезать как недописанную
+pub(crate) fn decode_record<T: DeserializeOwned>(
+    buf: &[u8],
+) -> Result<Option<(usize, T)>, bincode::Error> {
+    let Some((len, payload)) = decode_frame(buf) else {
+        return Ok(None);
+    };
+    let value = bincode::deserialize(payload)?;
+    Ok(Some((len, value)))
+}
+
+//длина записи по заголовку, даже если сама запись неполная или повреждена
+fn frame_end(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..HEADER_LEN)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    Some(HEADER_LEN + len)
+}
+
+//длина записи вместе с заголовком и её полезная нагрузка
+pub(crate) fn decode_frame(buf: &[u8]) -> Option<(usize, &[u8])> {
+    let header = buf.get(..HEADER_LEN)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let payload = buf.get(HEADER_LEN..HEADER_LEN + len)?;
+    (crc32fast::hash(payload) == crc).then_some((HEADER_LEN + len, payload))
+}
+
+impl<Id: BankAccountId> OpsStorage for FileOpsStorage<Id> {
+    type Id = Id;
+
+    fn transact(&mut self, at: Timestamp, ops: &[Operation<Id>]) -> Result<Vec<OpId>, BankError> {
+        //идентификаторы и хэши назначаются так же, как это сделает индекс в памяти
+        let record: Vec<_> = self
+            .index
+            .links(at, ops.iter())?
+            .into_iter()
+            .zip(ops)
+            .map(|((op_id, hash), op)| (op_id, at, op, hash))
+            .collect();
+        //транзакция фиксируется одной записью и fsync, при ошибке файл откатывается
+        let committed_len = self.file.metadata()?.len();
+        let mut buf = Vec::new();
+        self.encode(&mut buf, committed_len as usize, &record[..])?;
+        if let Err(err) = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data())
+        {
+            self.file.set_len(committed_len)?;
+            return Err(err.into());
+        }
+
+        self.index.transact(at, ops)
+    }
+
+    fn get_ops(
+        &self,
+        account_id: &Id,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.index.get_ops(account_id)
+    }
+
+    fn get_ops_after(
+        &self,
+        account_id: &Id,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.index.get_ops_after(account_id, after)
+    }
+
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.index.get_history()
+    }
+
+    fn get_history_after(
+        &self,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.index.get_history_after(after)
+    }
+
+    fn last_op_id(&self) -> Result<OpId, BankError> {
+        self.index.last_op_id()
+    }
+
+    fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+        self.index.get_hash(op_id)
+    }
+
+    fn head_hash(&self) -> Result<ChainHash, BankError> {
+        self.index.head_hash()
+    }
+
+    //сначала записывается новое начало истории, затем оставшиеся операции переписываются
+    //в новый файл одной записью, который подменяет старый
+    fn compact(&mut self, before: OpId) -> Result<History<Id>, BankError> {
+        let mut archived = None;
+        for entry in self.index.get_history()? {
+            let (op_id, _, _) = entry?;
+            if op_id >= before {
+                break;
+            }
+            archived = Some(op_id);
+        }
+        let Some(after) = archived else {
+            return Ok(Vec::new());
+        };
+        let base = Base {
+            after,
+            head: self.index.get_hash(after)?,
+            accounts: self.index.accounts().cloned().collect(),
+        };
+        write_base(&self.path, self.keys.as_ref(), &base)?;
+
+        let tail = self
+            .index
+            .get_history_after(after)?
+            .map(|entry| {
+                let (op_id, at, op) = entry?;
+                Ok((op_id, at, op, self.index.get_hash(op_id)?))
+            })
+            .collect::<Result<Vec<_>, BankError>>()?;
+        let mut buf = Vec::new();
+        if !tail.is_empty() {
+            self.encode(&mut buf, 0, &tail[..])?;
+        }
+        replace_file(&self.path, &buf)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+
+        self.index.compact(before)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+
+    use super::*;
+    use crate::{
+        bank::{Account, AccountStatus, Bank, InMemoryState, NonZeroMoney},
+        currency::Currency,
+    };
+
+    fn fill(bank: &mut Bank<FileOpsStorage, InMemoryState>) {
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        let _ = bank.withdraw(129, NonZeroMoney::MIN); //rejected
+        let _ = bank.move_money(128, 129, NonZeroMoney::new(12).unwrap());
+    }
+
+    #[test]
+    fn file_storage_should_restore_bank() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let history: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(bank);
+
+        let restored = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .expect("bank should be restored");
+
+        //время операций тоже сохраняется в файле
+        let restored_history: History = restored
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(history, restored_history);
+
+        assert_eq!(
+            restored.get_balance(&128),
+            Ok(Account {
+                account_id: 128,
+                balance: 30,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+        assert_eq!(
+            restored.get_balance(&129),
+            Ok(Account {
+                account_id: 129,
+                balance: 12,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+        assert_eq!(restored.get_account_ops(&129).unwrap().count(), 2); //Create + Deposit
+    }
+
+    #[test]
+    fn file_storage_should_continue_op_ids_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let last = bank
+            .get_history()
+            .unwrap()
+            .last()
+            .map(|entry| entry.unwrap().0);
+        drop(bank);
+
+        let mut bank: Bank<FileOpsStorage, InMemoryState> = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        let _ = bank.deposit(&129, NonZeroMoney::MIN);
+        let ids: Vec<OpId> = bank
+            .get_history()
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+
+        assert_eq!(ids.len(), 5);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(Some(ids[3]), last);
+    }
+
+    //после архивирования всех операций счета остаются известны, нумерация и цепочка продолжаются
+    #[test]
+    fn file_storage_should_keep_accounts_after_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+        let mut storage: FileOpsStorage = FileOpsStorage::open(&path).unwrap();
+        storage.persist(0, &Operation::Create(128)).unwrap();
+        storage.persist(1, &Operation::Create(129)).unwrap();
+        let last = storage
+            .persist(2, &Operation::Deposit(129, NonZeroMoney::MIN))
+            .unwrap();
+        let before_compaction = std::fs::read(&path).unwrap();
+        assert_eq!(storage.compact(last).unwrap().len(), 2);
+        assert_eq!(
+            storage.compact(last.checked_add(1).unwrap()).unwrap().len(),
+            1
+        );
+        let head = storage.head_hash().unwrap();
+        drop(storage);
+
+        let reopened: FileOpsStorage = FileOpsStorage::open(&path).unwrap();
+        assert_eq!(reopened.get_ops(&128).unwrap().count(), 0);
+        assert_eq!(reopened.get_ops(&129).unwrap().count(), 0);
+        assert!(matches!(
+            reopened.get_ops(&130).map(|ops| ops.count()),
+            Err(BankError::BadRequest(_))
+        ));
+        assert_eq!(reopened.last_op_id().unwrap(), last);
+        assert_eq!(reopened.head_hash().unwrap(), head);
+        drop(reopened);
+
+        //падение после записи начала истории, но до замены файла истории
+        std::fs::write(&path, before_compaction).unwrap();
+        let mut reopened: FileOpsStorage = FileOpsStorage::open(&path).unwrap();
+        assert_eq!(reopened.get_history().unwrap().count(), 0);
+        assert_eq!(reopened.get_ops(&128).unwrap().count(), 0);
+        let next = reopened
+            .persist(3, &Operation::Deposit(128, NonZeroMoney::MIN))
+            .unwrap();
+        assert_eq!(next, last.checked_add(1).unwrap());
+        assert!(crate::chain::verify(&reopened).unwrap().is_intact());
+    }
+
+    #[test]
+    fn file_storage_should_keep_receipts_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let deposit = |bank: &mut Bank<FileOpsStorage, InMemoryState>| {
+            bank.deposit(&129, NonZeroMoney::MIN).map(drop)
+        };
+        let receipt = bank.idempotent(42, 1, deposit).cloned().unwrap();
+        drop(bank);
+
+        let mut restored: Bank<FileOpsStorage, InMemoryState> = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(restored.receipt_of(&42), Some(&receipt));
+        assert_eq!(restored.idempotent(42, 1, deposit), Ok(&receipt));
+        assert_eq!(restored.get_balance(&129).unwrap().balance, 13);
+    }
+
+    #[test]
+    fn file_storage_should_truncate_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        drop(bank);
+
+        let committed_len = std::fs::metadata(&path).unwrap().len();
+
+        //имитируем падение посреди записи: заголовок есть, данных нет
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 42, 1, 2, 3, 4, 5]).unwrap();
+        drop(file);
+
+        let restored: Bank<FileOpsStorage, InMemoryState> = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(restored.get_history().unwrap().count(), 4);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), committed_len);
+    }
+
+    #[test]
+    fn file_storage_should_drop_record_with_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        drop(bank);
+
+        //портим последний байт последней записи (перевод целиком)
+        let mut content = std::fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        let restored = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(restored.get_history().unwrap().count(), 3);
+        assert_eq!(
+            restored.get_balance(&128),
+            Ok(Account {
+                account_id: 128,
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+    }
+
+    #[test]
+    fn file_storage_should_not_truncate_records_after_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        drop(bank);
+
+        //портим последний байт первой записи (создание счёта 128), за ней - ещё три
+        let mut content = std::fs::read(&path).unwrap();
+        let (len, _) = decode_frame(&content).unwrap();
+        content[len - 1] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+
+        let ret = FileOpsStorage::<AccountId>::open(&path);
+        assert!(matches!(ret, Err(BankError::CoreError(msg)) if msg.contains("offset[0]")));
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn file_storage_should_not_truncate_unreadable_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        drop(bank);
+
+        //целая запись с верной контрольной суммой, но в чужом формате
+        let mut buf = Vec::new();
+        encode_record(&mut buf, "written by another version").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&buf).unwrap();
+        drop(file);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let ret = FileOpsStorage::<AccountId>::open(&path);
+        assert!(ret.is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn file_storage_should_detect_rewritten_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let head = bank.head_hash().unwrap();
+        drop(bank);
+
+        //файл переписан с верными контрольными суммами, но другой суммой пополнения
+        let content = std::fs::read(&path).unwrap();
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        let mut deposit = None;
+        while let Some((len, mut ops)) =
+            decode_record::<Vec<StoredOp<AccountId>>>(&content[offset..]).unwrap()
+        {
+            for (op_id, _, op, _) in ops.iter_mut() {
+                if let Operation::Deposit(_, amount) = op {
+                    *amount = NonZeroMoney::new(4_200).unwrap();
+                    deposit = Some(*op_id);
+                }
+            }
+            encode_record(&mut buf, &ops).unwrap();
+            offset += len;
+        }
+        std::fs::write(&path, buf).unwrap();
+
+        let bank: Bank<FileOpsStorage, InMemoryState> = Bank::restore(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 4_188);
+        let report = bank.verify_history().unwrap();
+        assert_eq!(report.tampered, deposit);
+        assert_eq!(report.head, head);
+    }
+
+    #[test]
+    fn file_storage_should_encrypt_history_and_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+        let old = EncryptionKey::new([7; crypto::KEY_LEN]);
+        let new = EncryptionKey::generate().unwrap();
+        let open = |key: &EncryptionKey| {
+            FileOpsStorage::<AccountId>::open_encrypted(&path, Keyring::new(key.clone()))
+        };
+
+        //история пишется без ключа и шифруется ротацией
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let history: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(bank);
+        assert!(matches!(open(&old), Err(BankError::WrongKey(_))));
+        let mut storage = FileOpsStorage::<AccountId>::open(&path).unwrap();
+        assert!(storage.needs_rotation().unwrap());
+        storage.rotate_key(old.clone()).unwrap();
+        assert!(!storage.needs_rotation().unwrap());
+        drop(storage);
+
+        //ключ не подходит - ошибка, а не мусор при разборе
+        assert!(matches!(
+            FileOpsStorage::<AccountId>::open(&path),
+            Err(BankError::WrongKey(_))
+        ));
+        assert!(matches!(open(&new), Err(BankError::WrongKey(_))));
+        let mut bank: Bank<FileOpsStorage, InMemoryState> =
+            Bank::restore(open(&old).unwrap(), InMemoryState::default()).unwrap();
+        let _ = bank.deposit(&129, NonZeroMoney::MIN);
+        drop(bank);
+
+        //после ротации прежний ключ больше не нужен и не подходит
+        let mut storage = FileOpsStorage::<AccountId>::open_encrypted(
+            &path,
+            Keyring::new(new.clone()).with_previous(old.clone()),
+        )
+        .unwrap();
+        assert!(storage.needs_rotation().unwrap());
+        storage.rotate_key(new.clone()).unwrap();
+        drop(storage);
+        assert!(matches!(open(&old), Err(BankError::WrongKey(_))));
+        //всё уже зашифровано текущим ключом: прежний ключ ничего не добавляет к ротации
+        let storage = FileOpsStorage::<AccountId>::open_encrypted(
+            &path,
+            Keyring::new(new.clone()).with_previous(old.clone()),
+        )
+        .unwrap();
+        assert!(!storage.needs_rotation().unwrap());
+        drop(storage);
+
+        let bank: Bank<FileOpsStorage, InMemoryState> =
+            Bank::restore(open(&new).unwrap(), InMemoryState::default()).unwrap();
+        let restored: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(restored[..history.len()], history[..]);
+        assert_eq!(restored.len(), history.len() + 1);
+        assert_eq!(bank.get_balance(&129).unwrap().balance, 13);
+        assert!(bank.verify_history().unwrap().is_intact());
+    }
+
+    #[test]
+    fn file_storage_should_reject_moved_encrypted_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.ops");
+        let key = EncryptionKey::generate().unwrap();
+
+        let mut bank: Bank<FileOpsStorage, InMemoryState> = Bank::new(
+            FileOpsStorage::open_encrypted(&path, Keyring::new(key.clone())).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        drop(bank);
+
+        //пополнение записано ещё раз в конец файла: контрольная сумма верна, смещение - нет.
+        //Ключ подходит, поэтому это повреждение, а не ошибка ключа
+        let content = std::fs::read(&path).unwrap();
+        let (len, _) = decode_frame(&content).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&content[len..]).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            FileOpsStorage::<AccountId>::open_encrypted(&path, Keyring::new(key)),
+            Err(BankError::CoreError(_))
+        ));
+    }
+}
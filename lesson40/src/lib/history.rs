@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use crate::bank::{
+    AccountId, BankError, Entry, History, Money, OpId, Operation, OperationKind, OpsStorage,
+};
+
+//запрос страницы истории: фильтры и курсор.
+//Пустые фильтры пропускают все операции, диапазоны включают обе границы
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryQuery<Id = AccountId> {
+    pub account_id: Option<Id>, //история одного счёта или всего банка
+    pub from: Option<OpId>,
+    pub to: Option<OpId>,
+    pub kinds: Vec<OperationKind>,
+    pub min_amount: Option<Money>, //операции без суммы не проходят фильтр по сумме
+    pub max_amount: Option<Money>,
+    pub after: Option<OpId>, //курсор: next предыдущей страницы
+    pub limit: usize,
+}
+
+impl<Id> HistoryQuery<Id> {
+    pub const DEFAULT_LIMIT: usize = 100;
+    //больше операций на странице клиент не получит, иначе одним запросом можно выгрузить всю историю
+    pub const MAX_LIMIT: usize = 1_000;
+    //на страницу просматривается не больше limit * SCAN_FACTOR операций, чтобы редкий фильтр
+    //не читал всю историю одним запросом
+    pub const SCAN_FACTOR: usize = 10;
+
+    pub fn matches(&self, op_id: OpId, op: &Operation<Id>) -> bool {
+        let amount_filtered = self.min_amount.is_some() || self.max_amount.is_some();
+        self.from.is_none_or(|from| op_id >= from)
+            && self.to.is_none_or(|to| op_id <= to)
+            && (self.kinds.is_empty() || self.kinds.contains(&op.kind()))
+            && (!amount_filtered
+                || op.amount().is_some_and(|amount| {
+                    self.min_amount.is_none_or(|min| amount >= min)
+                        && self.max_amount.is_none_or(|max| amount <= max)
+                }))
+    }
+
+    //операции ops должны начинаться после курсора и идти по возрастанию OpId.
+    //Операции читаются, только пока страница не заполнена и не исчерпан лимит просмотра
+    pub(crate) fn page(
+        &self,
+        mut ops: impl Iterator<Item = Result<Entry<Id>, BankError>>,
+    ) -> Result<HistoryPage<Id>, BankError> {
+        let scan_limit = self.limit.saturating_mul(Self::SCAN_FACTOR);
+        let mut page: History<Id> = Vec::new();
+        let mut scanned = 0;
+        let mut last = None;
+        while page.len() < self.limit && scanned < scan_limit {
+            let Some(entry) = ops.next() else {
+                break;
+            };
+            let (op_id, at, op) = entry?;
+            if self.to.is_some_and(|to| op_id > to) {
+                break;
+            }
+            scanned += 1;
+            last = Some(op_id);
+            if self.matches(op_id, &op) {
+                page.push((op_id, at, op));
+            }
+        }
+        //полная страница может быть не последней, как и неполная, на которой исчерпан лимит просмотра:
+        //следующая продолжится после последней просмотренной операции
+        let next = last.filter(|_| page.len() == self.limit || scanned == scan_limit);
+        Ok(HistoryPage { ops: page, next })
+    }
+}
+
+impl<Id> Default for HistoryQuery<Id> {
+    fn default() -> Self {
+        HistoryQuery {
+            account_id: None,
+            from: None,
+            to: None,
+            kinds: Vec::new(),
+            min_amount: None,
+            max_amount: None,
+            after: None,
+            limit: Self::DEFAULT_LIMIT,
+        }
+    }
+}
+
+//страница истории storage по запросу с фильтрами.
+//Начало страницы находится по индексу, а просматривается не больше limit * SCAN_FACTOR операций,
+//поэтому запрос стоит O(lgM + limit)
+pub(crate) fn query<T: OpsStorage>(
+    storage: &T,
+    query: &HistoryQuery<T::Id>,
+) -> Result<HistoryPage<T::Id>, BankError> {
+    if query.limit == 0 {
+        return Err(BankError::BadRequest(
+            "History page limit should be positive".to_owned(),
+        ));
+    }
+    if query.limit > HistoryQuery::<T::Id>::MAX_LIMIT {
+        return Err(BankError::BadRequest(format!(
+            "History page limit[{}] is greater than[{}]",
+            query.limit,
+            HistoryQuery::<T::Id>::MAX_LIMIT
+        )));
+    }
+    //курсор и нижняя граница диапазона сводятся к одной операции, после которой начинается страница
+    let after = query
+        .from
+        .and_then(|from| OpId::new(from.get() - 1))
+        .max(query.after)
+        .unwrap_or(OpId::MIN);
+    match &query.account_id {
+        Some(account_id) => query.page(storage.get_ops_after(account_id, after)?),
+        None => query.page(storage.get_history_after(after)?),
+    }
+}
+
+//страница истории и курсор следующей страницы, None - если страница последняя.
+//Страница с курсором может быть неполной и даже пустой, если фильтру соответствует мало операций
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPage<Id = AccountId> {
+    pub ops: History<Id>,
+    pub next: Option<OpId>,
+}
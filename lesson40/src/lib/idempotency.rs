@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bank::{BankError, Money, Operation};
+
+//ключ идемпотентности, который клиент передаёт вместе с изменяющим запросом
+pub type RequestKey = u128;
+
+//отпечаток запроса с ключом, хранится вместе с квитанцией:
+//тот же ключ с другим запросом - ошибка, а не квитанция чужого запроса
+pub type RequestFingerprint = u64;
+
+//отпечаток - начало sha256 от запроса в bincode, поэтому он не меняется после перезапуска
+pub fn fingerprint<R: Serialize + ?Sized>(request: &R) -> Result<RequestFingerprint, BankError> {
+    let encoded = bincode::serialize(request)
+        .map_err(|err| BankError::CoreError(format!("Can't encode request: {err}")))?;
+    let digest = Sha256::digest(encoded);
+    let mut fingerprint = [0; 8];
+    fingerprint.copy_from_slice(&digest[..8]);
+    Ok(RequestFingerprint::from_be_bytes(fingerprint))
+}
+
+//результат запроса с ключом: счета транзакции после её выполнения и списанная комиссия.
+//Первый счёт - тот, ради которого выполнялся запрос
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt<A> {
+    pub accounts: Vec<A>,
+    pub fee: Money,
+}
+
+//квитанции последних запросов с ключами, самые старые вытесняются при превышении capacity
+#[derive(Debug)]
+pub struct Receipts<A> {
+    capacity: usize,
+    order: VecDeque<RequestKey>,
+    by_key: HashMap<RequestKey, (RequestFingerprint, Receipt<A>)>,
+}
+
+impl<A> Receipts<A> {
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    pub fn with_capacity(capacity: usize) -> Receipts<A> {
+        Receipts {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            by_key: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &RequestKey) -> Option<&Receipt<A>> {
+        self.by_key.get(key).map(|(_, receipt)| receipt)
+    }
+
+    //квитанция запроса с ключом key, если он уже выполнялся именно с этим запросом
+    pub fn find(
+        &self,
+        key: &RequestKey,
+        fingerprint: RequestFingerprint,
+    ) -> Result<Option<&Receipt<A>>, BankError> {
+        match self.by_key.get(key) {
+            Some((used, _)) if *used != fingerprint => Err(BankError::BadRequest(format!(
+                "Key[{key}] is already used by another request"
+            ))),
+            found => Ok(found.map(|(_, receipt)| receipt)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    pub fn insert(
+        &mut self,
+        key: RequestKey,
+        fingerprint: RequestFingerprint,
+        receipt: Receipt<A>,
+    ) {
+        if self.by_key.insert(key, (fingerprint, receipt)).is_none() {
+            self.order.push_back(key);
+            self.evict();
+        }
+    }
+
+    //квитанции от самой старой к самой новой, например, для снимка банка
+    pub fn entries(&self) -> impl Iterator<Item = (RequestKey, RequestFingerprint, &Receipt<A>)> {
+        self.order.iter().filter_map(|key| {
+            let (fingerprint, receipt) = self.by_key.get(key)?;
+            Some((*key, *fingerprint, receipt))
+        })
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(key) = self.order.pop_front() {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+}
+
+impl<A> Default for Receipts<A> {
+    fn default() -> Self {
+        Receipts::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+}
+
+//квитанция для транзакции ops: все её счета по порядку и комиссия
+pub(crate) fn receipt<Id: Clone + PartialEq>(
+    key: RequestKey,
+    fingerprint: RequestFingerprint,
+    ops: &[Operation<Id>],
+) -> Option<Operation<Id>> {
+    let mut account_ids: Vec<Id> = Vec::new();
+    for account_id in ops.iter().flat_map(Operation::account_ids) {
+        if !account_ids.contains(&account_id) {
+            account_ids.push(account_id);
+        }
+    }
+    let fee = ops
+        .iter()
+        .map(|op| match op {
+            Operation::Fee { amount, .. } => amount.get(),
+            _ => 0,
+        })
+        .fold(0, Money::saturating_add);
+    let mut account_ids = account_ids.into_iter();
+    Some(Operation::Receipt {
+        key,
+        fingerprint,
+        account_id: account_ids.next()?,
+        related: account_ids.collect(),
+        fee,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn receipts_should_evict_the_oldest_keys() {
+        let mut receipts = Receipts::with_capacity(2);
+        for key in 1..=3 {
+            receipts.insert(
+                key,
+                0,
+                Receipt {
+                    accounts: vec![key],
+                    fee: 0,
+                },
+            );
+        }
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts.get(&1).is_none());
+        assert_eq!(receipts.get(&3).map(|receipt| receipt.accounts[0]), Some(3));
+
+        //повторная вставка не продлевает жизнь ключа
+        receipts.insert(
+            2,
+            0,
+            Receipt {
+                accounts: vec![2],
+                fee: 1,
+            },
+        );
+        //тот же ключ с другим запросом
+        assert!(matches!(
+            receipts.find(&2, 1),
+            Err(BankError::BadRequest(_))
+        ));
+        assert!(receipts.find(&2, 0).unwrap().is_some());
+        assert!(receipts.find(&4, 1).unwrap().is_none());
+        assert_ne!(
+            fingerprint(&(1u128, 42u64)).unwrap(),
+            fingerprint(&(1u128, 43u64)).unwrap()
+        );
+        receipts.set_capacity(1);
+        assert!(receipts.get(&2).is_none());
+        assert!(receipts.get(&3).is_some());
+    }
+}
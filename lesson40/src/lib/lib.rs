@@ -1,2 +1,19 @@
+pub mod async_bank;
+pub mod audit;
 pub mod bank;
+pub mod cache;
+pub mod chain;
+pub mod clock;
+pub mod concurrent;
+pub mod crypto;
+pub mod currency;
+pub mod fee;
+pub mod file_storage;
+pub mod history;
+pub mod idempotency;
 pub mod protocol;
+pub mod segmented;
+pub mod sharded;
+pub mod snapshot;
+pub mod sqlite;
+pub mod statement;
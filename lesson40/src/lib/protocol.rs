@@ -2,9 +2,14 @@ use std::{fmt::Debug, fmt::Display};
 
 use serde::{Deserialize, Serialize};
 
-use crate::bank::{AccountId, Money, NonZeroMoney};
-
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+use crate::{
+    audit::AuditReport,
+    bank::{AccountId, History, Money, NonZeroMoney, OpId, SignedMoney},
+    history::{HistoryPage, HistoryQuery},
+    idempotency::RequestKey,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ClientRequest {
     Create(AccountId),                 //регистрация счёта
     Deposit(AccountId, NonZeroMoney),  //пополнение
@@ -17,6 +22,38 @@ pub enum ClientRequest {
     },
     GetBalance(AccountId), //получение баланса
     Quit,                  //завершение сеанса
+    Freeze(AccountId),     //заморозка счёта
+    Unfreeze(AccountId),   //разморозка счёта
+    Close {
+        //закрытие счёта, остаток переводится на счёт payout
+        account_id: AccountId,
+        payout: Option<AccountId>,
+    },
+    Keyed {
+        //изменяющий запрос с ключом идемпотентности: повтор с тем же ключом не выполняется,
+        //а получает исходный ответ
+        key: RequestKey,
+        request: Box<ClientRequest>,
+    },
+    GetBalanceAt {
+        //баланс счёта сразу после операции op_id
+        account_id: AccountId,
+        op_id: OpId,
+    },
+    GetAccountOps(AccountId),   //история операций счёта со временем
+    QueryHistory(HistoryQuery), //страница истории с фильтрами
+    //административный запрос: сверка состояния с историей, выполняется только с токеном администратора
+    Audit(AdminToken),
+}
+
+//токен администратора не выводится в журнал запросов
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AdminToken(pub String);
+
+impl Debug for AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AdminToken(***)")
+    }
 }
 
 impl ClientRequest {
@@ -32,7 +69,7 @@ impl ClientRequest {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AccountRef {
     pub account_id: AccountId,
-    pub balance: Money,
+    pub balance: SignedMoney,
 }
 
 impl Display for AccountRef {
@@ -47,16 +84,29 @@ impl Display for AccountRef {
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ServerResponse {
-    AccountState(AccountRef), //Create, Deposit, Withdraw, GetBalance ops response
+    AccountState(AccountRef), //Create, GetBalance ops response
+    AccountChange {
+        //Deposit, Withdraw ops response
+        account: AccountRef,
+        fee: Money, //комиссия, списанная со счёта
+    },
     FundsMovement {
         //Move op response
         from: AccountRef,
         to: AccountRef,
+        fee: Money, //комиссия, списанная со счёта from
     },
     Error {
         message: String,
     },
     Bye,
+    AccountOps {
+        //GetAccountOps op response
+        account_id: AccountId,
+        ops: History,
+    },
+    HistoryPage(HistoryPage), //QueryHistory op response
+    Audit(AuditReport),       //Audit op response
 }
 
 impl ServerResponse {
@@ -73,8 +123,14 @@ impl ServerResponse {
 mod tests {
     use std::fmt::Debug;
 
-    use super::ClientRequest;
-    use crate::protocol::{AccountRef, ServerResponse};
+    use super::{AdminToken, ClientRequest};
+    use crate::{
+        audit::{AuditIssue, AuditReport},
+        bank::{Account, BankAccount, NonZeroMoney, OpId, Operation, OperationKind},
+        currency::Currency,
+        history::HistoryQuery,
+        protocol::{AccountRef, ServerResponse},
+    };
     use serde::{Deserialize, Serialize};
 
     fn test_base<T>(message: T)
@@ -92,6 +148,34 @@ mod tests {
     fn test_client_marshalling() {
         test_base(ClientRequest::Create(128));
         test_base(ClientRequest::Quit);
+        test_base(ClientRequest::Freeze(128));
+        test_base(ClientRequest::Close {
+            account_id: 128,
+            payout: Some(129),
+        });
+        test_base(ClientRequest::Keyed {
+            key: u128::MAX,
+            request: Box::new(ClientRequest::Deposit(128, NonZeroMoney::MIN)),
+        });
+        test_base(ClientRequest::GetBalanceAt {
+            account_id: 128,
+            op_id: OpId::MAX,
+        });
+        test_base(ClientRequest::Audit(AdminToken("secret".to_owned())));
+        assert_eq!(
+            format!(
+                "{:?}",
+                ClientRequest::Audit(AdminToken("secret".to_owned()))
+            ),
+            "Audit(AdminToken(***))"
+        );
+        test_base(ClientRequest::QueryHistory(HistoryQuery {
+            account_id: Some(128),
+            kinds: vec![OperationKind::Transfer],
+            min_amount: Some(10),
+            after: Some(OpId::MIN),
+            ..HistoryQuery::default()
+        }));
     }
 
     #[test]
@@ -105,10 +189,52 @@ mod tests {
                 account_id: 129,
                 balance: 42,
             },
+            fee: 1,
+        });
+
+        test_base(ServerResponse::AccountChange {
+            account: AccountRef {
+                account_id: 128,
+                balance: 120,
+            },
+            fee: 0,
         });
 
         test_base(ServerResponse::Error {
             message: "an error".to_owned(),
         });
+
+        test_base(ServerResponse::AccountOps {
+            account_id: 128,
+            ops: vec![
+                (OpId::MIN, 1_700_000_000_000, Operation::Create(128)),
+                (
+                    OpId::new(2).unwrap(),
+                    1_700_000_000_001,
+                    Operation::Transfer {
+                        from: 128,
+                        to: 129,
+                        amount: NonZeroMoney::MIN,
+                    },
+                ),
+            ],
+        });
+
+        test_base(ServerResponse::Audit(AuditReport {
+            ops: 3,
+            accounts: 2,
+            issues: vec![
+                AuditIssue::Mismatch {
+                    account_id: 128,
+                    expected: Some(Account::open(128)),
+                    actual: None,
+                },
+                AuditIssue::Unbalanced {
+                    currency: Currency::RUB,
+                    turnover: 0,
+                    balances: -1,
+                },
+            ],
+        }));
     }
 }
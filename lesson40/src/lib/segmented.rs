@@ -0,0 +1,1007 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::{Read, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bank::{
+        AccountId, BankAccountId, BankError, Entry, HashedEntry, History, OpId, Operation,
+        OpsStorage,
+    },
+    chain::ChainHash,
+    clock::Timestamp,
+    crypto::{self, EncryptionKey, Keyring},
+    file_storage::{
+        decode_frame, encode_frame, remove_log, replace_file, FileOpsStorage, StoredOp, HEADER_LEN,
+    },
+};
+
+//когда открытый сегмент закрывается и начинается следующий
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentLimits {
+    pub max_bytes: u64,
+    pub max_ops: usize,
+}
+
+impl Default for SegmentLimits {
+    fn default() -> Self {
+        SegmentLimits {
+            max_bytes: 4 << 20,
+            max_ops: 10_000,
+        }
+    }
+}
+
+//сегмент истории: операции [first, last], sealed - закрыт и сжат
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub first: OpId,
+    pub last: OpId,
+    pub ops: usize,
+    pub bytes: u64,
+    pub sealed: bool,
+}
+
+//заголовок закрытого сегмента: его читают при открытии вместо самих операций
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentHeader<Id> {
+    first: OpId,
+    last: OpId,
+    ops: usize,
+    head: ChainHash,
+    accounts: Vec<Id>,
+}
+
+//хранилище операций в каталоге сегментов.
+//Новые операции дописываются в открытый сегмент `<first>.log` в формате FileOpsStorage.
+//Когда в нём набирается limits.max_ops операций или limits.max_bytes байт, он закрывается:
+//операции сжимаются (deflate) в `<first>.seg` одной записью, перед которой лежит заголовок
+//с диапазоном OpId, хэшем последней операции и счетами сегмента, и начинается следующий сегмент.
+//Счета, операции которых архивированы (compact), остаются в заголовке первого оставшегося сегмента.
+//В памяти держатся только заголовки закрытых сегментов и открытый сегмент,
+//поэтому get_history, get_ops и их варианты after распаковывают только нужные сегменты и по одному.
+//С ключами записи шифруются после сжатия (см. crypto), rotate_key перешифровывает и закрытые сегменты
+#[derive(Debug)]
+pub struct SegmentedOpsStorage<Id = AccountId> {
+    dir: PathBuf,
+    limits: SegmentLimits,
+    keys: Option<Keyring>,
+    //закрытые сегменты по первой операции
+    sealed: BTreeMap<OpId, SegmentInfo>,
+    //закрытые сегменты (по первой операции), в которых есть операции счёта
+    accounts: HashMap<Id, BTreeSet<OpId>>,
+    active: FileOpsStorage<Id>,
+    active_first: OpId,
+    active_ops: usize,
+    //сколько раз закрытые сегменты читались с диска и распаковывались
+    loads: Cell<u64>,
+}
+
+fn segment_path(dir: &Path, first: OpId, ext: &str) -> PathBuf {
+    dir.join(format!("{:032x}.{ext}", first.get()))
+}
+
+//первая операция сегмента по имени файла, None - для посторонних файлов
+fn parse_segment(path: &Path) -> Option<(OpId, String)> {
+    let ext = path.extension()?.to_str()?.to_owned();
+    let first = u128::from_str_radix(path.file_stem()?.to_str()?, 16).ok()?;
+    Some((OpId::new(first)?, ext))
+}
+
+//заголовок и операции закрытого сегмента подтверждаются вместе с его первой операцией и своей ролью
+fn aad(first: OpId, part: u8) -> Vec<u8> {
+    let mut aad = first.get().to_be_bytes().to_vec();
+    aad.push(part);
+    aad
+}
+
+const HEADER_PART: u8 = 0;
+const OPS_PART: u8 = 1;
+
+fn corrupted(path: &Path, err: impl std::fmt::Display) -> BankError {
+    BankError::CoreError(format!("Segment[{}] is corrupted: {err}", path.display()))
+}
+
+//ошибка ключа остаётся ошибкой ключа, но с указанием сегмента; остальное - повреждение сегмента
+fn in_segment(path: &Path, err: BankError) -> BankError {
+    match err {
+        BankError::WrongKey(msg) => {
+            BankError::WrongKey(format!("Segment[{}]: {msg}", path.display()))
+        }
+        err => corrupted(path, err),
+    }
+}
+
+fn compress(payload: &[u8]) -> Result<Vec<u8>, BankError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+    Ok(encoder.finish()?)
+}
+
+fn decompress(payload: &[u8]) -> Result<Vec<u8>, BankError> {
+    let mut buf = Vec::new();
+    DeflateDecoder::new(payload).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+impl<Id: BankAccountId> SegmentedOpsStorage<Id> {
+    //открывает (или создаёт) каталог сегментов
+    pub fn open(
+        dir: impl AsRef<Path>,
+        limits: SegmentLimits,
+    ) -> Result<SegmentedOpsStorage<Id>, BankError> {
+        Self::open_with(dir.as_ref(), limits, None)
+    }
+
+    //каталог сегментов, зашифрованных ключами keys, см. FileOpsStorage::open_encrypted
+    pub fn open_encrypted(
+        dir: impl AsRef<Path>,
+        limits: SegmentLimits,
+        keys: Keyring,
+    ) -> Result<SegmentedOpsStorage<Id>, BankError> {
+        Self::open_with(dir.as_ref(), limits, Some(keys))
+    }
+
+    fn open_with(
+        dir: &Path,
+        limits: SegmentLimits,
+        keys: Option<Keyring>,
+    ) -> Result<SegmentedOpsStorage<Id>, BankError> {
+        std::fs::create_dir_all(dir)?;
+        let mut seg_files = BTreeMap::new();
+        let mut log_files = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match parse_segment(&path) {
+                Some((first, ext)) if ext == "seg" => seg_files.insert(first, path),
+                Some((first, ext)) if ext == "log" => log_files.insert(first, path),
+                _ => None,
+            };
+        }
+
+        let mut sealed = BTreeMap::new();
+        let mut accounts: HashMap<Id, BTreeSet<OpId>> = HashMap::new();
+        let (mut last, mut head) = (OpId::MIN, ChainHash::GENESIS);
+        for (first, path) in seg_files {
+            let header: SegmentHeader<Id> = read_header(&path, keys.as_ref(), first)?;
+            if header.first <= last || header.last < header.first {
+                return Err(corrupted(
+                    &path,
+                    format!("operations[{}..={}] overlap", header.first, header.last),
+                ));
+            }
+            for account_id in header.accounts {
+                accounts.entry(account_id).or_default().insert(first);
+            }
+            (last, head) = (header.last, header.head);
+            sealed.insert(
+                first,
+                SegmentInfo {
+                    first,
+                    last,
+                    ops: header.ops,
+                    bytes: std::fs::metadata(&path)?.len(),
+                    sealed: true,
+                },
+            );
+        }
+
+        //открытый сегмент, который успели закрыть перед падением, остался рядом со своей копией
+        for (first, path) in log_files.extract_if(.., |first, _| sealed.contains_key(first)) {
+            log::warn!("Segment[{first}] is already sealed, its log is removed");
+            remove_log(&path)?;
+        }
+        let active_first = match log_files.len() {
+            0 => last
+                .checked_add(1)
+                .ok_or_else(|| BankError::CoreError("OpId overflow".to_owned()))?,
+            1 => *log_files.keys().next().unwrap_or(&last),
+            n => {
+                return Err(BankError::CoreError(format!(
+                    "Directory[{}] has {n} open segments",
+                    dir.display()
+                )))
+            }
+        };
+        if active_first <= last {
+            return Err(BankError::CoreError(format!(
+                "Open segment[{active_first}] overlaps sealed operations up to[{last}]"
+            )));
+        }
+        //имя открытого сегмента продолжает нумерацию, даже если все операции до него архивированы
+        let after = OpId::new(active_first.get() - 1).unwrap_or(OpId::MIN);
+        let active = FileOpsStorage::open_after(
+            &segment_path(dir, active_first, "log"),
+            keys.clone(),
+            after,
+            head,
+        )?;
+        let active_ops = active.get_history()?.count();
+
+        let mut storage = SegmentedOpsStorage {
+            dir: dir.to_path_buf(),
+            limits,
+            keys,
+            sealed,
+            accounts,
+            active,
+            active_first,
+            active_ops,
+            loads: Cell::new(0),
+        };
+        storage.rotate_if_full()?;
+        Ok(storage)
+    }
+
+    //закрытые сегменты и открытый по возрастанию OpId
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>, BankError> {
+        let mut segments: Vec<_> = self.sealed.values().copied().collect();
+        segments.push(SegmentInfo {
+            first: self.active_first,
+            last: self.active.last_op_id()?,
+            ops: self.active_ops,
+            bytes: self.active.len()?,
+            sealed: false,
+        });
+        Ok(segments)
+    }
+
+    //сегмент, в котором лежит (или будет лежать) операция op_id
+    pub fn segment_of(&self, op_id: OpId) -> Result<Option<SegmentInfo>, BankError> {
+        Ok(self
+            .segments()?
+            .into_iter()
+            .rev()
+            .find(|segment| segment.first <= op_id))
+    }
+
+    pub fn loads(&self) -> u64 {
+        self.loads.get()
+    }
+
+    //перешифровывает ключом key все закрытые сегменты и открытый
+    pub fn rotate_key(&mut self, key: EncryptionKey) -> Result<(), BankError> {
+        let keys = Keyring::new(key.clone());
+        for segment in self.sealed.values_mut() {
+            let path = segment_path(&self.dir, segment.first, "seg");
+            let (header, ops) = read_parts::<Id>(&path, self.keys.as_ref(), segment.first)?;
+            segment.bytes = write_segment(&path, Some(&keys), segment.first, &header, &ops)?;
+        }
+        self.active.rotate_key(key)?;
+        self.keys = Some(keys);
+        Ok(())
+    }
+
+    //операции закрытого сегмента, распакованные целиком
+    fn load(&self, first: OpId) -> Result<Vec<StoredOp<Id>>, BankError> {
+        self.loads.set(self.loads.get() + 1);
+        let path = segment_path(&self.dir, first, "seg");
+        let (_, ops) = read_parts::<Id>(&path, self.keys.as_ref(), first)?;
+        decode_ops(&path, &ops)
+    }
+
+    //операции сегментов firsts, прошедшие filter; сегмент распаковывается, когда до него дошёл обход
+    fn scan<'a>(
+        &'a self,
+        firsts: impl Iterator<Item = OpId> + 'a,
+        filter: impl Fn(&StoredOp<Id>) -> bool + 'a,
+    ) -> impl Iterator<Item = Result<Entry<Id>, BankError>> + 'a {
+        self.scan_stored(firsts, filter)
+            .map(|op| op.map(|(op_id, at, op, _)| (op_id, at, op)))
+    }
+
+    //то же, но вместе с хэшами операций
+    fn scan_stored<'a>(
+        &'a self,
+        firsts: impl Iterator<Item = OpId> + 'a,
+        filter: impl Fn(&StoredOp<Id>) -> bool + 'a,
+    ) -> impl Iterator<Item = Result<StoredOp<Id>, BankError>> + 'a {
+        firsts.flat_map(move |first| match self.load(first) {
+            Ok(ops) => ops.into_iter().filter(|op| filter(op)).map(Ok).collect(),
+            Err(err) => vec![Err(err)],
+        })
+    }
+
+    //закрытые сегменты, в которых могут быть операции после after
+    fn sealed_after(&self, after: OpId) -> impl Iterator<Item = OpId> + '_ {
+        self.sealed
+            .values()
+            .filter(move |segment| segment.last > after)
+            .map(|segment| segment.first)
+    }
+
+    fn rotate_if_full(&mut self) -> Result<(), BankError> {
+        if self.active_ops == 0
+            || (self.active_ops < self.limits.max_ops && self.active.len()? < self.limits.max_bytes)
+        {
+            return Ok(());
+        }
+        let first = self.active_first;
+        let ops = self
+            .active
+            .get_history()?
+            .map(|entry| {
+                let (op_id, at, op) = entry?;
+                Ok((op_id, at, op, self.active.get_hash(op_id)?))
+            })
+            .collect::<Result<Vec<StoredOp<Id>>, BankError>>()?;
+        //в том числе счета, операции которых архивированы из открытого сегмента
+        let mut accounts: Vec<Id> = self.active.accounts().cloned().collect();
+        accounts.sort();
+        let header = SegmentHeader {
+            first,
+            last: self.active.last_op_id()?,
+            ops: ops.len(),
+            head: self.active.head_hash()?,
+            accounts,
+        };
+        let payload = bincode::serialize(&ops)
+            .map_err(|err| BankError::CoreError(format!("Can't encode segment: {err}")))?;
+        let path = segment_path(&self.dir, first, "seg");
+        let bytes = write_segment(
+            &path,
+            self.keys.as_ref(),
+            first,
+            &header,
+            &compress(&payload)?,
+        )?;
+
+        let next = header
+            .last
+            .checked_add(1)
+            .ok_or_else(|| BankError::CoreError("OpId overflow".to_owned()))?;
+        let active = FileOpsStorage::open_after(
+            &segment_path(&self.dir, next, "log"),
+            self.keys.clone(),
+            header.last,
+            header.head,
+        )?;
+        remove_log(&segment_path(&self.dir, first, "log"))?;
+        for account_id in &header.accounts {
+            self.accounts
+                .entry(account_id.clone())
+                .or_default()
+                .insert(first);
+        }
+        self.sealed.insert(
+            first,
+            SegmentInfo {
+                first,
+                last: header.last,
+                ops: header.ops,
+                bytes,
+                sealed: true,
+            },
+        );
+        self.active = active;
+        self.active_first = next;
+        self.active_ops = 0;
+        Ok(())
+    }
+}
+
+//закрытый сегмент: запись с заголовком и запись со сжатыми операциями, файл подменяется атомарно.
+//Возвращает размер файла
+fn write_segment<Id: Serialize>(
+    path: &Path,
+    keys: Option<&Keyring>,
+    first: OpId,
+    header: &SegmentHeader<Id>,
+    compressed: &[u8],
+) -> Result<u64, BankError> {
+    let header = bincode::serialize(header)
+        .map_err(|err| BankError::CoreError(format!("Can't encode segment header: {err}")))?;
+    let mut buf = Vec::new();
+    encode_frame(
+        &mut buf,
+        &crypto::seal_payload(keys, header, &aad(first, HEADER_PART))?,
+    );
+    encode_frame(
+        &mut buf,
+        &crypto::seal_payload(keys, compressed.to_vec(), &aad(first, OPS_PART))?,
+    );
+    replace_file(path, &buf)?;
+    Ok(buf.len() as u64)
+}
+
+//только заголовок: файл читается до конца первой записи
+fn read_header<Id: BankAccountId>(
+    path: &Path,
+    keys: Option<&Keyring>,
+    first: OpId,
+) -> Result<SegmentHeader<Id>, BankError> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; HEADER_LEN];
+    file.read_exact(&mut buf)
+        .map_err(|err| corrupted(path, err))?;
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    buf.resize(HEADER_LEN + len, 0);
+    file.read_exact(&mut buf[HEADER_LEN..])
+        .map_err(|err| corrupted(path, err))?;
+    let (_, payload) = decode_frame(&buf).ok_or_else(|| corrupted(path, "bad checksum"))?;
+    decode_header(path, keys, first, payload)
+}
+
+fn decode_header<Id: BankAccountId>(
+    path: &Path,
+    keys: Option<&Keyring>,
+    first: OpId,
+    payload: &[u8],
+) -> Result<SegmentHeader<Id>, BankError> {
+    let header = crypto::open_payload(keys, payload.to_vec(), &aad(first, HEADER_PART))
+        .map_err(|err| in_segment(path, err))?;
+    let header: SegmentHeader<Id> =
+        bincode::deserialize(&header).map_err(|err| corrupted(path, err))?;
+    if header.first != first {
+        return Err(corrupted(path, format!("it starts at[{}]", header.first)));
+    }
+    Ok(header)
+}
+
+//заголовок и расшифрованные, но ещё сжатые операции
+fn read_parts<Id: BankAccountId>(
+    path: &Path,
+    keys: Option<&Keyring>,
+    first: OpId,
+) -> Result<(SegmentHeader<Id>, Vec<u8>), BankError> {
+    let buf = std::fs::read(path)?;
+    let (len, header) = decode_frame(&buf).ok_or_else(|| corrupted(path, "bad header"))?;
+    let header = decode_header(path, keys, first, header)?;
+    let (_, ops) = decode_frame(&buf[len..]).ok_or_else(|| corrupted(path, "bad operations"))?;
+    let ops = crypto::open_payload(keys, ops.to_vec(), &aad(first, OPS_PART))
+        .map_err(|err| in_segment(path, err))?;
+    Ok((header, ops))
+}
+
+fn decode_ops<Id: BankAccountId>(
+    path: &Path,
+    compressed: &[u8],
+) -> Result<Vec<StoredOp<Id>>, BankError> {
+    let payload = decompress(compressed).map_err(|err| corrupted(path, err))?;
+    bincode::deserialize(&payload).map_err(|err| corrupted(path, err))
+}
+
+impl<Id: BankAccountId> OpsStorage for SegmentedOpsStorage<Id> {
+    type Id = Id;
+
+    fn transact(&mut self, at: Timestamp, ops: &[Operation<Id>]) -> Result<Vec<OpId>, BankError> {
+        let op_ids = self.active.transact(at, ops)?;
+        self.active_ops += op_ids.len();
+        //транзакция уже сохранена, ошибка закрытия сегмента повторится при следующей
+        if let Err(err) = self.rotate_if_full() {
+            log::warn!("Segment[{}] can't be sealed: {err}", self.active_first);
+        }
+        Ok(op_ids)
+    }
+
+    fn get_ops(
+        &self,
+        account_id: &Id,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.get_ops_after(account_id, OpId::MIN)
+    }
+
+    fn get_ops_after(
+        &self,
+        account_id: &Id,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        let sealed = self.accounts.get(account_id);
+        //счёт без операций в открытом сегменте известен по закрытым
+        let active = match self.active.get_ops_after(account_id, after) {
+            Ok(active) => Some(active),
+            Err(BankError::BadRequest(_)) if sealed.is_some() => None,
+            Err(err) => return Err(err),
+        };
+        let firsts = sealed
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |first| self.sealed.get(first).is_some_and(|s| s.last > after));
+        let account_id = account_id.clone();
+        let sealed = self.scan(firsts, move |(op_id, _, op, _)| {
+            *op_id > after && op.account_ids().contains(&account_id)
+        });
+        Ok(sealed.chain(active.into_iter().flatten()))
+    }
+
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.get_history_after(OpId::MIN)
+    }
+
+    fn get_history_after(
+        &self,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        let sealed = self.scan(self.sealed_after(after), move |(op_id, ..)| *op_id > after);
+        Ok(sealed.chain(self.active.get_history_after(after)?))
+    }
+
+    fn last_op_id(&self) -> Result<OpId, BankError> {
+        self.active.last_op_id()
+    }
+
+    fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+        if op_id >= self.active_first {
+            return self.active.get_hash(op_id);
+        }
+        self.sealed
+            .range(..=op_id)
+            .next_back()
+            .map(|(first, _)| self.load(*first))
+            .transpose()?
+            .and_then(|ops| ops.into_iter().find(|(id, ..)| *id == op_id))
+            .map(|(.., hash)| hash)
+            .ok_or_else(|| {
+                BankError::BadRequest(format!("There is no operation[{op_id}] in the storage"))
+            })
+    }
+
+    //каждый закрытый сегмент распаковывается один раз, а не для каждой своей операции
+    fn get_hashed_history(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<HashedEntry<Id>, BankError>>, BankError> {
+        let sealed = self
+            .scan_stored(self.sealed_after(OpId::MIN), |_| true)
+            .map(|op| op.map(|(op_id, at, op, hash)| ((op_id, at, op), hash)));
+        Ok(sealed.chain(self.active.get_hashed_history()?))
+    }
+
+    fn head_hash(&self) -> Result<ChainHash, BankError> {
+        self.active.head_hash()
+    }
+
+    //закрытые сегменты до before удаляются, кроме последнего из них: он переписывается без архивных
+    //операций под своим прежним именем (даже пустым) и хранит счета всех удалённых сегментов,
+    //чтобы они оставались известны хранилищу, и хэш, от которого продолжается цепочка.
+    //Последний сегмент переписывается раньше, чем удаляются остальные
+    fn compact(&mut self, before: OpId) -> Result<History<Id>, BankError> {
+        let firsts: Vec<OpId> = self
+            .sealed
+            .range((Bound::Unbounded, Bound::Excluded(before)))
+            .map(|(first, _)| *first)
+            .collect();
+        let mut accounts: Vec<Id> = Vec::new();
+        for first in &firsts {
+            let path = segment_path(&self.dir, *first, "seg");
+            accounts.extend(read_header::<Id>(&path, self.keys.as_ref(), *first)?.accounts);
+        }
+        accounts.sort();
+        accounts.dedup();
+
+        let mut archived = Vec::new();
+        for first in firsts.iter().rev().copied() {
+            let path = segment_path(&self.dir, first, "seg");
+            let (header, ops) = read_parts::<Id>(&path, self.keys.as_ref(), first)?;
+            let mut ops = decode_ops(&path, &ops)?;
+            let tail = ops.split_off(ops.partition_point(|(op_id, ..)| *op_id < before));
+            archived.push(ops);
+            for account_id in &header.accounts {
+                if let Some(segments) = self.accounts.get_mut(account_id) {
+                    segments.remove(&first);
+                }
+            }
+            if firsts.last() != Some(&first) {
+                std::fs::remove_file(&path)?;
+                self.sealed.remove(&first);
+                continue;
+            }
+
+            for account_id in &accounts {
+                self.accounts
+                    .entry(account_id.clone())
+                    .or_default()
+                    .insert(first);
+            }
+            let header = SegmentHeader {
+                ops: tail.len(),
+                accounts: std::mem::take(&mut accounts),
+                ..header
+            };
+            let payload = bincode::serialize(&tail)
+                .map_err(|err| BankError::CoreError(format!("Can't encode segment: {err}")))?;
+            let bytes = write_segment(
+                &path,
+                self.keys.as_ref(),
+                first,
+                &header,
+                &compress(&payload)?,
+            )?;
+            if let Some(segment) = self.sealed.get_mut(&first) {
+                segment.ops = header.ops;
+                segment.bytes = bytes;
+            }
+        }
+        let mut archived: History<Id> = archived
+            .into_iter()
+            .rev()
+            .flatten()
+            .map(|(op_id, at, op, _)| (op_id, at, op))
+            .collect();
+        if before > self.active_first {
+            let active = self.active.compact(before)?;
+            self.active_ops -= active.len();
+            archived.extend(active);
+        }
+        Ok(archived)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::bank::{Bank, InMemoryState, NonZeroMoney};
+
+    //сегменты по три операции, чтобы тесты банка переходили между сегментами
+    const SMALL: SegmentLimits = SegmentLimits {
+        max_bytes: 1 << 20,
+        max_ops: 3,
+    };
+
+    //хранилище вместе со своим временным каталогом
+    #[derive(Debug)]
+    struct TempSegments {
+        storage: SegmentedOpsStorage,
+        _dir: TempDir,
+    }
+
+    impl Default for TempSegments {
+        fn default() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            TempSegments {
+                storage: SegmentedOpsStorage::open(dir.path(), SMALL).unwrap(),
+                _dir: dir,
+            }
+        }
+    }
+
+    impl OpsStorage for TempSegments {
+        type Id = AccountId;
+
+        fn transact(
+            &mut self,
+            at: Timestamp,
+            ops: &[Operation<AccountId>],
+        ) -> Result<Vec<OpId>, BankError> {
+            self.storage.transact(at, ops)
+        }
+
+        fn get_ops(
+            &self,
+            account_id: &AccountId,
+        ) -> Result<impl Iterator<Item = Result<Entry<AccountId>, BankError>>, BankError> {
+            self.storage.get_ops(account_id)
+        }
+
+        fn get_ops_after(
+            &self,
+            account_id: &AccountId,
+            after: OpId,
+        ) -> Result<impl Iterator<Item = Result<Entry<AccountId>, BankError>>, BankError> {
+            self.storage.get_ops_after(account_id, after)
+        }
+
+        fn get_history(
+            &self,
+        ) -> Result<impl Iterator<Item = Result<Entry<AccountId>, BankError>>, BankError> {
+            self.storage.get_history()
+        }
+
+        fn get_history_after(
+            &self,
+            after: OpId,
+        ) -> Result<impl Iterator<Item = Result<Entry<AccountId>, BankError>>, BankError> {
+            self.storage.get_history_after(after)
+        }
+
+        fn last_op_id(&self) -> Result<OpId, BankError> {
+            self.storage.last_op_id()
+        }
+
+        fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+            self.storage.get_hash(op_id)
+        }
+
+        fn head_hash(&self) -> Result<ChainHash, BankError> {
+            self.storage.head_hash()
+        }
+
+        fn compact(&mut self, before: OpId) -> Result<History<AccountId>, BankError> {
+            self.storage.compact(before)
+        }
+    }
+
+    mod segmented {
+        use super::*;
+        use crate::bank::test::bank_test_suite;
+
+        bank_test_suite!(Bank::new(TempSegments::default(), InMemoryState::default()));
+    }
+
+    fn fill(dir: &Path, limits: SegmentLimits) -> History {
+        let mut bank: Bank<SegmentedOpsStorage, InMemoryState> = Bank::new(
+            SegmentedOpsStorage::open(dir, limits).unwrap(),
+            InMemoryState::default(),
+        );
+        for account_id in 128..132 {
+            let _ = bank.create_account(account_id);
+            let _ = bank.deposit(&account_id, NonZeroMoney::new(100).unwrap());
+        }
+        for _ in 0..5 {
+            let _ = bank.move_money(128, 129, NonZeroMoney::new(10).unwrap());
+        }
+        bank.get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn segmented_storage_should_rotate_and_compress_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = fill(dir.path(), SMALL);
+        assert_eq!(history.len(), 13);
+
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        let segments = storage.segments().unwrap();
+        //4 закрытых сегмента по 3 операции и открытый с последней
+        assert_eq!(segments.len(), 5);
+        assert!(segments[..4].iter().all(|s| s.sealed && s.ops == 3));
+        assert_eq!((segments[4].sealed, segments[4].ops), (false, 1));
+        assert!(segments
+            .windows(2)
+            .all(|pair| pair[0].last.checked_add(1) == Some(pair[1].first)));
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 5);
+        //при открытии читаются только заголовки
+        assert_eq!(storage.loads(), 0);
+
+        let restored: History = storage
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(restored, history);
+        assert_eq!(storage.loads(), 4);
+        assert_eq!(
+            storage.head_hash().unwrap(),
+            storage.get_hash(history[12].0).unwrap()
+        );
+
+        //закрытый сегмент меньше того же журнала без сжатия
+        let dir = tempfile::tempdir().unwrap();
+        let limits = SegmentLimits {
+            max_bytes: 1 << 20,
+            max_ops: 200,
+        };
+        let mut storage: SegmentedOpsStorage =
+            SegmentedOpsStorage::open(dir.path(), limits).unwrap();
+        let mut log: FileOpsStorage = FileOpsStorage::open(dir.path().join("plain.bin")).unwrap();
+        let _ = storage.persist(0, &Operation::Create(128));
+        let _ = log.persist(0, &Operation::Create(128));
+        for at in 1..200 {
+            let _ = storage.persist(at, &Operation::Deposit(128, NonZeroMoney::MIN));
+            let _ = log.persist(at, &Operation::Deposit(128, NonZeroMoney::MIN));
+        }
+        let segments = storage.segments().unwrap();
+        assert_eq!((segments[0].sealed, segments[0].ops), (true, 200));
+        //хэши цепочки не сжимаются, остальное сжимается хорошо
+        assert!(segments[0].bytes * 2 < log.len().unwrap());
+        assert_eq!(storage.get_ops(&128).unwrap().count(), 200);
+    }
+
+    #[test]
+    fn segmented_storage_should_read_only_needed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = fill(dir.path(), SMALL);
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+
+        //по индексу находится сегмент операции
+        let (op_id, ..) = history[4];
+        let segment = storage.segment_of(op_id).unwrap().unwrap();
+        assert!(segment.first <= op_id && op_id <= segment.last);
+
+        //операции после op_id: сегмент с ней и следующие, но не предыдущие
+        let after: Vec<_> = storage
+            .get_history_after(op_id)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(after, history[5..]);
+        assert_eq!(storage.loads(), 3);
+
+        //счёт 131 создан и пополнен в одном закрытом сегменте
+        let ops: Vec<_> = storage
+            .get_ops(&131)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<_> = history
+            .iter()
+            .filter(|(_, _, op)| op.account_ids().contains(&131))
+            .cloned()
+            .collect();
+        assert_eq!(ops, expected);
+        assert_eq!(storage.loads(), 4);
+        assert!(matches!(
+            storage.get_ops(&132).map(|ops| ops.count()),
+            Err(BankError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn segmented_storage_should_verify_chain_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = fill(dir.path(), SMALL);
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+
+        //Bank::verify_history проверяет цепочку так же: каждый закрытый сегмент распаковывается один раз
+        let report = crate::chain::verify(&storage).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.ops, history.len());
+        let sealed = storage
+            .segments()
+            .unwrap()
+            .iter()
+            .filter(|s| s.sealed)
+            .count();
+        assert_eq!(storage.loads(), sealed as u64);
+    }
+
+    #[test]
+    fn segmented_storage_should_restore_bank_and_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = fill(dir.path(), SMALL);
+
+        let mut bank: Bank<SegmentedOpsStorage, InMemoryState> = Bank::restore(
+            SegmentedOpsStorage::open(dir.path(), SMALL).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 50);
+        assert_eq!(bank.get_balance(&129).unwrap().balance, 150);
+        assert!(bank.verify_history().unwrap().is_intact());
+        let _ = bank.deposit(&130, NonZeroMoney::MIN);
+        drop(bank);
+
+        //архивируются два закрытых сегмента и часть третьего
+        let mut storage: SegmentedOpsStorage =
+            SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        let before = history[7].0;
+        let archived = storage.compact(before).unwrap();
+        assert_eq!(archived, history[..7]);
+        let segments = storage.segments().unwrap();
+        assert_eq!(segments[0].ops, 2);
+        assert_eq!(segments[0].first, history[6].0);
+        drop(storage);
+
+        let mut storage: SegmentedOpsStorage =
+            SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        let rest: History = storage
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rest[..6], history[7..]);
+        assert_eq!(rest.len(), 7);
+        assert!(crate::chain::verify(&storage).unwrap().is_intact());
+        assert_eq!(storage.get_ops(&130).unwrap().count(), 1);
+
+        //архивируются все закрытые сегменты и часть открытого, счета остаются известны
+        let (last, _, _) = rest[6];
+        let head = storage.head_hash().unwrap();
+        assert_eq!(storage.compact(last).unwrap(), rest[..6]);
+        drop(storage);
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        assert_eq!(storage.get_history().unwrap().count(), 1);
+        for account_id in [128, 129, 131] {
+            assert_eq!(storage.get_ops(&account_id).unwrap().count(), 0);
+        }
+        assert_eq!(storage.get_ops(&130).unwrap().count(), 1);
+        assert_eq!(storage.last_op_id().unwrap(), last);
+        assert_eq!(storage.head_hash().unwrap(), head);
+        assert!(crate::chain::verify(&storage).unwrap().is_intact());
+    }
+
+    #[test]
+    fn segmented_storage_should_remove_log_of_sealed_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = fill(dir.path(), SMALL);
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        let first = storage.segments().unwrap()[0].first;
+        drop(storage);
+
+        //падение после записи сжатого сегмента, но до удаления его журнала
+        std::fs::write(segment_path(dir.path(), first, "log"), b"stale").unwrap();
+        let storage: SegmentedOpsStorage = SegmentedOpsStorage::open(dir.path(), SMALL).unwrap();
+        assert!(!segment_path(dir.path(), first, "log").exists());
+        assert_eq!(storage.get_history().unwrap().count(), history.len());
+    }
+
+    #[test]
+    fn segmented_storage_should_not_truncate_active_log_after_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = SegmentLimits {
+            max_bytes: 1 << 20,
+            max_ops: 10,
+        };
+        let mut storage: SegmentedOpsStorage =
+            SegmentedOpsStorage::open(dir.path(), limits).unwrap();
+        let _ = storage.persist(0, &Operation::Create(128));
+        let _ = storage.persist(1, &Operation::Deposit(128, NonZeroMoney::MIN));
+        let _ = storage.persist(2, &Operation::Deposit(128, NonZeroMoney::MIN));
+        let first = storage.segments().unwrap()[0].first;
+        drop(storage);
+
+        //повреждена первая запись открытого сегмента, за ней - зафиксированные
+        let path = segment_path(dir.path(), first, "log");
+        let mut content = std::fs::read(&path).unwrap();
+        let (len, _) = decode_frame(&content).unwrap();
+        content[len - 1] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+
+        assert!(matches!(
+            SegmentedOpsStorage::<AccountId>::open(dir.path(), limits),
+            Err(BankError::CoreError(_))
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn segmented_storage_should_encrypt_segments_and_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = EncryptionKey::generate().unwrap();
+        let new = EncryptionKey::generate().unwrap();
+        let open = |keys: Keyring| {
+            SegmentedOpsStorage::<AccountId>::open_encrypted(dir.path(), SMALL, keys)
+        };
+
+        let mut bank: Bank<SegmentedOpsStorage, InMemoryState> = Bank::new(
+            open(Keyring::new(old.clone())).unwrap(),
+            InMemoryState::default(),
+        );
+        for account_id in 128..132 {
+            let _ = bank.create_account(account_id);
+        }
+        let history: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(bank);
+
+        assert!(matches!(
+            SegmentedOpsStorage::<AccountId>::open(dir.path(), SMALL),
+            Err(BankError::WrongKey(_))
+        ));
+        assert!(matches!(
+            open(Keyring::new(new.clone())),
+            Err(BankError::WrongKey(_))
+        ));
+
+        let mut storage = open(Keyring::new(new.clone()).with_previous(old.clone())).unwrap();
+        storage.rotate_key(new.clone()).unwrap();
+        drop(storage);
+        assert!(matches!(
+            open(Keyring::new(old)),
+            Err(BankError::WrongKey(_))
+        ));
+        let storage = open(Keyring::new(new)).unwrap();
+        assert!(storage.segments().unwrap()[0].sealed);
+        let restored: History = storage
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(restored, history);
+    }
+}
@@ -0,0 +1,850 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::Deref,
+    sync::{Arc, LockResult, Mutex, MutexGuard},
+};
+
+use serde::Serialize;
+
+use crate::{
+    audit::{AuditIssue, AuditReport},
+    bank::{
+        Bank, BankAccount, BankError, History, IdOf, Money, NonZeroMoney, OpId, Operation,
+        OpsStorage, SignedMoney, State,
+    },
+    clock::{Clock, MonotonicClock, SystemClock, Timestamp},
+    currency::Currency,
+    statement::Statement,
+};
+
+//банк из нескольких шардов: у каждого шарда свои хранилище и состояние,
+//счёт живёт в шарде, номер которого - хэш идентификатора счёта.
+//Операции одного шарда выполняются как в обычном банке и не ждут другие шарды.
+//Перевод между шардами выполняется в две фазы:
+//TransferOut списывает деньги в шарде отправителя, его OpId - номер перевода,
+//TransferIn с этим номером зачисляет их в шарде получателя,
+//а если зачисление не удалось, TransferCancel возвращает деньги отправителю.
+//Перевод, прерванный между фазами (например, падением процесса), завершается при restore.
+//Перевод, который не удалось ни завершить, ни отменить, запоминается и повторяется
+//при следующих обращениях к банку, не дожидаясь restore.
+//Все шарды идут по одним часам MonotonicClock, поэтому время операций не повторяется
+//и задаёт их общий порядок (см. get_history).
+//Комиссии и обмен валют между шардами не поддерживаются
+#[derive(Debug)]
+pub struct ShardedBank<T, S: State> {
+    shards: Vec<Mutex<Bank<T, S>>>,
+    //переводы, которые не удалось ни завершить, ни отменить; блокируется без шардов, см. settle
+    in_doubt: Mutex<Vec<Transfer<IdOf<S>>>>,
+}
+
+//списание перевода между шардами: шард отправителя и номер перевода - OpId списания в нём
+#[derive(Debug, Clone)]
+struct Transfer<Id> {
+    source: usize,
+    id: OpId,
+    from: Id,
+    to: Id,
+    amount: NonZeroMoney,
+}
+
+impl<Id: Clone> Transfer<Id> {
+    fn transfer_in(&self) -> Operation<Id> {
+        Operation::TransferIn {
+            id: self.id,
+            from: self.from.clone(),
+            to: self.to.clone(),
+            amount: self.amount,
+        }
+    }
+
+    fn cancel(&self) -> Operation<Id> {
+        Operation::TransferCancel {
+            id: self.id,
+            from: self.from.clone(),
+            to: self.to.clone(),
+            amount: self.amount,
+        }
+    }
+}
+
+type Shard<'a, T, S> = MutexGuard<'a, Bank<T, S>>;
+//шарды отправителя и получателя перевода
+type ShardPair<'a, T, S> = (Shard<'a, T, S>, Shard<'a, T, S>);
+
+fn locked<G>(lock: LockResult<G>) -> Result<G, BankError> {
+    lock.map_err(|_| BankError::CoreError("Shard lock is poisoned by a panic".to_owned()))
+}
+
+impl<T, S> ShardedBank<T, S>
+where
+    T: OpsStorage<Id = IdOf<S>>,
+    S: State,
+{
+    //шарды восстанавливаются по своим историям, порядок шардов должен сохраняться между запусками
+    pub fn restore(shards: Vec<(T, S)>) -> Result<ShardedBank<T, S>, BankError> {
+        Self::restore_with_clock(shards, SystemClock)
+    }
+
+    pub fn restore_with_clock<C: Clock + 'static>(
+        shards: Vec<(T, S)>,
+        clock: C,
+    ) -> Result<ShardedBank<T, S>, BankError> {
+        if shards.is_empty() {
+            return Err(BankError::BadRequest(
+                "A sharded bank needs at least one shard".to_owned(),
+            ));
+        }
+        let clock = Arc::new(MonotonicClock::new(clock));
+        let mut banks = Vec::with_capacity(shards.len());
+        for (storage, state) in shards {
+            let bank = Bank::restore(storage, state)?;
+            clock.advance_past(last_at(&bank)?);
+            banks.push(bank.with_clock(Arc::clone(&clock)));
+        }
+        let mut bank = ShardedBank {
+            shards: banks.into_iter().map(Mutex::new).collect(),
+            in_doubt: Mutex::new(Vec::new()),
+        };
+        bank.recover()?;
+        Ok(bank)
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_of(&self, account_id: &IdOf<S>) -> Result<usize, BankError> {
+        shard_of(account_id, self.shards.len())
+    }
+
+    fn shard(&self, account_id: &IdOf<S>) -> Result<Shard<'_, T, S>, BankError> {
+        self.settle()?;
+        locked(self.shards[self.shard_of(account_id)?].lock())
+    }
+
+    //шарды блокируются по возрастанию номеров, поэтому встречные переводы не ждут друг друга по кругу
+    fn lock_pair(&self, first: usize, second: usize) -> Result<ShardPair<'_, T, S>, BankError> {
+        if first < second {
+            let first = locked(self.shards[first].lock())?;
+            Ok((first, locked(self.shards[second].lock())?))
+        } else {
+            let second = locked(self.shards[second].lock())?;
+            Ok((locked(self.shards[first].lock())?, second))
+        }
+    }
+
+    //переводы, для которых есть TransferOut, но нет ни TransferIn, ни TransferCancel,
+    //доводятся до конца, а если получатель уже не может принять деньги - отменяются
+    fn recover(&mut self) -> Result<(), BankError> {
+        let shards = self.shards.len();
+        let mut banks = self
+            .shards
+            .iter_mut()
+            .map(|shard| locked(shard.get_mut()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (transfer, resolved) in transfers(&banks)?.into_values() {
+            let Some(transfer) = transfer.filter(|_| resolved == 0) else {
+                continue;
+            };
+            log::warn!(
+                "Transfer[{}] from account[{}] to account[{}] is in doubt, recovering",
+                transfer.id,
+                transfer.from,
+                transfer.to
+            );
+            let target = shard_of(&transfer.to, shards)?;
+            let [source, target] = banks
+                .get_disjoint_mut([transfer.source, target])
+                .map_err(|err| BankError::CoreError(format!("Transfer between shards: {err}")))?;
+            Self::complete(source, target, &transfer)?;
+        }
+        Ok(())
+    }
+
+    //зачисление списанного перевода, а если получатель уже не может принять деньги - отмена
+    fn complete(
+        source: &mut Bank<T, S>,
+        target: &mut Bank<T, S>,
+        transfer: &Transfer<IdOf<S>>,
+    ) -> Result<(), BankError> {
+        if let Err(err) = target.transact(vec![transfer.transfer_in()]) {
+            log::warn!(
+                "Transfer[{}] can't be completed: {err}, cancelling",
+                transfer.id
+            );
+            source.transact(vec![transfer.cancel()])?;
+        }
+        Ok(())
+    }
+
+    //переводы, которые не удалось ни завершить, ни отменить, повторяются перед операциями банка.
+    //Список забирается целиком, поэтому его блокировка не держится вместе с шардами
+    fn settle(&self) -> Result<(), BankError> {
+        let in_doubt = std::mem::take(&mut *locked(self.in_doubt.lock())?);
+        if in_doubt.is_empty() {
+            return Ok(());
+        }
+        let mut left = Vec::new();
+        for transfer in in_doubt {
+            let settled = self.shard_of(&transfer.to).and_then(|target| {
+                let (mut source, mut target) = self.lock_pair(transfer.source, target)?;
+                Self::complete(&mut source, &mut target, &transfer)
+            });
+            if let Err(err) = settled {
+                log::warn!("Transfer[{}] is still in doubt: {err}", transfer.id);
+                left.push(transfer);
+            }
+        }
+        locked(self.in_doubt.lock())?.extend(left);
+        Ok(())
+    }
+
+    //перевод между шардами двумя транзакциями, source и target должны быть уже проверены.
+    //Если не удалась и отмена, перевод запоминается и повторяется при следующих обращениях (см. settle)
+    fn transfer(
+        &self,
+        (source_shard, source): (usize, &mut Bank<T, S>),
+        target: &mut Bank<T, S>,
+        from: IdOf<S>,
+        to: IdOf<S>,
+        amount: NonZeroMoney,
+    ) -> Result<(), BankError> {
+        let id = source
+            .transact(vec![Operation::TransferOut {
+                from: from.clone(),
+                to: to.clone(),
+                amount,
+            }])?
+            .first()
+            .copied()
+            .ok_or_else(|| {
+                BankError::CoreError("Storage returned no OpId for a transfer".to_owned())
+            })?;
+        let transfer = Transfer {
+            source: source_shard,
+            id,
+            from,
+            to,
+            amount,
+        };
+        if let Err(err) = target.transact(vec![transfer.transfer_in()]) {
+            if let Err(cancel) = source.transact(vec![transfer.cancel()]) {
+                locked(self.in_doubt.lock())?.push(transfer);
+                return Err(BankError::CoreError(format!(
+                    "Transfer[{id}] is in doubt and will be retried: {err}, {cancel}"
+                )));
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    //перед списанием проверяется, что получатель в другом шарде примет деньги
+    fn prepare(
+        source: &Bank<T, S>,
+        target: &Bank<T, S>,
+        ops: &[Operation<IdOf<S>>],
+        from: &IdOf<S>,
+        to: &IdOf<S>,
+        amount: NonZeroMoney,
+    ) -> Result<(), BankError> {
+        let currencies = (
+            source.get_balance(from)?.currency(),
+            target.get_balance(to)?.currency(),
+        );
+        if currencies.0 != currencies.1 {
+            return Err(BankError::BadRequest(format!(
+                "Exchange from {} to {} between shards is not supported",
+                currencies.0, currencies.1
+            )));
+        }
+        source.validate(ops)?;
+        target.validate(&[Operation::TransferIn {
+            id: OpId::MIN,
+            from: from.clone(),
+            to: to.clone(),
+            amount,
+        }])
+    }
+
+    pub fn create_account(&self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?.create_account(account_id)
+    }
+
+    pub fn create_account_in(
+        &self,
+        account_id: IdOf<S>,
+        currency: Currency,
+    ) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?
+            .create_account_in(account_id, currency)
+    }
+
+    pub fn get_balance(&self, account_id: &IdOf<S>) -> Result<S::Account, BankError> {
+        self.shard(account_id)?.get_balance(account_id)
+    }
+
+    //op_id - операция шарда счёта
+    pub fn get_balance_at(
+        &self,
+        account_id: &IdOf<S>,
+        op_id: OpId,
+    ) -> Result<S::Account, BankError> {
+        self.shard(account_id)?.get_balance_at(account_id, op_id)
+    }
+
+    pub fn deposit(
+        &self,
+        account_id: &IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<S::Account, BankError> {
+        self.shard(account_id)?.deposit(account_id, money)
+    }
+
+    pub fn withdraw(
+        &self,
+        account_id: IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?.withdraw(account_id, money)
+    }
+
+    pub fn set_credit_limit(
+        &self,
+        account_id: IdOf<S>,
+        limit: Money,
+    ) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?.set_credit_limit(account_id, limit)
+    }
+
+    pub fn freeze_account(&self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?.freeze_account(account_id)
+    }
+
+    pub fn unfreeze_account(&self, account_id: IdOf<S>) -> Result<S::Account, BankError> {
+        self.shard(&account_id)?.unfreeze_account(account_id)
+    }
+
+    pub fn move_money(
+        &self,
+        from: IdOf<S>,
+        to: IdOf<S>,
+        money: NonZeroMoney,
+    ) -> Result<(S::Account, S::Account), BankError> {
+        if from == to {
+            return Err(BankError::Prohibited(format!(
+                "Sending funds to yourself[{to}] is prohibited"
+            )));
+        }
+        self.settle()?;
+        let (source_shard, target) = (self.shard_of(&from)?, self.shard_of(&to)?);
+        if source_shard == target {
+            return locked(self.shards[source_shard].lock())?.move_money(from, to, money);
+        }
+
+        let (mut source, mut target) = self.lock_pair(source_shard, target)?;
+        let transfer_out = Operation::TransferOut {
+            from: from.clone(),
+            to: to.clone(),
+            amount: money,
+        };
+        Self::prepare(&source, &target, &[transfer_out], &from, &to, money)?;
+        self.transfer(
+            (source_shard, &mut source),
+            &mut target,
+            from.clone(),
+            to.clone(),
+            money,
+        )?;
+        Ok((source.get_balance(&from)?, target.get_balance(&to)?))
+    }
+
+    //остаток переводится на payout в другом шарде до закрытия счёта отдельной транзакцией
+    pub fn close_account(
+        &self,
+        account_id: IdOf<S>,
+        payout: Option<IdOf<S>>,
+    ) -> Result<S::Account, BankError> {
+        self.settle()?;
+        let source_shard = self.shard_of(&account_id)?;
+        let target = match &payout {
+            Some(to) => self.shard_of(to)?,
+            None => source_shard,
+        };
+        if source_shard == target {
+            return locked(self.shards[source_shard].lock())?.close_account(account_id, payout);
+        }
+
+        let (mut source, mut target) = self.lock_pair(source_shard, target)?;
+        let funds = NonZeroMoney::new(source.get_balance(&account_id)?.funds());
+        let (Some(funds), Some(to)) = (funds, payout) else {
+            return source.close_account(account_id, None);
+        };
+        let ops = [
+            Operation::TransferOut {
+                from: account_id.clone(),
+                to: to.clone(),
+                amount: funds,
+            },
+            Operation::Close(account_id.clone()),
+        ];
+        Self::prepare(&source, &target, &ops, &account_id, &to, funds)?;
+        self.transfer(
+            (source_shard, &mut source),
+            &mut target,
+            account_id.clone(),
+            to,
+            funds,
+        )?;
+        source.transact(vec![Operation::Close(account_id.clone())])?;
+        source.get_balance(&account_id)
+    }
+
+    //op_id в выписке - операции шарда счёта
+    pub fn statement(
+        &self,
+        account_id: &IdOf<S>,
+        from: OpId,
+        to: OpId,
+    ) -> Result<Statement<S::Account>, BankError> {
+        self.shard(account_id)?.statement(account_id, from, to)
+    }
+
+    //история счёта читается целиком, потому что шард нельзя держать заблокированным
+    pub fn get_account_ops(&self, account_id: &IdOf<S>) -> Result<History<IdOf<S>>, BankError> {
+        let shard = self.shard(account_id)?;
+        let ops = shard.get_account_ops(account_id)?.collect();
+        ops
+    }
+
+    //история всего банка: истории шардов, слитые по времени операций.
+    //OpId остаются номерами в своих шардах и могут повторяться,
+    //но порядок операций годится для Bank::from
+    pub fn get_history(&self) -> Result<History<IdOf<S>>, BankError> {
+        let shards = self.lock_all()?;
+        let mut history = Vec::new();
+        for (shard, bank) in shards.iter().enumerate() {
+            for entry in bank.get_history()? {
+                history.push((shard, entry?));
+            }
+        }
+        history.sort_by_key(|(shard, (op_id, at, _))| (*at, *shard, *op_id));
+        Ok(history.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    //каждый шард сверяется со своей историей: деньги перевода между шардами
+    //уходят из одного шарда как снятие и приходят в другой как пополнение.
+    //Затем каждое списание сопоставляется ровно с одним зачислением или отменой в истории шардов
+    pub fn audit(&self) -> Result<AuditReport<S::Account>, BankError>
+    where
+        <S::Account as BankAccount>::Balance: Into<SignedMoney>,
+    {
+        let shards = self.lock_all()?;
+        let mut report = AuditReport {
+            ops: 0,
+            accounts: 0,
+            issues: Vec::new(),
+        };
+        for bank in shards.iter() {
+            let shard = bank.audit()?;
+            report.ops += shard.ops;
+            report.accounts += shard.accounts;
+            report.issues.extend(shard.issues);
+        }
+        for ((shard, id), (transfer, resolved)) in transfers(&shards)? {
+            if transfer.is_none() || resolved != 1 {
+                report
+                    .issues
+                    .push(AuditIssue::UnpairedTransfer { shard, id });
+            }
+        }
+        Ok(report)
+    }
+
+    fn lock_all(&self) -> Result<Vec<Shard<'_, T, S>>, BankError> {
+        self.settle()?;
+        self.shards
+            .iter()
+            .map(|shard| locked(shard.lock()))
+            .collect()
+    }
+}
+
+//переводы между шардами по (шарду отправителя, номеру перевода): списание, если оно есть в истории,
+//и сколько раз перевод завершён зачислением или отменой.
+//Номер перевода - OpId в шарде отправителя, поэтому он уникален только вместе с шардом
+type Transfers<Id> = BTreeMap<(usize, OpId), (Option<Transfer<Id>>, usize)>;
+
+fn transfers<T, S>(
+    banks: &[impl Deref<Target = Bank<T, S>>],
+) -> Result<Transfers<IdOf<S>>, BankError>
+where
+    T: OpsStorage<Id = IdOf<S>>,
+    S: State,
+{
+    let mut transfers: Transfers<IdOf<S>> = BTreeMap::new();
+    for (shard, bank) in banks.iter().enumerate() {
+        for entry in bank.get_history()? {
+            match entry? {
+                (id, _, Operation::TransferOut { from, to, amount }) => {
+                    transfers.entry((shard, id)).or_default().0 = Some(Transfer {
+                        source: shard,
+                        id,
+                        from,
+                        to,
+                        amount,
+                    })
+                }
+                (_, _, Operation::TransferIn { id, from, .. })
+                | (_, _, Operation::TransferCancel { id, from, .. }) => {
+                    let source = shard_of(&from, banks.len())?;
+                    transfers.entry((source, id)).or_default().1 += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(transfers)
+}
+
+//хэш закодированного идентификатора не зависит от запуска, в отличие от Hash из std
+fn shard_of<Id: Serialize + Display>(account_id: &Id, shards: usize) -> Result<usize, BankError> {
+    let encoded = bincode::serialize(account_id).map_err(|err| {
+        BankError::CoreError(format!("Can't encode account_id[{account_id}]: {err}"))
+    })?;
+    Ok(crc32fast::hash(&encoded) as usize % shards)
+}
+
+//время последней операции шарда, 0 - если операций не было
+fn last_at<T, S>(bank: &Bank<T, S>) -> Result<Timestamp, BankError>
+where
+    T: OpsStorage<Id = IdOf<S>>,
+    S: State,
+{
+    bank.get_history()?
+        .try_fold(0, |last, entry| entry.map(|(_, at, _)| last.max(at)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, collections::HashSet, path::Path, rc::Rc, thread};
+
+    use super::*;
+    use crate::{
+        bank::{Account, AccountId, AccountStatus, Entry, InMemoryOpsStorage, InMemoryState},
+        chain::ChainHash,
+        clock::ManualClock,
+        file_storage::FileOpsStorage,
+    };
+
+    type MemoryBank = ShardedBank<InMemoryOpsStorage, InMemoryState>;
+    type FileBank = ShardedBank<FileOpsStorage, InMemoryState>;
+
+    fn memory_bank(shards: usize) -> MemoryBank {
+        let shards = (0..shards)
+            .map(|_| (InMemoryOpsStorage::default(), InMemoryState::default()))
+            .collect();
+        ShardedBank::restore(shards).unwrap()
+    }
+
+    fn file_bank(dir: &Path) -> FileBank {
+        let shards = (0..2)
+            .map(|shard| {
+                let storage = FileOpsStorage::open(dir.join(format!("shard{shard}.bin"))).unwrap();
+                (storage, InMemoryState::default())
+            })
+            .collect();
+        ShardedBank::restore_with_clock(shards, ManualClock::new(1_000)).unwrap()
+    }
+
+    //первый счёт не меньше start, который живёт в шарде shard
+    fn account_in(shard: usize, shards: usize, start: AccountId) -> AccountId {
+        (start..)
+            .find(|account_id| shard_of(account_id, shards).unwrap() == shard)
+            .unwrap()
+    }
+
+    fn next(seed: &mut u64) -> u64 {
+        *seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        *seed >> 33
+    }
+
+    #[test]
+    fn sharded_bank_should_keep_money_in_parallel_transfers() {
+        const ACCOUNTS: AccountId = 16;
+        let bank = memory_bank(4);
+        for account_id in 0..ACCOUNTS {
+            let _ = bank.create_account(account_id).unwrap();
+            let _ = bank.deposit(&account_id, NonZeroMoney::new(1_000).unwrap());
+        }
+        let shards: HashSet<_> = (0..ACCOUNTS)
+            .map(|account_id| bank.shard_of(&account_id).unwrap())
+            .collect();
+        assert_eq!(shards.len(), 4);
+
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let bank = &bank;
+                scope.spawn(move || {
+                    let mut seed = thread;
+                    for _ in 0..200 {
+                        let from = (next(&mut seed) % ACCOUNTS as u64) as AccountId;
+                        let to = (next(&mut seed) % ACCOUNTS as u64) as AccountId;
+                        let money = NonZeroMoney::new(next(&mut seed) % 300 + 1).unwrap();
+                        //недостаточно средств или перевод самому себе - ожидаемые отказы
+                        let _ = bank.move_money(from, to, money);
+                    }
+                });
+            }
+        });
+
+        let balances: Vec<_> = (0..ACCOUNTS)
+            .map(|account_id| bank.get_balance(&account_id).unwrap())
+            .collect();
+        assert_eq!(
+            balances.iter().map(|account| account.balance).sum::<i128>(),
+            1_000 * ACCOUNTS as i128
+        );
+        let report = bank.audit().unwrap();
+        assert_eq!(report.issues, vec![]);
+        assert_eq!(report.accounts, ACCOUNTS as usize);
+
+        //слитая история восстанавливает те же счета в одном банке
+        let history = bank.get_history().unwrap();
+        assert_eq!(report.ops, history.len());
+        assert!(history.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        let restored: Bank<InMemoryOpsStorage, InMemoryState> =
+            Bank::from(history.into_iter()).unwrap();
+        for account in &balances {
+            assert_eq!(restored.get_balance(&account.account_id).unwrap(), *account);
+        }
+        assert!(restored.audit().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn sharded_bank_should_reject_transfer_before_withdrawal() {
+        let bank = memory_bank(2);
+        let from = account_in(0, 2, 128);
+        let to = account_in(1, 2, 128);
+        let usd = account_in(1, 2, to + 1);
+        let _ = bank.create_account(from);
+        let _ = bank.create_account(to);
+        let _ = bank.create_account_in(usd, Currency::USD);
+        let _ = bank.deposit(&from, NonZeroMoney::new(100).unwrap());
+
+        let (sender, receiver) = bank
+            .move_money(from, to, NonZeroMoney::new(30).unwrap())
+            .unwrap();
+        assert_eq!((sender.balance, receiver.balance), (70, 30));
+        let ops = bank.get_account_ops(&to).unwrap();
+        assert!(matches!(
+            ops.last(),
+            Some((_, _, Operation::TransferIn { from: sender, .. })) if *sender == from
+        ));
+
+        //получатель заморожен или в другой валюте: деньги не списываются
+        let _ = bank.freeze_account(to);
+        let ops = bank.get_history().unwrap().len();
+        assert!(matches!(
+            bank.move_money(from, to, NonZeroMoney::new(10).unwrap()),
+            Err(BankError::Prohibited(_))
+        ));
+        assert!(matches!(
+            bank.move_money(from, usd, NonZeroMoney::new(10).unwrap()),
+            Err(BankError::BadRequest(_))
+        ));
+        assert!(matches!(
+            bank.move_money(from, from, NonZeroMoney::new(10).unwrap()),
+            Err(BankError::Prohibited(_))
+        ));
+        assert_eq!(bank.get_history().unwrap().len(), ops);
+        assert_eq!(bank.get_balance(&from).unwrap().balance, 70);
+
+        //остаток закрываемого счёта уходит в другой шард
+        let _ = bank.unfreeze_account(to);
+        let closed = bank.close_account(from, Some(to)).unwrap();
+        assert_eq!((closed.balance, closed.status), (0, AccountStatus::Closed));
+        assert_eq!(bank.get_balance(&to).unwrap().balance, 100);
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn sharded_bank_should_complete_interrupted_transfer_at_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = account_in(0, 2, 128);
+        let to = account_in(1, 2, 128);
+        {
+            let bank = file_bank(dir.path());
+            let _ = bank.create_account(from);
+            let _ = bank.create_account(to);
+            let _ = bank.deposit(&from, NonZeroMoney::new(100).unwrap());
+        }
+        //процесс упал после списания в шарде отправителя
+        let id = {
+            let storage = FileOpsStorage::open(dir.path().join("shard0.bin")).unwrap();
+            let mut shard: Bank<_, InMemoryState> =
+                Bank::restore(storage, InMemoryState::default()).unwrap();
+            shard
+                .transact(vec![Operation::TransferOut {
+                    from,
+                    to,
+                    amount: NonZeroMoney::new(40).unwrap(),
+                }])
+                .unwrap()[0]
+        };
+
+        let bank = file_bank(dir.path());
+        assert_eq!(bank.get_balance(&from).unwrap().balance, 60);
+        assert_eq!(bank.get_balance(&to).unwrap().balance, 40);
+        let ops = bank.get_account_ops(&to).unwrap();
+        assert!(matches!(
+            ops.last(),
+            Some((_, _, Operation::TransferIn { id: transfer, .. })) if *transfer == id
+        ));
+        assert!(bank.audit().unwrap().is_consistent());
+        drop(bank);
+
+        //завершённый перевод не повторяется
+        let bank = file_bank(dir.path());
+        assert_eq!(bank.get_history().unwrap().len(), ops.len() + 3);
+        assert_eq!(bank.get_balance(&to).unwrap().balance, 40);
+    }
+
+    #[test]
+    fn sharded_bank_should_cancel_interrupted_transfer_to_frozen_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = account_in(0, 2, 128);
+        let to = account_in(1, 2, 128);
+        {
+            let bank = file_bank(dir.path());
+            let _ = bank.create_account(from);
+            let _ = bank.create_account(to);
+            let _ = bank.deposit(&from, NonZeroMoney::new(100).unwrap());
+            let _ = bank.freeze_account(to);
+        }
+        let id = {
+            let storage = FileOpsStorage::open(dir.path().join("shard0.bin")).unwrap();
+            let mut shard: Bank<_, InMemoryState> =
+                Bank::restore(storage, InMemoryState::default()).unwrap();
+            shard
+                .transact(vec![Operation::TransferOut {
+                    from,
+                    to,
+                    amount: NonZeroMoney::new(40).unwrap(),
+                }])
+                .unwrap()[0]
+        };
+
+        let bank = file_bank(dir.path());
+        assert_eq!(bank.get_balance(&from).unwrap().balance, 100);
+        assert_eq!(
+            bank.get_balance(&to).unwrap(),
+            Account {
+                account_id: to,
+                status: AccountStatus::Frozen,
+                ..Account::default()
+            }
+        );
+        let ops = bank.get_account_ops(&from).unwrap();
+        assert!(matches!(
+            ops.last(),
+            Some((_, _, Operation::TransferCancel { id: transfer, .. })) if *transfer == id
+        ));
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    //хранилище, которое после writes удачных записей (общих для всех шардов) отказывает, как сбойный диск
+    #[derive(Debug)]
+    struct FlakyDisk {
+        ops: InMemoryOpsStorage,
+        writes: Rc<Cell<Option<usize>>>,
+    }
+
+    impl OpsStorage for FlakyDisk {
+        type Id = AccountId;
+
+        fn transact(&mut self, at: Timestamp, ops: &[Operation]) -> Result<Vec<OpId>, BankError> {
+            match self.writes.get() {
+                Some(0) => return Err(BankError::CoreError("I/O error".to_owned())),
+                Some(writes) => self.writes.set(Some(writes - 1)),
+                None => {}
+            }
+            self.ops.transact(at, ops)
+        }
+
+        fn get_ops(
+            &self,
+            account_id: &AccountId,
+        ) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_ops(account_id)
+        }
+
+        fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry, BankError>>, BankError> {
+            self.ops.get_history()
+        }
+
+        fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+            self.ops.get_hash(op_id)
+        }
+
+        fn head_hash(&self) -> Result<ChainHash, BankError> {
+            self.ops.head_hash()
+        }
+    }
+
+    #[test]
+    fn sharded_bank_should_retry_transfer_in_doubt() {
+        let writes = Rc::new(Cell::new(None));
+        let shards = (0..2)
+            .map(|_| {
+                let storage = FlakyDisk {
+                    ops: InMemoryOpsStorage::default(),
+                    writes: writes.clone(),
+                };
+                (storage, InMemoryState::default())
+            })
+            .collect();
+        let bank: ShardedBank<FlakyDisk, InMemoryState> = ShardedBank::restore(shards).unwrap();
+        let from = account_in(0, 2, 128);
+        let to = account_in(1, 2, 128);
+        let _ = bank.create_account(from);
+        let _ = bank.create_account(to);
+        let _ = bank.deposit(&from, NonZeroMoney::new(100).unwrap());
+
+        //списание записано, а зачисление и отмена - нет
+        writes.set(Some(1));
+        assert!(matches!(
+            bank.move_money(from, to, NonZeroMoney::new(40).unwrap()),
+            Err(BankError::CoreError(_))
+        ));
+        //пока диск сбоит, перевод остаётся в списке и виден сверке
+        let report = bank.audit().unwrap();
+        assert!(matches!(
+            report.issues[..],
+            [AuditIssue::UnpairedTransfer { shard: 0, .. }]
+        ));
+        assert_eq!(bank.in_doubt.lock().unwrap().len(), 1);
+
+        //диск восстановился: перевод завершается при следующем обращении, без restore
+        writes.set(None);
+        assert_eq!(bank.get_balance(&to).unwrap().balance, 40);
+        assert_eq!(bank.get_balance(&from).unwrap().balance, 60);
+        assert!(bank.in_doubt.lock().unwrap().is_empty());
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn sharded_bank_should_need_a_shard() {
+        assert!(matches!(
+            MemoryBank::restore(Vec::new()),
+            Err(BankError::BadRequest(_))
+        ));
+        let bank = memory_bank(1);
+        assert_eq!(bank.shards(), 1);
+        assert_eq!(bank.shard_of(&u128::MAX).unwrap(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bank::{Account, BankAccount, BankError, InMemoryState, OpId},
+    file_storage::{decode_record, encode_record, replace_file},
+    idempotency::{Receipt, Receipts, RequestFingerprint, RequestKey},
+};
+
+//снимок состояния счетов банка, помеченный последней применённой операцией.
+//Для восстановления банка достаточно снимка и операций из хранилища после last_op_id.
+//Квитанции запросов с ключами тоже в снимке: их операции могут быть уже архивированы
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "A: BankAccount")]
+pub struct Snapshot<A: BankAccount = Account> {
+    pub last_op_id: OpId,
+    pub accounts: Vec<A>,
+    //от самой старой к самой новой
+    pub receipts: Vec<(RequestKey, RequestFingerprint, Receipt<A>)>,
+}
+
+impl<A: BankAccount> Snapshot<A> {
+    pub fn new(last_op_id: OpId, state: &InMemoryState<A>, receipts: &Receipts<A>) -> Snapshot<A> {
+        let mut accounts: Vec<A> = state.0.values().cloned().collect();
+        accounts.sort_by(|a, b| a.account_id().cmp(b.account_id()));
+        Snapshot {
+            last_op_id,
+            accounts,
+            receipts: receipts
+                .entries()
+                .map(|(key, fingerprint, receipt)| (key, fingerprint, receipt.clone()))
+                .collect(),
+        }
+    }
+
+    //снимок записывается атомарно, в том же формате записи с контрольной суммой, что и FileOpsStorage
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), BankError> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, self)?;
+        replace_file(path.as_ref(), &buf)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Snapshot<A>, BankError> {
+        let buf = std::fs::read(path.as_ref())?;
+        match decode_record(&buf) {
+            Ok(Some((len, snapshot))) if len == buf.len() => Ok(snapshot),
+            _ => Err(BankError::CoreError(format!(
+                "Snapshot[{}] is damaged",
+                path.as_ref().display()
+            ))),
+        }
+    }
+}
+
+impl<A: BankAccount> From<Snapshot<A>> for InMemoryState<A> {
+    fn from(snapshot: Snapshot<A>) -> Self {
+        snapshot.accounts.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bank::{AccountId, Bank, History, InMemoryOpsStorage, NonZeroMoney, OpsStorage},
+        chain::ChainHash,
+        file_storage::FileOpsStorage,
+        idempotency,
+    };
+
+    fn fill<T: OpsStorage<Id = AccountId>>(bank: &mut Bank<T, InMemoryState>) {
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        let _ = bank.move_money(128, 129, NonZeroMoney::new(12).unwrap());
+    }
+
+    #[test]
+    fn bank_should_restore_from_snapshot_and_tail() {
+        let mut bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+        fill(&mut bank);
+        let snapshot = bank.snapshot().unwrap();
+
+        let _ = bank.create_account(130);
+        let _ = bank.move_money(129, 130, NonZeroMoney::new(2).unwrap());
+
+        let history: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut storage = InMemoryOpsStorage::default();
+        let mut hash = ChainHash::GENESIS;
+        for (op_id, at, op) in history {
+            hash = hash.link(op_id, at, &op).unwrap();
+            storage.restore(op_id, at, op, hash).unwrap();
+        }
+
+        let restored = Bank::from_snapshot(storage, snapshot).unwrap();
+        for account_id in [128, 129, 130] {
+            assert_eq!(
+                restored.get_balance(&account_id),
+                bank.get_balance(&account_id)
+            );
+        }
+    }
+
+    #[test]
+    fn bank_should_keep_receipts_after_restart_from_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let ops_path = dir.path().join("bank.ops");
+        let snapshot_path = dir.path().join("bank.snapshot");
+        let deposit = |bank: &mut Bank<FileOpsStorage, InMemoryState>| {
+            bank.deposit(&128, NonZeroMoney::MIN).map(drop)
+        };
+        let fingerprint = idempotency::fingerprint(&("deposit", 128)).unwrap();
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&ops_path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let receipt = bank.idempotent(1, fingerprint, deposit).cloned().unwrap();
+        let _ = bank.deposit(&129, NonZeroMoney::MIN);
+        //квитанция до снимка, её операции архивируются
+        let snapshot = bank.snapshot().unwrap();
+        snapshot.write(&snapshot_path).unwrap();
+        let _ = bank.compact(&snapshot).unwrap();
+        drop(bank);
+
+        let mut bank = Bank::from_snapshot(
+            FileOpsStorage::open(&ops_path).unwrap(),
+            Snapshot::read(&snapshot_path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(bank.receipt_of(&1), Some(&receipt));
+        //повтор не выполняется, другой запрос с тем же ключом - ошибка
+        assert_eq!(bank.idempotent(1, fingerprint, deposit), Ok(&receipt));
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 31);
+        assert!(bank.idempotent(1, fingerprint + 1, deposit).is_err());
+    }
+
+    #[test]
+    fn bank_should_compact_operations_before_snapshot() {
+        let mut bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+        fill(&mut bank);
+        let snapshot = bank.snapshot().unwrap();
+        let _ = bank.deposit(&129, NonZeroMoney::MIN);
+        let head = bank.head_hash().unwrap();
+
+        let archived = bank.compact(&snapshot).unwrap();
+        //Create + Create + Deposit, Transfer помечен снимком и остаётся
+        assert_eq!(archived.len(), 3);
+        assert!(archived
+            .iter()
+            .all(|(op_id, _, _)| *op_id < snapshot.last_op_id));
+
+        assert_eq!(bank.get_history().unwrap().count(), 2);
+        //у счёта 128 остался только перевод, остальные операции в архиве
+        assert_eq!(bank.get_account_ops(&128).unwrap().count(), 1);
+        assert_eq!(bank.get_account_ops(&129).unwrap().count(), 2);
+        assert!(bank.get_account_ops(&130).is_err());
+        //начало истории счёта в архиве, баланс в прошлом не восстановить
+        assert!(bank.get_balance_at(&128, snapshot.last_op_id).is_err());
+        //цепочка проверяется от первой оставшейся операции, голова не меняется
+        let report = bank.verify_history().unwrap();
+        assert_eq!(report.ops, 2);
+        assert_eq!(report.head, head);
+        assert!(report.is_intact());
+
+        //новые операции продолжают нумерацию
+        let _ = bank.deposit(&128, NonZeroMoney::MIN);
+        let ops: Vec<OpId> = bank
+            .get_account_ops(&128)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(ops.len(), 2);
+        assert!(ops[1] > snapshot.last_op_id);
+    }
+
+    #[test]
+    fn file_bank_should_restart_from_snapshot_after_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let ops_path = dir.path().join("bank.ops");
+        let snapshot_path = dir.path().join("bank.snapshot");
+
+        let mut bank = Bank::new(
+            FileOpsStorage::open(&ops_path).unwrap(),
+            InMemoryState::default(),
+        );
+        fill(&mut bank);
+        let snapshot = bank.snapshot().unwrap();
+        snapshot.write(&snapshot_path).unwrap();
+        let _ = bank.compact(&snapshot).unwrap();
+        let _ = bank.deposit(&128, NonZeroMoney::new(100).unwrap());
+        let head = bank.head_hash().unwrap();
+        drop(bank);
+
+        let snapshot: Snapshot = Snapshot::read(&snapshot_path).unwrap();
+        let mut bank =
+            Bank::from_snapshot(FileOpsStorage::open(&ops_path).unwrap(), snapshot).unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 130);
+        assert_eq!(bank.get_balance(&129).unwrap().balance, 12);
+        assert_eq!(bank.get_history().unwrap().count(), 2);
+        assert_eq!(bank.head_hash(), Ok(head));
+        assert!(bank.verify_history().unwrap().is_intact());
+
+        let last = bank.get_history().unwrap().last().unwrap().unwrap().0;
+        let _ = bank.deposit(&129, NonZeroMoney::MIN);
+        assert!(bank.get_history().unwrap().last().unwrap().unwrap().0 > last);
+    }
+
+    #[test]
+    fn snapshot_should_detect_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.snapshot");
+
+        let mut bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default());
+        fill(&mut bank);
+        let snapshot = bank.snapshot().unwrap();
+        snapshot.write(&path).unwrap();
+        assert_eq!(Snapshot::read(&path), Ok(snapshot));
+
+        let mut content = std::fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+        assert!(Snapshot::<Account>::read(&path).is_err());
+    }
+}
@@ -0,0 +1,1212 @@
+use std::{marker::PhantomData, path::Path};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    bank::{
+        Account, AccountId, BankAccount, BankAccountId, BankError, Entry, History,
+        InMemoryOpsStorage, InMemoryState, OpId, Operation, OpsStorage, Prior, State,
+    },
+    chain::ChainHash,
+    clock::Timestamp,
+    crypto::{self, EncryptionKey, Keyring},
+};
+
+impl From<rusqlite::Error> for BankError {
+    fn from(err: rusqlite::Error) -> Self {
+        BankError::CoreError(format!("SQLite error: {err}"))
+    }
+}
+
+//идентификаторы операций u128 не помещаются в INTEGER, поэтому хранятся как BLOB в big-endian,
+//такой BLOB сортируется так же, как число.
+//Идентификаторы и данные счетов могут быть любого типа и хранятся в bincode.
+//Время операции at - миллисекунды Unix, hash - хэш цепочки операций (см. chain).
+//account_id операции - её основной счёт, все её счета (у перевода - оба) в operation_accounts.
+//known_accounts - счета, которые были в истории, в том числе архивированной (см. OpsStorage::compact),
+//compaction - последняя операция и её хэш на момент архивирования
+const OPS_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS operations (
+        op_id BLOB PRIMARY KEY,
+        account_id BLOB NOT NULL,
+        payload BLOB NOT NULL,
+        at INTEGER NOT NULL DEFAULT 0,
+        hash BLOB
+    );
+    CREATE TABLE IF NOT EXISTS operation_accounts (
+        account_id BLOB NOT NULL,
+        op_id BLOB NOT NULL,
+        PRIMARY KEY (account_id, op_id)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS known_accounts (
+        account_id BLOB PRIMARY KEY
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS compaction (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        op_id BLOB NOT NULL,
+        hash BLOB NOT NULL
+    );
+";
+
+const STATE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        account_id BLOB PRIMARY KEY,
+        payload BLOB NOT NULL
+    );
+";
+
+fn decode_u128(bytes: &[u8]) -> Result<u128, BankError> {
+    bytes
+        .try_into()
+        .map(u128::from_be_bytes)
+        .map_err(|_| BankError::CoreError(format!("Bad u128 value in database[{:?}]", bytes)))
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BankError> {
+    bincode::serialize(value)
+        .map_err(|err| BankError::CoreError(format!("Can't encode value for database: {err}")))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BankError> {
+    bincode::deserialize(bytes)
+        .map_err(|err| BankError::CoreError(format!("Can't decode value from database: {err}")))
+}
+
+fn decode_hash(bytes: &[u8]) -> Result<ChainHash, BankError> {
+    bytes
+        .try_into()
+        .map(ChainHash)
+        .map_err(|_| BankError::CoreError(format!("Bad hash in database[{:?}]", bytes)))
+}
+
+//стёртый хэш операции не совпадёт ни с одним звеном цепочки, поэтому chain::verify укажет на неё
+fn stored_hash(hash: Option<Vec<u8>>) -> Result<ChainHash, BankError> {
+    hash.map_or(Ok(ChainHash::GENESIS), |hash| decode_hash(&hash))
+}
+
+fn decode_op_id(bytes: &[u8]) -> Result<OpId, BankError> {
+    OpId::new(decode_u128(bytes)?)
+        .ok_or_else(|| BankError::CoreError("OpId in database can't be zero".to_owned()))
+}
+
+//реализация хранилища операций банка в SQLite (в файле или в `:memory:`).
+//Операции в памяти не держатся: история и операции счёта читаются запросами к базе страницами.
+//В памяти только идентификатор и хэш последней операции, по ним назначаются идентификаторы и хэши новых.
+//С ключами (open_encrypted) payload операции шифруется вместе с её op_id (см. crypto),
+//колонки op_id, account_id, at и hash остаются открытыми
+#[derive(Debug)]
+pub struct SqliteOpsStorage<Id = AccountId> {
+    conn: Connection,
+    keys: Option<Keyring>,
+    last: OpId,
+    head: ChainHash,
+    ids: PhantomData<Id>,
+}
+
+//операции читаются страницами по OpId, поэтому обход не держит открытым запрос к базе
+const OPS_PAGE: usize = 256;
+
+//версия схемы в PRAGMA user_version: с версии 1 все счета операций в operation_accounts
+const OPS_SCHEMA_VERSION: i64 = 1;
+
+impl<Id: BankAccountId> SqliteOpsStorage<Id> {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteOpsStorage<Id>, BankError> {
+        Self::init(Connection::open(path)?, None)
+    }
+
+    //база, операции в которой зашифрованы ключами keys, см. FileOpsStorage::open_encrypted
+    pub fn open_encrypted(
+        path: impl AsRef<Path>,
+        keys: Keyring,
+    ) -> Result<SqliteOpsStorage<Id>, BankError> {
+        Self::init(Connection::open(path)?, Some(keys))
+    }
+
+    pub fn open_in_memory() -> Result<SqliteOpsStorage<Id>, BankError> {
+        Self::init(Connection::open_in_memory()?, None)
+    }
+
+    //перешифровывает все операции ключом key одной транзакцией SQLite
+    pub fn rotate_key(&mut self, key: EncryptionKey) -> Result<(), BankError> {
+        let keys = Keyring::new(key);
+        let tx = self.conn.transaction()?;
+        {
+            let mut select = tx.prepare("SELECT op_id, payload FROM operations")?;
+            let mut update = tx.prepare("UPDATE operations SET payload = ?2 WHERE op_id = ?1")?;
+            let mut rows = select.query([])?;
+            while let Some(row) = rows.next()? {
+                let op_id: Vec<u8> = row.get(0)?;
+                let payload = open_payload(self.keys.as_ref(), &op_id, row.get(1)?)?;
+                update.execute(params![
+                    &op_id,
+                    crypto::seal_payload(Some(&keys), payload, &op_id)?
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.keys = Some(keys);
+        Ok(())
+    }
+
+    fn init(conn: Connection, keys: Option<Keyring>) -> Result<SqliteOpsStorage<Id>, BankError> {
+        conn.execute_batch(OPS_SCHEMA)?;
+        //в базах, созданных до появления времени операций, колонки at нет
+        //а в базах, созданных до появления цепочки хэшей, - колонки hash
+        for (column, definition) in [("at", "INTEGER NOT NULL DEFAULT 0"), ("hash", "BLOB")] {
+            let exists: bool = conn.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('operations') WHERE name = ?1",
+                params![column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE operations ADD COLUMN {column} {definition}"
+                ))?;
+            }
+        }
+
+        let mut storage = SqliteOpsStorage {
+            conn,
+            keys,
+            last: OpId::MIN,
+            head: ChainHash::GENESIS,
+            ids: PhantomData,
+        };
+        storage.index_accounts()?;
+        storage.chain_unchained()?;
+        (storage.last, storage.head) = storage.load_head()?;
+        Ok(storage)
+    }
+
+    //в базах до версии 1 у операции был известен только счёт account_id (у перевода - отправитель),
+    //все счета операций добавляются в operation_accounts один раз
+    fn index_accounts(&mut self) -> Result<(), BankError> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version >= OPS_SCHEMA_VERSION {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut select = tx.prepare("SELECT op_id, payload FROM operations")?;
+            let mut rows = select.query([])?;
+            while let Some(row) = rows.next()? {
+                let raw_id: Vec<u8> = row.get(0)?;
+                let op = decode_op::<Id>(self.keys.as_ref(), &raw_id, row.get(1)?)?;
+                index_op(&tx, &raw_id, &op)?;
+            }
+        }
+        tx.execute_batch(&format!(
+            "DROP INDEX IF EXISTS operations_by_account;
+             PRAGMA user_version = {OPS_SCHEMA_VERSION};"
+        ))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    //операции, записанные до появления цепочки, добавляются в цепочку при открытии.
+    //Такой может быть только вся история целиком: хэш, стёртый у части операций, не восстанавливается,
+    //иначе изменённая операция со стёртыми хэшами стала бы подлинной. Её найдёт chain::verify
+    fn chain_unchained(&mut self) -> Result<(), BankError> {
+        let (ops, chained): (i64, i64) =
+            self.conn
+                .query_row("SELECT COUNT(*), COUNT(hash) FROM operations", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+        if chained > 0 {
+            if chained < ops {
+                log::warn!(
+                    "{} operations have no hash, the history may be edited",
+                    ops - chained
+                );
+            }
+            return Ok(());
+        }
+        let mut hashes = Vec::new();
+        let mut prev = ChainHash::GENESIS;
+        {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT op_id, at, payload FROM operations ORDER BY op_id")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let raw_id: Vec<u8> = row.get(0)?;
+                let op = decode_op::<Id>(self.keys.as_ref(), &raw_id, row.get(2)?)?;
+                prev = prev.link(decode_op_id(&raw_id)?, row.get(1)?, &op)?;
+                hashes.push((raw_id, prev));
+            }
+        }
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        log::warn!(
+            "{} operations without hash are added to the chain",
+            hashes.len()
+        );
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("UPDATE operations SET hash = ?2 WHERE op_id = ?1")?;
+            for (raw_id, hash) in hashes {
+                stmt.execute(params![&raw_id, &hash.0[..]])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    //последняя операция и её хэш; если все операции архивированы - запомненные при архивировании.
+    //Последняя операция расшифровывается, поэтому чужой ключ - ошибка уже при открытии
+    fn load_head(&self) -> Result<(OpId, ChainHash), BankError> {
+        let last = self
+            .conn
+            .query_row(
+                "SELECT op_id, payload, hash FROM operations ORDER BY op_id DESC LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, Option<Vec<u8>>>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let last = match last {
+            Some((raw_id, payload, hash)) => {
+                decode_op::<Id>(self.keys.as_ref(), &raw_id, payload)?;
+                Some((raw_id, hash))
+            }
+            None => self
+                .conn
+                .query_row("SELECT op_id, hash FROM compaction", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()?,
+        };
+        match last {
+            None => Ok((OpId::MIN, ChainHash::GENESIS)),
+            Some((op_id, hash)) => Ok((decode_op_id(&op_id)?, stored_hash(hash)?)),
+        }
+    }
+
+    //страница истории после операции after
+    fn history_page(&self, after: OpId) -> Result<Vec<Entry<Id>>, BankError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT op_id, at, payload FROM operations
+             WHERE op_id > ?1 ORDER BY op_id LIMIT ?2",
+        )?;
+        let rows = stmt.query(params![&after.get().to_be_bytes()[..], OPS_PAGE as i64])?;
+        self.entries(rows)
+    }
+
+    //страница операций счёта (с закодированным идентификатором account_id) после операции after
+    fn account_page(&self, account_id: &[u8], after: OpId) -> Result<Vec<Entry<Id>>, BankError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT o.op_id, o.at, o.payload FROM operation_accounts a
+             JOIN operations o ON o.op_id = a.op_id
+             WHERE a.account_id = ?1 AND a.op_id > ?2 ORDER BY a.op_id LIMIT ?3",
+        )?;
+        let rows = stmt.query(params![
+            account_id,
+            &after.get().to_be_bytes()[..],
+            OPS_PAGE as i64
+        ])?;
+        self.entries(rows)
+    }
+
+    fn entries(&self, mut rows: rusqlite::Rows<'_>) -> Result<Vec<Entry<Id>>, BankError> {
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            let raw_id: Vec<u8> = row.get(0)?;
+            let op = decode_op(self.keys.as_ref(), &raw_id, row.get(2)?)?;
+            entries.push((decode_op_id(&raw_id)?, row.get(1)?, op));
+        }
+        Ok(entries)
+    }
+
+    //закодированный идентификатор счёта, если хранилищу известен такой счёт
+    fn known_account(&self, account_id: &Id) -> Result<Vec<u8>, BankError> {
+        let encoded = encode(account_id)?;
+        let known: bool = self
+            .conn
+            .prepare_cached("SELECT EXISTS (SELECT 1 FROM known_accounts WHERE account_id = ?1)")?
+            .query_row(params![&encoded], |row| row.get(0))?;
+        if !known {
+            return Err(BankError::BadRequest(format!(
+                "There is no account[{}] in the bank",
+                account_id
+            )));
+        }
+        Ok(encoded)
+    }
+}
+
+//все счета операции, по ним она находится в истории счёта; счёт остаётся известен и после архивирования
+fn index_op<Id: BankAccountId>(
+    conn: &Connection,
+    raw_id: &[u8],
+    op: &Operation<Id>,
+) -> Result<(), BankError> {
+    let mut by_account = conn.prepare_cached(
+        "INSERT OR IGNORE INTO operation_accounts (account_id, op_id) VALUES (?1, ?2)",
+    )?;
+    let mut known =
+        conn.prepare_cached("INSERT OR IGNORE INTO known_accounts (account_id) VALUES (?1)")?;
+    for account_id in op.account_ids() {
+        let account_id = encode(&account_id)?;
+        by_account.execute(params![&account_id, raw_id])?;
+        known.execute(params![&account_id])?;
+    }
+    Ok(())
+}
+
+//операции страницами page по возрастанию OpId, начиная после after
+fn paged<'a, Id: 'a>(
+    mut after: OpId,
+    page: impl Fn(OpId) -> Result<Vec<Entry<Id>>, BankError> + 'a,
+) -> impl Iterator<Item = Result<Entry<Id>, BankError>> + 'a {
+    let mut entries = Vec::new().into_iter();
+    let mut done = false;
+    std::iter::from_fn(move || loop {
+        if let Some(entry) = entries.next() {
+            return Some(Ok(entry));
+        }
+        if done {
+            return None;
+        }
+        match page(after) {
+            Ok(page) => {
+                done = page.len() < OPS_PAGE;
+                if let Some((op_id, _, _)) = page.last() {
+                    after = *op_id;
+                }
+                entries = page.into_iter();
+            }
+            Err(err) => {
+                done = true;
+                return Some(Err(err));
+            }
+        }
+    })
+}
+
+//операция из payload, расшифрованного, если он зашифрован
+fn decode_op<Id: BankAccountId>(
+    keys: Option<&Keyring>,
+    raw_id: &[u8],
+    payload: Vec<u8>,
+) -> Result<Operation<Id>, BankError> {
+    let payload = open_payload(keys, raw_id, payload)?;
+    bincode::deserialize(&payload).map_err(|err| {
+        let op_id = decode_u128(raw_id).map_or_else(|_| format!("{raw_id:?}"), |id| id.to_string());
+        BankError::CoreError(format!("Can't decode operation[{op_id}]: {err}"))
+    })
+}
+
+//payload операции, расшифрованный, если он зашифрован; ошибка ключа или повреждения указывает операцию
+fn open_payload(
+    keys: Option<&Keyring>,
+    op_id: &[u8],
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, BankError> {
+    crypto::open_payload(keys, payload, op_id).map_err(|err| {
+        let op_id = decode_u128(op_id).map_or_else(|_| format!("{op_id:?}"), |id| id.to_string());
+        match err {
+            BankError::WrongKey(msg) => BankError::WrongKey(format!("operation[{op_id}]: {msg}")),
+            BankError::CoreError(msg) => BankError::CoreError(format!("operation[{op_id}]: {msg}")),
+            err => err,
+        }
+    })
+}
+
+impl<Id: BankAccountId> Default for SqliteOpsStorage<Id> {
+    fn default() -> Self {
+        Self::open_in_memory().expect("in-memory SQLite database should be available")
+    }
+}
+
+impl<Id: BankAccountId> OpsStorage for SqliteOpsStorage<Id> {
+    type Id = Id;
+
+    fn transact(&mut self, at: Timestamp, ops: &[Operation<Id>]) -> Result<Vec<OpId>, BankError> {
+        //идентификаторы и хэши назначаются так же, как в хранилище в памяти
+        let links = InMemoryOpsStorage::<Id>::after(self.last, self.head).links(at, ops.iter())?;
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO operations (op_id, account_id, payload, at, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for ((op_id, hash), op) in links.iter().zip(ops) {
+                let raw_id = op_id.get().to_be_bytes();
+                let payload = bincode::serialize(op).map_err(|err| {
+                    BankError::CoreError(format!("Can't encode operation[{op_id}]: {err}"))
+                })?;
+                let payload = crypto::seal_payload(self.keys.as_ref(), payload, &raw_id)?;
+                stmt.execute(params![
+                    &raw_id[..],
+                    encode(op.account_id())?,
+                    payload,
+                    at,
+                    &hash.0[..]
+                ])?;
+                index_op(&tx, &raw_id, op)?;
+            }
+        }
+        tx.commit()?;
+
+        if let Some((op_id, hash)) = links.last() {
+            (self.last, self.head) = (*op_id, *hash);
+        }
+        Ok(links.into_iter().map(|(op_id, _)| op_id).collect())
+    }
+
+    fn get_ops(
+        &self,
+        account_id: &Id,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.get_ops_after(account_id, OpId::MIN)
+    }
+
+    fn get_ops_after(
+        &self,
+        account_id: &Id,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        let account_id = self.known_account(account_id)?;
+        Ok(paged(after, move |after| {
+            self.account_page(&account_id, after)
+        }))
+    }
+
+    fn get_history(&self) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        self.get_history_after(OpId::MIN)
+    }
+
+    fn get_history_after(
+        &self,
+        after: OpId,
+    ) -> Result<impl Iterator<Item = Result<Entry<Id>, BankError>>, BankError> {
+        Ok(paged(after, move |after| self.history_page(after)))
+    }
+
+    fn last_op_id(&self) -> Result<OpId, BankError> {
+        Ok(self.last)
+    }
+
+    fn get_hash(&self, op_id: OpId) -> Result<ChainHash, BankError> {
+        let hash = self
+            .conn
+            .prepare_cached("SELECT hash FROM operations WHERE op_id = ?1")?
+            .query_row(params![&op_id.get().to_be_bytes()[..]], |row| {
+                row.get::<_, Option<Vec<u8>>>(0)
+            })
+            .optional()?
+            .ok_or_else(|| {
+                BankError::BadRequest(format!("There is no operation[{op_id}] in the storage"))
+            })?;
+        stored_hash(hash)
+    }
+
+    fn head_hash(&self) -> Result<ChainHash, BankError> {
+        Ok(self.head)
+    }
+
+    //голова цепочки запоминается: после архивирования всех операций по ней продолжается история
+    fn compact(&mut self, before: OpId) -> Result<History<Id>, BankError> {
+        let archived = self
+            .get_history()?
+            .take_while(|entry| !matches!(entry, Ok((op_id, _, _)) if *op_id >= before))
+            .collect::<Result<History<Id>, _>>()?;
+        let before = before.get().to_be_bytes();
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM operation_accounts WHERE op_id < ?1",
+            params![&before[..]],
+        )?;
+        tx.execute(
+            "DELETE FROM operations WHERE op_id < ?1",
+            params![&before[..]],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO compaction (id, op_id, hash) VALUES (0, ?1, ?2)",
+            params![&self.last.get().to_be_bytes()[..], &self.head.0[..]],
+        )?;
+        tx.commit()?;
+        Ok(archived)
+    }
+}
+
+//реализация State банка в SQLite (в файле или в `:memory:`).
+//Копии счетов в памяти нет: каждый запрос читает таблицу accounts, а транзакция читает только
+//затронутые ею счета, вычисляет изменения и пишет их одной транзакцией SQLite.
+//Состояние фиксируется отдельно от истории (даже в той же базе), поэтому после падения между фиксациями
+//оно может опережать историю: банк с ним открывается через Bank::reopen, который сверяет их
+#[derive(Debug)]
+pub struct SqliteState<A: BankAccount = Account> {
+    conn: Connection,
+    accounts: PhantomData<A>,
+}
+
+//счета читаются страницами по ключу, поэтому обход не держит открытым запрос к базе
+const ACCOUNTS_PAGE: usize = 256;
+
+impl<A: BankAccount> SqliteState<A> {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteState<A>, BankError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteState<A>, BankError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteState<A>, BankError> {
+        conn.execute_batch(STATE_SCHEMA)?;
+        Ok(SqliteState {
+            conn,
+            accounts: PhantomData,
+        })
+    }
+
+    fn load(&self, account_id: &A::Id) -> Result<Option<A>, BankError> {
+        self.conn
+            .prepare_cached("SELECT payload FROM accounts WHERE account_id = ?1")?
+            .query_row(params![encode(account_id)?], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?
+            .map(|payload| decode(&payload))
+            .transpose()
+    }
+
+    //счета, которых касаются операции, - всё, что нужно для их проверки и применения.
+    //Квитанция проверяет и счета related, хотя в истории индексируется только по account_id
+    fn view(&self, ops: &[&Operation<A::Id>]) -> Result<InMemoryState<A>, BankError> {
+        let mut account_ids: Vec<A::Id> = Vec::new();
+        for op in ops {
+            account_ids.extend(op.account_ids());
+            if let Operation::Receipt { related, .. } = op {
+                account_ids.extend(related.iter().cloned());
+            }
+        }
+        account_ids.sort();
+        account_ids.dedup();
+        account_ids
+            .iter()
+            .filter_map(|account_id| self.load(account_id).transpose())
+            .collect()
+    }
+
+    //страница счетов с ключом больше after и ключ последнего из них
+    fn page(&self, after: Option<&[u8]>) -> Result<(Vec<A>, Option<Vec<u8>>), BankError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT account_id, payload FROM accounts
+             WHERE ?1 IS NULL OR account_id > ?1 ORDER BY account_id LIMIT ?2",
+        )?;
+        let mut rows = stmt.query(params![after, ACCOUNTS_PAGE as i64])?;
+        let mut accounts = Vec::new();
+        let mut last = None;
+        while let Some(row) = rows.next()? {
+            last = Some(row.get::<_, Vec<u8>>(0)?);
+            accounts.push(decode(&row.get::<_, Vec<u8>>(1)?)?);
+        }
+        Ok((accounts, last))
+    }
+}
+
+impl<A: BankAccount> Default for SqliteState<A> {
+    fn default() -> Self {
+        Self::open_in_memory().expect("in-memory SQLite database should be available")
+    }
+}
+
+impl<A: BankAccount> State for SqliteState<A> {
+    type Account = A;
+
+    fn transact<'b>(
+        &mut self,
+        ops: impl Iterator<Item = &'b Operation<A::Id>>,
+    ) -> Result<Vec<A>, BankError> {
+        let ops: Vec<_> = ops.collect();
+        let mut view = self.view(&ops)?;
+        let accounts = view.transact(ops.into_iter())?;
+
+        //в view только счета транзакции, они и сохраняются
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO accounts (account_id, payload) VALUES (?1, ?2)
+                 ON CONFLICT (account_id) DO UPDATE SET payload = excluded.payload",
+            )?;
+            for account in view.accounts()? {
+                let account = account?;
+                stmt.execute(params![encode(account.account_id())?, encode(&account)?])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(accounts)
+    }
+
+    fn validate<'b>(
+        &self,
+        ops: impl Iterator<Item = &'b Operation<A::Id>>,
+    ) -> Result<(), BankError> {
+        let ops: Vec<_> = ops.collect();
+        self.view(&ops)?.validate(ops.into_iter())
+    }
+
+    fn revert(&mut self, accounts: Prior<A>) -> Result<(), BankError> {
+        let tx = self.conn.transaction()?;
+        for (account_id, account) in accounts {
+            match account {
+                Some(account) => tx.execute(
+                    "INSERT INTO accounts (account_id, payload) VALUES (?1, ?2)
+                     ON CONFLICT (account_id) DO UPDATE SET payload = excluded.payload",
+                    params![encode(&account_id)?, encode(&account)?],
+                )?,
+                None => tx.execute(
+                    "DELETE FROM accounts WHERE account_id = ?1",
+                    params![encode(&account_id)?],
+                )?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_balance(&self, account_id: &A::Id) -> Result<A, BankError> {
+        self.load(account_id)?.ok_or_else(|| {
+            BankError::BadRequest(format!("Account[{}] not found in bank", account_id))
+        })
+    }
+
+    fn accounts(&self) -> Result<impl Iterator<Item = Result<A, BankError>>, BankError> {
+        let mut after = None;
+        let mut page = Vec::new().into_iter();
+        let mut done = false;
+        Ok(std::iter::from_fn(move || loop {
+            if let Some(account) = page.next() {
+                return Some(Ok(account));
+            }
+            if done {
+                return None;
+            }
+            match self.page(after.as_deref()) {
+                Ok((accounts, last)) => {
+                    done = accounts.len() < ACCOUNTS_PAGE;
+                    after = last;
+                    page = accounts.into_iter();
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        bank::{
+            test::{bank_should_work_with_custom_account, bank_test_suite, NamedAccount},
+            AccountStatus, Bank, History, NonZeroMoney,
+        },
+        chain,
+        currency::Currency,
+    };
+
+    mod sqlite {
+        use super::*;
+
+        bank_test_suite!(Bank::new(
+            SqliteOpsStorage::default(),
+            SqliteState::default()
+        ));
+    }
+
+    #[test]
+    fn sqlite_bank_should_work_with_custom_account() {
+        bank_should_work_with_custom_account(Bank::new(
+            SqliteOpsStorage::default(),
+            SqliteState::default(),
+        ));
+    }
+
+    #[test]
+    fn sqlite_state_should_reopen_custom_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.sqlite");
+
+        let mut bank = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            SqliteState::<NamedAccount>::open(&path).unwrap(),
+        );
+        let _ = bank.create_account("alice".to_owned());
+        let _ = bank.deposit(&"alice".to_owned(), NonZeroMoney::new(42).unwrap());
+        drop(bank);
+
+        let bank = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            SqliteState::<NamedAccount>::open(&path).unwrap(),
+        );
+        assert_eq!(
+            bank.get_balance(&"alice".to_owned()),
+            Ok(NamedAccount {
+                name: "alice".to_owned(),
+                balance: 42,
+                ops_count: 1
+            })
+        );
+        assert_eq!(
+            bank.get_account_ops(&"alice".to_owned()).unwrap().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn sqlite_bank_should_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.sqlite");
+
+        let mut bank: Bank<SqliteOpsStorage, SqliteState> = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            SqliteState::open(&path).unwrap(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        let _ = bank.move_money(128, 129, NonZeroMoney::new(12).unwrap());
+        let _ = bank.withdraw(129, NonZeroMoney::new(13).unwrap()); //rejected
+        let deposit = |bank: &mut Bank<SqliteOpsStorage, SqliteState>| {
+            bank.deposit(&129, NonZeroMoney::new(5).unwrap()).map(drop)
+        };
+        let receipt = bank.idempotent(1, 10, deposit).cloned().unwrap();
+        drop(bank);
+
+        //состояние уже на диске, по истории восстанавливаются только квитанции
+        let mut bank = Bank::reopen(
+            SqliteOpsStorage::open(&path).unwrap(),
+            SqliteState::open(&path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(bank.idempotent(1, 10, deposit), Ok(&receipt));
+        assert!(bank.idempotent(1, 20, deposit).is_err());
+        assert_eq!(
+            bank.get_balance(&128),
+            Ok(Account {
+                account_id: 128,
+                balance: 30,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+        assert_eq!(
+            bank.get_balance(&129),
+            Ok(Account {
+                account_id: 129,
+                balance: 17,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+        assert_eq!(receipt.accounts[0].balance, 17);
+        assert_eq!(bank.get_history().unwrap().count(), 6);
+        assert_eq!(bank.get_account_ops(&129).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn sqlite_bank_should_repair_state_saved_without_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bank.sqlite");
+        let open = || {
+            (
+                SqliteOpsStorage::open(&path).unwrap(),
+                SqliteState::open(&path).unwrap(),
+            )
+        };
+
+        let (storage, state) = open();
+        let mut bank: Bank<SqliteOpsStorage, SqliteState> = Bank::new(storage, state);
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        drop(bank);
+
+        //процесс упал после фиксации состояния, но до фиксации операций в истории
+        let mut state: SqliteState = SqliteState::open(&path).unwrap();
+        state
+            .transact(
+                [
+                    Operation::Deposit(128, NonZeroMoney::new(8).unwrap()),
+                    Operation::Create(129),
+                ]
+                .iter(),
+            )
+            .unwrap();
+        drop(state);
+
+        let (storage, state) = open();
+        let bank = Bank::new(storage, state);
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 50);
+        assert!(!bank.audit().unwrap().is_consistent());
+        drop(bank);
+
+        //при повторном открытии состояние сверяется с историей
+        let (storage, state) = open();
+        let bank = Bank::reopen(storage, state).unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 42);
+        assert!(bank.get_balance(&129).is_err());
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn sqlite_state_should_read_accounts_from_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.sqlite");
+
+        let mut bank: Bank<InMemoryOpsStorage, SqliteState> = Bank::new(
+            InMemoryOpsStorage::default(),
+            SqliteState::open(&path).unwrap(),
+        );
+        let count = 2 * ACCOUNTS_PAGE as u128 + 1;
+        for account_id in 0..count {
+            let _ = bank.create_account(account_id);
+        }
+        let _ = bank.deposit(&7, NonZeroMoney::new(42).unwrap());
+
+        //счета не кэшируются: второе подключение сразу видит изменения первого
+        let other: SqliteState = SqliteState::open(&path).unwrap();
+        assert_eq!(other.get_balance(&7).unwrap().balance, 42);
+        let _ = bank.withdraw(7, NonZeroMoney::new(2).unwrap());
+        assert_eq!(other.get_balance(&7).unwrap().balance, 40);
+
+        //обход идёт страницами и возвращает каждый счёт один раз
+        let ids: Vec<u128> = other
+            .accounts()
+            .unwrap()
+            .map(|account| account.unwrap().account_id)
+            .collect();
+        assert_eq!(ids.len(), count as usize);
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+        assert!(bank.audit().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_restore_in_memory_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        let mut bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        drop(bank);
+
+        let bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::restore(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            bank.get_balance(&128),
+            Ok(Account {
+                account_id: 128,
+                balance: 42,
+                currency: Currency::RUB,
+                credit_limit: 0,
+                status: AccountStatus::Active,
+            })
+        );
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_read_operations_from_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        let mut bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        for _ in 0..OPS_PAGE {
+            let _ = bank.deposit(&128, NonZeroMoney::MIN);
+        }
+        let _ = bank.move_money(128, 129, NonZeroMoney::new(12).unwrap());
+        let history: History = bank
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(bank);
+
+        //история читается страницами, перевод находится и в истории получателя
+        let storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        let restored: History = storage
+            .get_history()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(restored, history);
+        assert_eq!(storage.get_ops(&128).unwrap().count(), OPS_PAGE + 2);
+        let ops: History = storage
+            .get_ops(&129)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[1].2, Operation::Transfer { to: 129, .. }));
+        assert_eq!(
+            storage.get_history_after(history[2].0).unwrap().count(),
+            history.len() - 3
+        );
+        assert!(matches!(
+            storage.get_ops(&130).map(|ops| ops.count()),
+            Err(BankError::BadRequest(_))
+        ));
+        drop(storage);
+
+        //в базе до версии 1 операции счёта искались только по отправителю
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "DELETE FROM operation_accounts;
+             DELETE FROM known_accounts;
+             PRAGMA user_version = 0;",
+        )
+        .unwrap();
+        drop(conn);
+        let storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        assert_eq!(storage.get_ops(&129).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_keep_accounts_after_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        let mut storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        let _ = storage.persist(1, &Operation::Create(128));
+        let _ = storage.persist(2, &Operation::Create(129));
+        let last = storage
+            .persist(3, &Operation::Deposit(129, NonZeroMoney::MIN))
+            .unwrap();
+        let head = storage.head_hash().unwrap();
+
+        //архивируются все операции, счета и голова цепочки остаются
+        let archived = storage.compact(last.checked_add(1).unwrap()).unwrap();
+        assert_eq!(archived.len(), 3);
+        drop(storage);
+
+        let mut storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        assert_eq!(storage.get_history().unwrap().count(), 0);
+        assert_eq!(storage.get_ops(&128).unwrap().count(), 0);
+        assert_eq!(storage.last_op_id(), Ok(last));
+        assert_eq!(storage.head_hash(), Ok(head));
+        let op_id = storage
+            .persist(4, &Operation::Deposit(128, NonZeroMoney::MIN))
+            .unwrap();
+        assert!(op_id > last);
+        assert_eq!(storage.get_ops(&128).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_open_database_without_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        //база в формате до появления времени операций
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE operations (
+                op_id BLOB PRIMARY KEY,
+                account_id BLOB NOT NULL,
+                payload BLOB NOT NULL
+            );",
+        )
+        .unwrap();
+        let op: Operation = Operation::Create(128);
+        conn.execute(
+            "INSERT INTO operations (op_id, account_id, payload) VALUES (?1, ?2, ?3)",
+            params![
+                &2u128.to_be_bytes()[..],
+                encode(op.account_id()).unwrap(),
+                encode(&op).unwrap()
+            ],
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        let _ = storage.persist(42, &Operation::Deposit(128, NonZeroMoney::MIN));
+        drop(storage);
+
+        let storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        let times: Vec<Timestamp> = storage
+            .get_history()
+            .unwrap()
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(times, vec![0, 42]);
+        //старая операция добавлена в цепочку при первом открытии
+        assert!(chain::verify(&storage).unwrap().is_intact());
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_detect_edited_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        let mut bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        let _ = bank.withdraw(128, NonZeroMoney::new(2).unwrap());
+        let deposit = bank
+            .get_account_ops(&128)
+            .unwrap()
+            .nth(1)
+            .unwrap()
+            .unwrap()
+            .0;
+        let head = bank.head_hash().unwrap();
+        drop(bank);
+
+        let storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        assert_eq!(storage.head_hash(), Ok(head));
+        assert!(chain::verify(&storage).unwrap().is_intact());
+        drop(storage);
+
+        //пополнение "исправлено" прямо в базе
+        let conn = Connection::open(&path).unwrap();
+        let op: Operation = Operation::Deposit(128, NonZeroMoney::new(4_200).unwrap());
+        conn.execute(
+            "UPDATE operations SET payload = ?2 WHERE op_id = ?1",
+            params![&deposit.get().to_be_bytes()[..], encode(&op).unwrap()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::restore(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        )
+        .unwrap();
+        let report = bank.verify_history().unwrap();
+        assert_eq!(report.tampered, Some(deposit));
+        assert_eq!(report.head, head);
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_not_rechain_edited_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+
+        let mut bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::new(
+            SqliteOpsStorage::open(&path).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        let _ = bank.withdraw(128, NonZeroMoney::new(2).unwrap());
+        let deposit = bank
+            .get_account_ops(&128)
+            .unwrap()
+            .nth(1)
+            .unwrap()
+            .unwrap()
+            .0;
+        drop(bank);
+
+        //пополнение изменено, а его хэш и хэши следующих операций стёрты, как у старой базы
+        let conn = Connection::open(&path).unwrap();
+        let op: Operation = Operation::Deposit(128, NonZeroMoney::new(4_200).unwrap());
+        let raw_id = &deposit.get().to_be_bytes()[..];
+        conn.execute(
+            "UPDATE operations SET payload = ?2 WHERE op_id = ?1",
+            params![raw_id, encode(&op).unwrap()],
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE operations SET hash = NULL WHERE op_id >= ?1",
+            params![raw_id],
+        )
+        .unwrap();
+
+        let storage: SqliteOpsStorage = SqliteOpsStorage::open(&path).unwrap();
+        let report = chain::verify(&storage).unwrap();
+        assert_eq!(report.tampered, Some(deposit));
+        drop(storage);
+        let unchained: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM operations WHERE hash IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unchained, 2);
+    }
+
+    #[test]
+    fn sqlite_ops_storage_should_encrypt_payloads_and_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.sqlite");
+        let old = EncryptionKey::generate().unwrap();
+        let new = EncryptionKey::generate().unwrap();
+        let open = |keys: Keyring| SqliteOpsStorage::<AccountId>::open_encrypted(&path, keys);
+
+        let mut bank: Bank<SqliteOpsStorage, InMemoryState> = Bank::new(
+            open(Keyring::new(old.clone())).unwrap(),
+            InMemoryState::default(),
+        );
+        let _ = bank.create_account(128);
+        let _ = bank.deposit(&128, NonZeroMoney::new(42).unwrap());
+        drop(bank);
+
+        //в базе нет операций в открытом виде
+        let conn = Connection::open(&path).unwrap();
+        let payloads: Vec<Vec<u8>> = conn
+            .prepare("SELECT payload FROM operations")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(conn);
+        let op: Operation = Operation::Create(128);
+        assert!(!payloads.contains(&encode(&op).unwrap()));
+
+        assert!(matches!(
+            SqliteOpsStorage::<AccountId>::open(&path),
+            Err(BankError::WrongKey(_))
+        ));
+        assert!(matches!(
+            open(Keyring::new(new.clone())),
+            Err(BankError::WrongKey(_))
+        ));
+
+        let mut storage = open(Keyring::new(new.clone()).with_previous(old.clone())).unwrap();
+        storage.rotate_key(new.clone()).unwrap();
+        drop(storage);
+        assert!(matches!(
+            open(Keyring::new(old)),
+            Err(BankError::WrongKey(_))
+        ));
+        let bank: Bank<SqliteOpsStorage, InMemoryState> =
+            Bank::restore(open(Keyring::new(new)).unwrap(), InMemoryState::default()).unwrap();
+        assert_eq!(bank.get_balance(&128).unwrap().balance, 42);
+        assert!(bank.verify_history().unwrap().is_intact());
+    }
+}
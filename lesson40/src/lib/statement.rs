@@ -0,0 +1,328 @@
+use std::fmt::{Display, Write};
+
+use crate::{
+    bank::{
+        apply_to, Account, BankAccount, BankError, Money, OpId, Operation, OperationKind,
+        OpsStorage,
+    },
+    clock::Timestamp,
+};
+
+//итоги выписки могут не поместиться в Money
+pub type Total = u128;
+
+//строка выписки: операция, сколько она зачислила и списала со счёта и баланс после неё
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementLine<A: BankAccount = Account> {
+    pub op_id: OpId,
+    pub at: Timestamp,
+    pub op: Operation<A::Id>,
+    pub credit: Money,
+    pub debit: Money,
+    pub balance: A::Balance,
+}
+
+//выписка по счёту за диапазон операций [from, to].
+//opening - None, если счёт открыт внутри диапазона
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement<A: BankAccount = Account> {
+    pub account_id: A::Id,
+    pub from: OpId,
+    pub to: OpId,
+    pub opening: Option<A::Balance>,
+    pub lines: Vec<StatementLine<A>>,
+    pub total_in: Total,
+    pub total_out: Total,
+    pub closing: A::Balance,
+}
+
+//сколько операция зачислила на счёт account_id и сколько списала с него
+pub(crate) fn flows<Id: PartialEq>(
+    account_id: &Id,
+    op: &Operation<Id>,
+) -> Result<(Money, Money), BankError> {
+    Ok(match op {
+        Operation::Deposit(_, amount) => (amount.get(), 0),
+        Operation::Withdraw(_, amount) => (0, amount.get()),
+        Operation::Fee { from, amount, .. } | Operation::Transfer { from, amount, .. } => {
+            if from == account_id {
+                (0, amount.get())
+            } else {
+                (amount.get(), 0)
+            }
+        }
+        Operation::Exchange {
+            from, amount, rate, ..
+        } => {
+            if from == account_id {
+                (0, amount.get())
+            } else {
+                (rate.convert(*amount)?.get(), 0)
+            }
+        }
+        Operation::TransferOut { amount, .. } => (0, amount.get()),
+        Operation::TransferIn { amount, .. } | Operation::TransferCancel { amount, .. } => {
+            (amount.get(), 0)
+        }
+        _ => (0, 0),
+    })
+}
+
+//выписка по счёту за операции [from, to] из истории storage
+pub(crate) fn build<T, A>(
+    storage: &T,
+    account_id: &A::Id,
+    from: OpId,
+    to: OpId,
+) -> Result<Statement<A>, BankError>
+where
+    T: OpsStorage<Id = A::Id>,
+    A: BankAccount,
+{
+    let mut account = None;
+    let mut opening = None;
+    let mut lines = Vec::new();
+    for entry in storage.get_ops(account_id)? {
+        let (op_id, at, op) = entry?;
+        if op_id > to {
+            break;
+        }
+        if op_id >= from && lines.is_empty() {
+            opening = account.as_ref().map(A::balance);
+        }
+        let current = apply_to(account, account_id, &op)?;
+        //квитанции запросов не меняют счёт и в выписку не попадают
+        if op_id >= from && op.kind() != OperationKind::Receipt {
+            let (credit, debit) = flows(account_id, &op)?;
+            lines.push(StatementLine {
+                op_id,
+                at,
+                op,
+                credit,
+                debit,
+                balance: current.balance(),
+            });
+        }
+        account = Some(current);
+    }
+    let closing = account.as_ref().map(A::balance).ok_or_else(|| {
+        BankError::BadRequest(format!(
+            "Account[{account_id}] doesn't exist at operation[{to}]"
+        ))
+    })?;
+    if lines.is_empty() {
+        opening = Some(closing.clone());
+    }
+    Ok(Statement {
+        account_id: account_id.clone(),
+        from,
+        to,
+        opening,
+        total_in: lines.iter().map(|line| Total::from(line.credit)).sum(),
+        total_out: lines.iter().map(|line| Total::from(line.debit)).sum(),
+        lines,
+        closing,
+    })
+}
+
+//поле CSV в кавычках, если в нём есть разделитель, кавычки или перевод строки
+fn csv_field(value: impl Display) -> String {
+    let value = value.to_string();
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+impl<A: BankAccount> Statement<A> {
+    //CSV с заголовком; начальный и конечный баланс - отдельные строки opening и closing,
+    //в строке closing - итоги зачислений и списаний
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("op_id,at,kind,counterparty,in,out,balance\n");
+        if let Some(opening) = &self.opening {
+            let _ = writeln!(csv, ",,opening,,,,{}", csv_field(opening));
+        }
+        for line in &self.lines {
+            let _ = writeln!(
+                csv,
+                "{},{},{:?},{},{},{},{}",
+                line.op_id,
+                line.at,
+                line.op.kind(),
+                line.op
+                    .counterparty(&self.account_id)
+                    .map(csv_field)
+                    .unwrap_or_default(),
+                line.credit,
+                line.debit,
+                csv_field(&line.balance)
+            );
+        }
+        let _ = writeln!(
+            csv,
+            ",,closing,,{},{},{}",
+            self.total_in,
+            self.total_out,
+            csv_field(&self.closing)
+        );
+        csv
+    }
+}
+
+//текстовая выписка
+impl<A: BankAccount> Display for Statement<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Statement of account[{}] for operations [{}..={}]",
+            self.account_id, self.from, self.to
+        )?;
+        match &self.opening {
+            Some(opening) => writeln!(f, "Opening balance: {opening}")?,
+            None => writeln!(f, "Opening balance: account is opened in the period")?,
+        }
+        for line in &self.lines {
+            write!(f, "[{}] at[{}] {:?}", line.op_id, line.at, line.op.kind())?;
+            if let Some(counterparty) = line.op.counterparty(&self.account_id) {
+                write!(f, " with[{counterparty}]")?;
+            }
+            if line.credit > 0 {
+                write!(f, " +{}", line.credit)?;
+            }
+            if line.debit > 0 {
+                write!(f, " -{}", line.debit)?;
+            }
+            writeln!(f, " = {}", line.balance)?;
+        }
+        writeln!(
+            f,
+            "Total in: {}, total out: {}",
+            self.total_in, self.total_out
+        )?;
+        write!(f, "Closing balance: {}", self.closing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bank::{Bank, InMemoryOpsStorage, InMemoryState, NonZeroMoney},
+        clock::ManualClock,
+        currency::{Currency, StaticRates},
+        fee::FlatFee,
+    };
+
+    fn bank() -> Bank<InMemoryOpsStorage, InMemoryState> {
+        let mut bank = Bank::new(InMemoryOpsStorage::default(), InMemoryState::default())
+            .with_clock(ManualClock::new(1_000))
+            .with_fees(0, FlatFee(1))
+            .unwrap()
+            .with_rates(StaticRates::default().with(Currency::USD, Currency::RUB, 80_000_000));
+        let _ = bank.create_account(128);
+        let _ = bank.create_account(129);
+        let _ = bank.create_account_in(130, Currency::USD);
+        let _ = bank.deposit(&128, NonZeroMoney::new(100).unwrap());
+        let _ = bank.move_money(128, 129, NonZeroMoney::new(30).unwrap());
+        let _ = bank.deposit(&130, NonZeroMoney::new(2).unwrap());
+        let _ = bank.move_money(130, 128, NonZeroMoney::new(1).unwrap());
+        let _ = bank.set_credit_limit(128, 200);
+        let _ = bank.withdraw(128, NonZeroMoney::new(200).unwrap());
+        bank
+    }
+
+    #[test]
+    fn statement_should_track_running_balance() {
+        let bank = bank();
+        let ops: Vec<OpId> = bank
+            .get_account_ops(&128)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+
+        //диапазон начинается с перевода: пополнение и комиссия за него - в начальном балансе
+        let statement = bank.statement(&128, ops[3], OpId::MAX).unwrap();
+        assert_eq!(statement.opening, Some(99));
+        assert_eq!(statement.closing, -53);
+        assert_eq!(statement.closing, bank.get_balance(&128).unwrap().balance);
+        for line in &statement.lines {
+            assert_eq!(
+                Some(line.balance),
+                bank.get_balance_at(&128, line.op_id)
+                    .ok()
+                    .map(|account| account.balance)
+            );
+        }
+        //перевод 30 + комиссия 1, обмен 80, лимит без движения денег, снятие 200 + комиссия 1
+        assert_eq!(
+            statement
+                .lines
+                .iter()
+                .map(|line| (line.credit, line.debit))
+                .collect::<Vec<_>>(),
+            vec![(0, 30), (0, 1), (80, 0), (0, 0), (0, 200), (0, 1)]
+        );
+        assert_eq!(statement.total_in, 80);
+        assert_eq!(statement.total_out, 232);
+
+        //счёт открыт внутри диапазона
+        let statement = bank.statement(&129, OpId::MIN, OpId::MAX).unwrap();
+        assert_eq!(statement.opening, None);
+        assert_eq!(statement.closing, 30);
+        assert_eq!(statement.lines.len(), 2);
+
+        //в диапазоне нет операций счёта
+        let statement = bank.statement(&129, ops[5], ops[5]).unwrap();
+        assert_eq!(statement.lines, vec![]);
+        assert_eq!(statement.opening, Some(30));
+        assert_eq!(statement.closing, 30);
+
+        assert!(bank.statement(&129, OpId::MIN, ops[0]).is_err());
+        assert!(bank.statement(&131, OpId::MIN, OpId::MAX).is_err());
+    }
+
+    #[test]
+    fn statement_should_be_exported_as_csv_and_text() {
+        let bank = bank();
+        let ops: Vec<OpId> = bank
+            .get_account_ops(&129)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        let statement = bank.statement(&129, OpId::MIN, OpId::MAX).unwrap();
+
+        assert_eq!(
+            statement.to_csv(),
+            format!(
+                "op_id,at,kind,counterparty,in,out,balance\n\
+                 {},1000,Create,,0,0,0\n\
+                 {},1000,Transfer,128,30,0,30\n\
+                 ,,closing,,30,0,30\n",
+                ops[0], ops[1]
+            )
+        );
+        assert_eq!(
+            statement.to_string(),
+            format!(
+                "Statement of account[129] for operations [{}..={}]\n\
+                 Opening balance: account is opened in the period\n\
+                 [{}] at[1000] Create = 0\n\
+                 [{}] at[1000] Transfer with[128] +30 = 30\n\
+                 Total in: 30, total out: 0\n\
+                 Closing balance: 30",
+                OpId::MIN,
+                OpId::MAX,
+                ops[0],
+                ops[1]
+            )
+        );
+    }
+
+    #[test]
+    fn csv_field_should_be_quoted_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("Smith, John"), "\"Smith, John\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}